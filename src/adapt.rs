@@ -1,10 +1,36 @@
 use super::*;
-use crate::sched::{Car, CarState, Schedule, Scheduler};
+use crate::engine::{self, LightPolicy, WaitingQueue};
+use crate::intersect::{QueueOrder, QueuePolicy};
+use crate::sched::{Schedule, Scheduler};
+use crate::seed::SeedSource;
 use log::info;
-use std::collections::{HashSet, VecDeque};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashSet;
 
-#[derive(Default)]
-pub struct AdaptiveScheduler {}
+pub struct AdaptiveScheduler {
+    queue_order: QueueOrder,
+    seeds: SeedSource,
+}
+
+impl Default for AdaptiveScheduler {
+    fn default() -> Self {
+        Self {
+            queue_order: QueueOrder::Longest,
+            seeds: SeedSource::default(),
+        }
+    }
+}
+
+impl AdaptiveScheduler {
+    pub fn set_queue_order(&mut self, queue_order: QueueOrder) {
+        self.queue_order = queue_order;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seeds = SeedSource::new(seed);
+    }
+}
 
 impl Scheduler for AdaptiveScheduler {
     fn schedule<'a>(&self, simulation: &'a Simulation) -> Schedule<'a> {
@@ -40,103 +66,17 @@ impl Scheduler for AdaptiveScheduler {
             }
         }
 
-        let mut inter_order: HashMap<IntersectionId, Vec<Option<StreetId>>> =
-            HashMap::new();
-        for (&inter_id, streets) in crossed_streets.iter() {
-            inter_order
-                .entry(inter_id)
-                .or_insert_with(|| vec![None; streets.len()]);
-        }
-
-        // TODO: replace the rest of this function with a call to
-        // intersect::reorder_all_intersections
-
-        // All cars that haven't reached their end yet
-        let mut moving_cars: HashMap<CarId, Car> = simulation
-            .car_paths
-            .iter()
-            .map(|path| Car::new(path))
-            .enumerate()
-            .collect();
-
-        // Queues of cars at the end of streets
-        let mut queues: HashMap<StreetId, VecDeque<CarId>> = HashMap::new();
-
-        // Add cars to the queues of their starting street (in order of car ID)
-        for car_id in 0..simulation.car_paths.len() {
-            let street_id = moving_cars.get_mut(&car_id).unwrap().start();
-            queues
-                .entry(street_id)
-                .and_modify(|cars| cars.push_back(car_id))
-                .or_insert_with(|| vec![car_id].into_iter().collect());
-        }
-
-        for time in 0..=simulation.duration {
-            // Let cars move if possible
-            for (&car_id, car) in moving_cars.iter_mut() {
-                if car.state == CarState::Ready {
-                    if let Some(next_street_id) = car.move_forward() {
-                        queues
-                            .entry(next_street_id)
-                            .and_modify(|cars| cars.push_back(car_id))
-                            .or_insert_with(|| {
-                                vec![car_id].into_iter().collect()
-                            });
-                    }
-                }
-            }
-
-            // Sort queues by number of cars waiting
-            let mut queue_order: Vec<(StreetId, usize)> = queues
-                .iter()
-                .map(|(&street_id, cars)| (street_id, cars.len()))
-                .collect();
-            queue_order.sort_unstable_by(|a, b| b.1.cmp(&a.1));
-
-            // Let cars at the top of the queue cross intersections if possible
-            for (street_id, _) in queue_order.into_iter() {
-                let inter_id = simulation.streets[street_id].end_intersection;
-                let order = inter_order.get_mut(&inter_id).unwrap();
-                let slot_pos = usize::try_from(time).unwrap() % order.len();
-                let curr_slot = order.get_mut(slot_pos).unwrap();
-                if let Some(open_street_id) = curr_slot {
-                    // The current slot is already assigned to a street
-                    if street_id != *open_street_id {
-                        // But the intersection is not open for the street the
-                        // car is waiting at, so it will have to wait
-                        continue;
-                    }
-                } else {
-                    // Current slot is not yet assigned to any street
-                    if !crossed_streets
-                        .get_mut(&inter_id)
-                        .unwrap()
-                        .remove(&street_id)
-                    {
-                        // The street the car is waiting on has either already
-                        // been assigned a different slot, or it does not need
-                        // to be open (the car will never finish in time)
-                        continue;
-                    }
-                    // Assign the current slot to the street
-                    *curr_slot = Some(street_id);
-                }
-
-                // Let the car go through
-                let car_id =
-                    queues.get_mut(&street_id).unwrap().pop_front().unwrap();
-                moving_cars
-                    .get_mut(&car_id)
-                    .unwrap()
-                    .cross_intersection(simulation);
-            }
-
-            // Drop empty traffic light queues
-            queues.retain(|_, cars| !cars.is_empty());
-
-            // Remove cars that reached their end
-            moving_cars.retain(|_, car| car.state != CarState::Arrived);
-        }
+        let mut rng = StdRng::seed_from_u64(self.seeds.next_seed());
+        let queue_policy = self.queue_order.policy(&mut rng);
+        info!("Adaptive scheduler: {:?} queue policy", queue_policy);
+        let mut policy =
+            SlotLights::new(simulation, crossed_streets, queue_policy);
+        engine::run(simulation, &mut policy, &mut ());
+        let SlotLights {
+            inter_order,
+            mut crossed_streets,
+            ..
+        } = policy;
 
         let unused_count: usize =
             crossed_streets.values().map(|streets| streets.len()).sum();
@@ -156,7 +96,7 @@ impl Scheduler for AdaptiveScheduler {
                     let unused_streets =
                         crossed_streets.get_mut(&inter_id).unwrap();
                     let unused_street_id =
-                        unused_streets.iter().copied().next().unwrap();
+                        unused_streets.iter().copied().min().unwrap();
                     schedule.add_street(inter_id, unused_street_id, 1);
                     unused_streets.remove(&unused_street_id);
                 }
@@ -166,3 +106,86 @@ impl Scheduler for AdaptiveScheduler {
         schedule
     }
 }
+
+// Light policy in which every street of an intersection is green for one
+// second per cycle, with the seconds assigned to streets in the order cars
+// reach them
+struct SlotLights<'a> {
+    simulation: &'a Simulation,
+    // Streets assigned to each second of the intersection's cycle
+    inter_order: HashMap<IntersectionId, Vec<Option<StreetId>>>,
+    // Streets not assigned to any second yet
+    crossed_streets: HashMap<IntersectionId, HashSet<StreetId>>,
+    // Second of the cycle assigned to each street
+    street_slots: HashMap<StreetId, usize>,
+    queue_policy: QueuePolicy,
+}
+
+impl<'a> SlotLights<'a> {
+    fn new(
+        simulation: &'a Simulation,
+        crossed_streets: HashMap<IntersectionId, HashSet<StreetId>>,
+        queue_policy: QueuePolicy,
+    ) -> Self {
+        let inter_order = crossed_streets
+            .iter()
+            .map(|(&inter_id, streets)| (inter_id, vec![None; streets.len()]))
+            .collect();
+
+        Self {
+            simulation,
+            inter_order,
+            crossed_streets,
+            street_slots: HashMap::new(),
+            queue_policy,
+        }
+    }
+}
+
+impl LightPolicy for SlotLights<'_> {
+    fn next_green(&mut self, street_id: StreetId, time: Time) -> Option<Time> {
+        let inter_id = self.simulation.streets[street_id].end_intersection;
+        let order = self.inter_order.get_mut(&inter_id)?;
+        let cycle = order.len();
+        let slot_pos = usize::try_from(time).unwrap() % cycle;
+
+        if let Some(&street_slot) = self.street_slots.get(&street_id) {
+            // The street is already assigned a slot: wait for it
+            let wait = (street_slot + cycle - slot_pos) % cycle;
+            return Some(time + Time::try_from(wait).unwrap());
+        }
+
+        if !self
+            .crossed_streets
+            .get(&inter_id)
+            .unwrap()
+            .contains(&street_id)
+        {
+            // The street does not need to be open (the car will never finish
+            // in time)
+            return None;
+        }
+
+        if order[slot_pos].is_some() {
+            // The current slot is already assigned to a different street, try
+            // again on the next slot that is still free
+            let wait = (1..cycle)
+                .find(|wait| order[(slot_pos + wait) % cycle].is_none())?;
+            return Some(time + Time::try_from(wait).unwrap());
+        }
+
+        // Assign the current slot to the street and let the car go through
+        order[slot_pos] = Some(street_id);
+        self.crossed_streets
+            .get_mut(&inter_id)
+            .unwrap()
+            .remove(&street_id);
+        self.street_slots.insert(street_id, slot_pos);
+        Some(time)
+    }
+
+    fn prioritize(&mut self, time: Time, queues: &mut [WaitingQueue]) {
+        // Longest queues first by default
+        self.queue_policy.sort(self.simulation, time, queues);
+    }
+}
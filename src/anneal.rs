@@ -0,0 +1,520 @@
+use super::*;
+use crate::engine::{simulate_with_trace, SimulationTrace};
+use crate::improve::Improver;
+use crate::intersect::{reorder_intersection, QueueOrder, QueuePolicy};
+use crate::sched::Schedule;
+use crate::seed::SeedSource;
+use log::{debug, info};
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use std::collections::BTreeSet;
+use std::iter::once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub struct SimulatedAnnealingImprover {
+    initial_temp: f64,
+    min_temp: f64,
+    cooling_rate: f64,
+    max_add_time: Time,
+    max_sub_time: Time,
+    queue_order: QueueOrder,
+    seeds: SeedSource,
+}
+
+impl Default for SimulatedAnnealingImprover {
+    fn default() -> Self {
+        Self {
+            initial_temp: 100.0,
+            min_temp: 0.5,
+            cooling_rate: 0.995,
+            max_add_time: 2,
+            max_sub_time: 1,
+            queue_order: QueueOrder::Random,
+            seeds: SeedSource::default(),
+        }
+    }
+}
+
+impl SimulatedAnnealingImprover {
+    pub fn set_initial_temp(&mut self, initial_temp: f64) {
+        self.initial_temp = initial_temp;
+    }
+
+    pub fn set_min_temp(&mut self, min_temp: f64) {
+        self.min_temp = min_temp;
+    }
+
+    pub fn set_cooling_rate(&mut self, cooling_rate: f64) {
+        self.cooling_rate = cooling_rate;
+    }
+
+    pub fn set_max_add_time(&mut self, max_add_time: Time) {
+        self.max_add_time = max_add_time;
+    }
+
+    pub fn set_max_sub_time(&mut self, max_sub_time: Time) {
+        self.max_sub_time = max_sub_time;
+    }
+
+    pub fn set_queue_order(&mut self, queue_order: QueueOrder) {
+        self.queue_order = queue_order;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seeds = SeedSource::new(seed);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Move {
+    AddTime(StreetId, Time),
+    SubTime(StreetId, Time),
+    Reorder(QueuePolicy),
+    Shuffle,
+    AddStreet(StreetId),
+    RemoveStreet(StreetId),
+}
+
+// Number of intersections changed since the last full simulation above which
+// the trace is refreshed, as moves then re-simulate too many of them
+const MAX_CHANGED: usize = 8;
+
+// Current schedule of the annealing process, along with the simulation
+// results used to pick and evaluate moves
+struct State<'a> {
+    schedule: Schedule<'a>,
+    score: Score,
+    // Trace of the last full simulation, and intersections changed since
+    // then; moves are scored by re-simulating these and the moved one
+    trace: SimulationTrace,
+    changed: BTreeSet<IntersectionId>,
+    // Intersections with non-zero wait times and their total wait time, as of
+    // the last full simulation
+    intersections: Vec<(IntersectionId, Time)>,
+    // Streets with non-zero wait times of each intersection
+    waiting: HashMap<IntersectionId, Vec<StreetId>>,
+}
+
+impl<'a> State<'a> {
+    fn new(schedule: Schedule<'a>) -> Self {
+        let (stats, trace) = simulate_with_trace(&schedule);
+        let mut inter_wait: HashMap<IntersectionId, Time> = HashMap::new();
+        let mut waiting: HashMap<IntersectionId, Vec<StreetId>> =
+            HashMap::new();
+        for (&street_id, &time) in stats.total_wait_time.iter() {
+            let inter_id = schedule.get_intersection_id(street_id).unwrap();
+            *inter_wait.entry(inter_id).or_insert(0) += time;
+            waiting.entry(inter_id).or_default().push(street_id);
+        }
+        let mut intersections: Vec<(IntersectionId, Time)> =
+            inter_wait.into_iter().collect();
+        intersections.sort_unstable();
+        for streets in waiting.values_mut() {
+            streets.sort_unstable();
+        }
+
+        Self {
+            schedule,
+            score: stats.score,
+            trace,
+            changed: BTreeSet::new(),
+            intersections,
+            waiting,
+        }
+    }
+
+    // Intersections to re-simulate for a move on the given intersection
+    fn changed_with(&self, inter_id: IntersectionId) -> Vec<IntersectionId> {
+        self.changed.iter().copied().chain(once(inter_id)).collect()
+    }
+
+    // Move to a schedule that differs from the current one in the given
+    // intersection, with its score
+    fn update(
+        self,
+        schedule: Schedule<'a>,
+        inter_id: IntersectionId,
+        score: Score,
+    ) -> Self {
+        let unchanged = schedule
+            .intersections
+            .get(&inter_id)
+            .map(|inter| &inter.turns)
+            == self
+                .schedule
+                .intersections
+                .get(&inter_id)
+                .map(|inter| &inter.turns);
+        if unchanged {
+            return self;
+        }
+
+        let mut changed = self.changed;
+        changed.insert(inter_id);
+        if changed.len() > MAX_CHANGED {
+            return Self::new(schedule);
+        }
+        Self {
+            schedule,
+            score,
+            changed,
+            ..self
+        }
+    }
+}
+
+impl Improver for SimulatedAnnealingImprover {
+    fn improve<'a>(
+        &self,
+        abort_flag: Arc<AtomicBool>,
+        schedule: Schedule<'a>,
+    ) -> Option<(Schedule<'a>, Score)> {
+        info!(
+            "Simulated annealing improver: {} initial temperature, {} minimum \
+            temperature, {} cooling rate, {} max additional time, {} max \
+            subtracted time",
+            self.initial_temp,
+            self.min_temp,
+            self.cooling_rate,
+            self.max_add_time,
+            self.max_sub_time,
+        );
+
+        let mut rng = StdRng::seed_from_u64(self.seeds.next_seed());
+        let mut state = State::new(schedule);
+        let initial_score = state.score;
+        let mut best_score = initial_score;
+        let mut best_sched = None;
+
+        let mut temp = self.initial_temp;
+        let mut steps = 0;
+        let mut accepted = 0;
+        while temp > self.min_temp {
+            if abort_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            if state.intersections.is_empty() {
+                // No car waits at any traffic light
+                break;
+            }
+            temp *= self.cooling_rate;
+            steps += 1;
+
+            let (inter_id, new_move) = match self.pick_move(&state, &mut rng) {
+                Some(picked) => picked,
+                None => continue,
+            };
+            let mut new_schedule = state.schedule.clone();
+            let new_score = apply_move(
+                &mut new_schedule,
+                &state.trace,
+                &state.changed_with(inter_id),
+                inter_id,
+                new_move,
+                &mut rng,
+            );
+
+            let curr_score = state.score;
+            let accept = accept_move(curr_score, new_score, temp, &mut rng);
+            debug!(
+                "Simulated annealing: {:?} on intersection {}, score {} -> {}, \
+                temperature {:.3}, {}",
+                new_move,
+                inter_id,
+                curr_score,
+                new_score,
+                temp,
+                if accept { "accepted" } else { "rejected" },
+            );
+            if !accept {
+                continue;
+            }
+
+            accepted += 1;
+            state = state.update(new_schedule, inter_id, new_score);
+            if state.score > best_score {
+                best_score = state.score;
+                best_sched = Some(state.schedule.clone());
+                info!(
+                    "=> New best score after {:?} on intersection {} \
+                    (temperature {:.3}): {}",
+                    new_move, inter_id, temp, best_score,
+                );
+            }
+        }
+
+        info!(
+            "Simulated annealing: {} steps, {} accepted moves, final score {}, \
+            best score {} (initial {})",
+            steps, accepted, state.score, best_score, initial_score,
+        );
+
+        best_sched.map(|sched| (sched, best_score))
+    }
+}
+
+impl SimulatedAnnealingImprover {
+    // Pick an intersection (the longer its total wait time, the more likely)
+    // and a move to apply to it
+    fn pick_move<R: Rng>(
+        &self,
+        state: &State,
+        rng: &mut R,
+    ) -> Option<(IntersectionId, Move)> {
+        let weights = WeightedIndex::new(
+            state.intersections.iter().map(|&(_, wait)| wait),
+        )
+        .ok()?;
+        let inter_id = state.intersections[weights.sample(rng)].0;
+        let schedule = &state.schedule;
+        let turns = match schedule.intersections.get(&inter_id) {
+            Some(inter) => &inter.turns,
+            None => return None,
+        };
+        let waiting = state.waiting.get(&inter_id)?;
+
+        let new_move = match rng.gen_range(0..6) {
+            0 if self.max_add_time > 0 => {
+                let &street_id = waiting
+                    .iter()
+                    .filter(|&&street_id| {
+                        schedule.get_street_time(street_id).is_some()
+                    })
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .choose(rng)?;
+                Move::AddTime(street_id, rng.gen_range(1..=self.max_add_time))
+            }
+            1 if self.max_sub_time > 0 => {
+                let sub_time = rng.gen_range(1..=self.max_sub_time);
+                let &(street_id, _) = turns
+                    .iter()
+                    .filter(|&&(_, time)| time > sub_time)
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .choose(rng)?;
+                Move::SubTime(street_id, sub_time)
+            }
+            2 => Move::Reorder(self.queue_order.policy(rng)),
+            3 if turns.len() > 1 => Move::Shuffle,
+            4 => {
+                let &street_id = waiting
+                    .iter()
+                    .filter(|&&street_id| {
+                        schedule.get_street_time(street_id).is_none()
+                    })
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .choose(rng)?;
+                Move::AddStreet(street_id)
+            }
+            5 if turns.len() > 1 => {
+                let &(street_id, _) = turns.choose(rng)?;
+                Move::RemoveStreet(street_id)
+            }
+            _ => return None,
+        };
+        Some((inter_id, new_move))
+    }
+}
+
+// Whether to move to a schedule with the given score: always if it is not
+// worse, otherwise with a probability that falls as the temperature cools
+fn accept_move<R: Rng>(
+    curr_score: Score,
+    new_score: Score,
+    temp: f64,
+    rng: &mut R,
+) -> bool {
+    if new_score >= curr_score {
+        return true;
+    }
+    let delta = f64::from(curr_score - new_score);
+    rng.gen::<f64>() < (-delta / temp).exp()
+}
+
+// Apply a move to a schedule and return its new score, re-simulating the
+// given intersections (the moved one included) from the trace
+fn apply_move<R: Rng>(
+    schedule: &mut Schedule,
+    trace: &SimulationTrace,
+    changed: &[IntersectionId],
+    inter_id: IntersectionId,
+    new_move: Move,
+    rng: &mut R,
+) -> Score {
+    match new_move {
+        Move::AddTime(street_id, time) => {
+            schedule.add_street_time(street_id, time);
+        }
+        Move::SubTime(street_id, time) => {
+            schedule.sub_street_time(street_id, time);
+        }
+        Move::Reorder(queue_policy) => {
+            return reorder_intersection(schedule, inter_id, queue_policy);
+        }
+        Move::Shuffle => {
+            schedule.shuffle_intersection(inter_id, rng);
+        }
+        Move::AddStreet(street_id) => {
+            schedule.add_street(inter_id, street_id, 1);
+        }
+        Move::RemoveStreet(street_id) => {
+            let inter = schedule.intersections.get_mut(&inter_id).unwrap();
+            inter.remove_street(street_id);
+        }
+    }
+    schedule.rescore(trace, changed.iter().copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::simulation;
+
+    // Ring of 12 intersections with streets both ways, and cars driving
+    // random walks around it
+    fn ring<R: Rng>(rng: &mut R) -> Simulation {
+        let num_intersections = 12;
+        let streets: Vec<(IntersectionId, IntersectionId, Time)> = (0
+            ..num_intersections)
+            .flat_map(|inter_id| {
+                let next = (inter_id + 1) % num_intersections;
+                vec![(inter_id, next, 1), (next, inter_id, 1)]
+            })
+            .collect();
+        let car_paths: Vec<Vec<StreetId>> = (0..60)
+            .map(|_| {
+                let mut path = vec![rng.gen_range(0..streets.len())];
+                for _ in 0..8 {
+                    let end = streets[*path.last().unwrap()].1;
+                    let next: Vec<StreetId> = (0..streets.len())
+                        .filter(|&street_id| streets[street_id].0 == end)
+                        .collect();
+                    path.push(*next.choose(rng).unwrap());
+                }
+                path
+            })
+            .collect();
+        let car_paths: Vec<&[StreetId]> =
+            car_paths.iter().map(|path| path.as_slice()).collect();
+        simulation(30, num_intersections, &streets, &car_paths, 100)
+    }
+
+    // Schedule of a ring with both incoming streets of each intersection
+    fn ring_schedule(simulation: &Simulation) -> Schedule<'_> {
+        let mut schedule = Schedule::new(simulation);
+        for (street_id, street) in simulation.streets.iter().enumerate() {
+            schedule.add_street(street.end_intersection, street_id, 1);
+        }
+        schedule
+    }
+
+    #[test]
+    fn worse_moves() {
+        // Two cars meeting at intersection 1, both arriving in time
+        let simulation = simulation(
+            10,
+            3,
+            &[(0, 1, 1), (2, 1, 1), (1, 0, 1)],
+            &[&[0, 2], &[1, 2]],
+            1000,
+        );
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 0, 1);
+        schedule.add_street(1, 1, 1);
+        let state = State::new(schedule);
+        assert_eq!(state.score, 1009 + 1008);
+
+        // The second car never leaves without its green light
+        let mut rng = StdRng::seed_from_u64(2021);
+        let mut new_schedule = state.schedule.clone();
+        let new_score = apply_move(
+            &mut new_schedule,
+            &state.trace,
+            &state.changed_with(1),
+            1,
+            Move::RemoveStreet(1),
+            &mut rng,
+        );
+        assert_eq!(new_score, 1009);
+        assert_eq!(new_schedule.score(), Ok(new_score));
+
+        // Accepted while hot, rejected once cooled down
+        let annealer = SimulatedAnnealingImprover::default();
+        for _ in 0..20 {
+            assert!(accept_move(state.score, new_score, 1e6, &mut rng));
+            assert!(!accept_move(
+                state.score,
+                new_score,
+                annealer.min_temp,
+                &mut rng
+            ));
+            assert!(accept_move(new_score, state.score, 1e-6, &mut rng));
+            assert!(accept_move(new_score, new_score, 1e-6, &mut rng));
+        }
+    }
+
+    #[test]
+    fn state_updates() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        let simulation = ring(&mut rng);
+        let mut state = State::new(ring_schedule(&simulation));
+        for inter_id in 0..simulation.num_intersections {
+            let mut new_schedule = state.schedule.clone();
+            new_schedule.add_street_time(2 * inter_id as StreetId + 1, 1);
+            let new_score = new_schedule
+                .rescore(&state.trace, state.changed_with(inter_id));
+            assert_eq!(new_schedule.score(), Ok(new_score));
+            state = state.update(new_schedule, inter_id, new_score);
+            assert_eq!(state.schedule.score(), Ok(state.score));
+
+            // The trace is refreshed once too many intersections changed
+            let num_changed = (inter_id as usize + 1) % (MAX_CHANGED + 1);
+            assert_eq!(state.changed.len(), num_changed);
+            if num_changed == 0 {
+                assert_eq!(state.trace.score, state.score);
+            }
+        }
+    }
+
+    #[test]
+    fn improved_scores() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..5 {
+            let simulation = ring(&mut rng);
+            let schedule = ring_schedule(&simulation);
+            let score = schedule.score().unwrap();
+            let mut annealer = SimulatedAnnealingImprover::default();
+            annealer.set_seed(rng.gen());
+            annealer.set_initial_temp(1000.0);
+            annealer.set_cooling_rate(0.99);
+            let abort_flag = Arc::new(AtomicBool::new(false));
+            let (new_schedule, new_score) =
+                annealer.improve(abort_flag, schedule).unwrap();
+            assert!(new_score > score);
+            assert_eq!(new_schedule.score(), Ok(new_score));
+        }
+    }
+
+    #[test]
+    fn no_better_schedule() {
+        // The second car waits for the first one, whatever the schedule
+        let simulation = simulation(
+            10,
+            3,
+            &[(0, 1, 1), (1, 2, 1)],
+            &[&[0, 1], &[0, 1]],
+            100,
+        );
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 0, 1);
+        assert_eq!(schedule.score(), Ok(109 + 108));
+
+        let mut annealer = SimulatedAnnealingImprover::default();
+        annealer.set_seed(2021);
+        let abort_flag = Arc::new(AtomicBool::new(false));
+        assert!(annealer.improve(abort_flag, schedule).is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use super::*;
+use crate::engine::intersection_lights;
+use crate::sched::{Intersection, Schedule, Scheduler};
+use crate::wave::street_wait;
+use log::info;
+use std::cmp::Reverse;
+
+// Scheduler based on free-flow arrival times: cars are driven along their
+// paths without ever waiting, which gives for every intersection the times at
+// which cars reach each incoming street's light; each intersection then gets
+// the cycle length, order and green times whose lights let these cars through
+// with the least waiting
+pub struct ArrivalScheduler {
+    // Candidate cycles give the busiest street of an intersection from 1 to
+    // max_scale seconds of green, and the other streets proportionally less
+    max_scale: Time,
+}
+
+impl Default for ArrivalScheduler {
+    fn default() -> Self {
+        Self { max_scale: 4 }
+    }
+}
+
+impl ArrivalScheduler {
+    pub fn set_max_scale(&mut self, max_scale: Time) {
+        self.max_scale = max_scale.max(1);
+    }
+}
+
+impl Scheduler for ArrivalScheduler {
+    fn schedule<'a>(&self, simulation: &'a Simulation) -> Schedule<'a> {
+        let arrivals = free_flow_arrivals(simulation);
+        let mut inter_ids: Vec<IntersectionId> =
+            arrivals.keys().copied().collect();
+        inter_ids.sort_unstable();
+
+        let mut schedule = Schedule::new(simulation);
+        let mut total_wait = 0;
+        for inter_id in inter_ids {
+            let (inter, wait) =
+                self.best_intersection(simulation, &arrivals[&inter_id]);
+            schedule.intersections.insert(inter_id, inter);
+            total_wait += wait;
+        }
+        info!(
+            "Arrival scheduler: {} intersections, {} sec free-flow wait time",
+            schedule.intersections.len(),
+            total_wait,
+        );
+        schedule
+    }
+}
+
+impl ArrivalScheduler {
+    // Schedule of an intersection that minimizes the wait time of cars
+    // arriving at the given times on each street
+    fn best_intersection(
+        &self,
+        simulation: &Simulation,
+        streets: &[(StreetId, Vec<Time>)],
+    ) -> (Intersection, u64) {
+        let max_cars = streets.iter().map(|(_, times)| times.len()).max();
+        let max_cars = max_cars.unwrap() as f64;
+
+        let mut best: Option<(Intersection, u64)> = None;
+        for scale in 1..=self.max_scale {
+            // Green times proportional to the number of cars
+            let times: Vec<Time> = streets
+                .iter()
+                .map(|(_, arrivals)| {
+                    let share = arrivals.len() as f64 / max_cars;
+                    ((f64::from(scale) * share).round() as Time).max(1)
+                })
+                .collect();
+            let cycle: Time = times.iter().sum();
+
+            // Streets in order of the start of their arrival peaks within the
+            // cycle, then every rotation of that order
+            let mut order: Vec<(Time, usize)> = streets
+                .iter()
+                .zip(times.iter())
+                .enumerate()
+                .map(|(idx, ((_, arrivals), &time))| {
+                    (peak_start(arrivals, time, cycle), idx)
+                })
+                .collect();
+            order.sort_unstable();
+            for rotation in 0..order.len() {
+                let mut inter = Intersection::default();
+                for &(_, idx) in
+                    order[rotation..].iter().chain(order[..rotation].iter())
+                {
+                    inter.add_street(streets[idx].0, times[idx]);
+                }
+                let wait = intersection_wait(simulation, &inter, streets);
+                if best.as_ref().is_none_or(|&(_, best_wait)| wait < best_wait)
+                {
+                    best = Some((inter, wait));
+                }
+            }
+        }
+        best.unwrap()
+    }
+}
+
+// For each intersection, the incoming streets crossed by cars (in order of
+// street ID) with the times at which cars reach their lights if they never
+// wait, in increasing order; cars that can't reach the end of their path in
+// time are ignored
+pub fn free_flow_arrivals(
+    simulation: &Simulation,
+) -> HashMap<IntersectionId, Vec<(StreetId, Vec<Time>)>> {
+    let mut street_arrivals: HashMap<StreetId, Vec<Time>> = HashMap::new();
+    for car_path in simulation.car_paths.iter() {
+        let travel_time: Time = car_path
+            .iter()
+            .skip(1)
+            .map(|&street_id| simulation.streets[street_id].travel_time)
+            .sum();
+        if travel_time > simulation.duration {
+            continue;
+        }
+
+        // The car starts at the end of its first street
+        let mut time = 0;
+        for (idx, &street_id) in car_path.iter().enumerate() {
+            if idx > 0 {
+                time += simulation.streets[street_id].travel_time;
+            }
+            if idx + 1 < car_path.len() {
+                street_arrivals.entry(street_id).or_default().push(time);
+            }
+        }
+    }
+
+    let mut arrivals: HashMap<IntersectionId, Vec<(StreetId, Vec<Time>)>> =
+        HashMap::new();
+    for (street_id, mut times) in street_arrivals.into_iter() {
+        times.sort_unstable();
+        let inter_id = simulation.streets[street_id].end_intersection;
+        arrivals
+            .entry(inter_id)
+            .or_default()
+            .push((street_id, times));
+    }
+    for streets in arrivals.values_mut() {
+        streets.sort_unstable_by_key(|&(street_id, _)| street_id);
+    }
+    arrivals
+}
+
+// Start within the cycle of the green window of the given length that covers
+// the most arrivals (the earliest one on ties)
+fn peak_start(arrivals: &[Time], green_time: Time, cycle: Time) -> Time {
+    let mut histogram = vec![0; cycle as usize];
+    for &time in arrivals.iter() {
+        histogram[(time % cycle) as usize] += 1;
+    }
+    (0..cycle)
+        .max_by_key(|&start| {
+            let covered: usize = (start..start + green_time)
+                .map(|time| histogram[(time % cycle) as usize])
+                .sum();
+            (covered, Reverse(start))
+        })
+        .unwrap()
+}
+
+// Wait time at an intersection of cars arriving at the given times
+fn intersection_wait(
+    simulation: &Simulation,
+    inter: &Intersection,
+    streets: &[(StreetId, Vec<Time>)],
+) -> u64 {
+    intersection_lights(inter)
+        .into_iter()
+        .map(|(street_id, light)| {
+            // Streets are in order of street ID
+            let idx = streets
+                .binary_search_by_key(&street_id, |&(id, _)| id)
+                .unwrap();
+            let arrivals = streets[idx].1.iter().copied();
+            street_wait(&light, arrivals, simulation.duration)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{example, simulation};
+    use crate::validate::validate_schedule;
+
+    #[test]
+    fn peaks() {
+        // Arrivals at 5 and 10 come back at 0 on a 5 second cycle, so the
+        // best window wraps around the end of the cycle
+        assert_eq!(peak_start(&[4, 5, 10], 2, 5), 4);
+        assert_eq!(peak_start(&[4, 5, 10], 1, 5), 0);
+        // Earliest start on ties
+        assert_eq!(peak_start(&[1, 3], 1, 4), 1);
+        assert_eq!(peak_start(&[], 1, 3), 0);
+    }
+
+    #[test]
+    fn streets_in_order_of_peaks() {
+        let simulation = simulation(
+            10,
+            3,
+            &[(0, 2, 1), (1, 2, 1), (2, 0, 1)],
+            &[&[0, 2], &[1, 2]],
+            100,
+        );
+        // Street 0 gets its cars on odd seconds, street 1 on even seconds
+        let streets = vec![(0, vec![1, 3, 5]), (1, vec![0, 2, 4])];
+        let scheduler = ArrivalScheduler::default();
+        let (inter, wait) = scheduler.best_intersection(&simulation, &streets);
+        assert_eq!(inter.turns, [(1, 1), (0, 1)]);
+        assert_eq!(wait, 0);
+
+        // Cars arriving at the same times on both streets: the second street
+        // makes its cars wait whatever the cycle
+        let streets = vec![(0, vec![0, 1]), (1, vec![0, 1])];
+        let (inter, wait) = scheduler.best_intersection(&simulation, &streets);
+        assert_eq!(inter.turns, [(0, 1), (1, 1)]);
+        assert_eq!(wait, 1 + 1 + 2);
+    }
+
+    #[test]
+    fn example_schedule() {
+        let simulation = example();
+        let arrivals = free_flow_arrivals(&simulation);
+        assert_eq!(arrivals[&0], vec![(0, vec![0])]);
+        assert_eq!(arrivals[&2], vec![(4, vec![3, 4])]);
+
+        let schedule = ArrivalScheduler::default().schedule(&simulation);
+        let s = schedule.to_string();
+        assert_eq!(validate_schedule(&simulation, &s), vec![]);
+        assert!(schedule.score().unwrap() >= 1002);
+    }
+}
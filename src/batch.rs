@@ -0,0 +1,273 @@
+use super::*;
+use crate::scoring::{score_files, ScoreResult};
+use log::info;
+use rayon::prelude::*;
+use std::fs::{create_dir_all, rename, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Runs the same pipeline (scheduler, improver and their options) over several
+// inputs, each one in a child process of the given executable; the cores are
+// shared between the children running at the same time
+pub struct BatchRunner {
+    exe: PathBuf,
+    pipeline: Vec<String>,
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    jobs: usize,
+}
+
+// Outcome of the pipeline on one input
+pub struct BatchResult {
+    pub name: String,
+    pub result: Result<(Score, PathBuf), String>,
+}
+
+impl BatchRunner {
+    pub fn new(exe: &Path, pipeline: Vec<String>) -> Self {
+        Self {
+            exe: exe.to_path_buf(),
+            pipeline,
+            input_dir: PathBuf::from("input"),
+            output_dir: PathBuf::from("output"),
+            jobs: num_cores(),
+        }
+    }
+
+    pub fn set_input_dir(&mut self, input_dir: &Path) {
+        self.input_dir = input_dir.to_path_buf();
+    }
+
+    pub fn set_output_dir(&mut self, output_dir: &Path) {
+        self.output_dir = output_dir.to_path_buf();
+    }
+
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+
+    // Run the pipeline over inputs such as "a" (read from "<input dir>/a.txt")
+    // and write each result as "<output dir>/a_<score>.txt", with its log
+    pub fn run(
+        &self,
+        abort_flag: Arc<AtomicBool>,
+        names: &[&str],
+    ) -> Vec<BatchResult> {
+        if let Err(err) = create_dir_all(&self.output_dir) {
+            let error = format!(
+                "Failed to create '{}': {}",
+                self.output_dir.display(),
+                err
+            );
+            return names
+                .iter()
+                .map(|name| BatchResult {
+                    name: name.to_string(),
+                    result: Err(error.clone()),
+                })
+                .collect();
+        }
+
+        info!(
+            "Batch: running '{}' over {} inputs, {} at a time",
+            self.pipeline.join(" "),
+            names.len(),
+            self.jobs,
+        );
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            names
+                .par_iter()
+                .map(|&name| {
+                    let result = if abort_flag.load(Ordering::SeqCst) {
+                        Err("Not started (termination requested)".to_string())
+                    } else {
+                        self.run_input(name)
+                    };
+                    BatchResult {
+                        name: name.to_string(),
+                        result,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    fn run_input(&self, name: &str) -> Result<(Score, PathBuf), String> {
+        let input = self.input_dir.join(format!("{}.txt", name));
+        let tmp_output = self.output_dir.join(format!(
+            "{}_running_{}.txt",
+            name,
+            std::process::id()
+        ));
+        let tmp_log = tmp_output.with_extension("log");
+
+        let log_file = File::create(&tmp_log).map_err(|err| {
+            format!("Failed to create '{}': {}", tmp_log.display(), err)
+        })?;
+        let log_clone = log_file.try_clone().map_err(|err| {
+            format!("Failed to create '{}': {}", tmp_log.display(), err)
+        })?;
+
+        info!("Batch: starting input '{}'", name);
+        let threads = (num_cores() / self.jobs).max(1);
+        let status = Command::new(&self.exe)
+            .env("RAYON_NUM_THREADS", threads.to_string())
+            .arg(&input)
+            .args(&self.pipeline)
+            .arg("--output")
+            .arg(&tmp_output)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(log_file))
+            .stderr(Stdio::from(log_clone))
+            .status()
+            .map_err(|err| {
+                format!("Failed to run '{}': {}", self.exe.display(), err)
+            })?;
+        if !status.success() {
+            return Err(format!(
+                "Pipeline failed ({}), see '{}'",
+                status,
+                tmp_log.display()
+            ));
+        }
+
+        // Score the result again rather than trusting the log
+        let score = score_output(&input, &tmp_output)?;
+
+        let (output, log) = output_paths(&self.output_dir, name, score);
+        for (from, to) in [(&tmp_output, output.clone()), (&tmp_log, log)] {
+            rename(from, &to).map_err(|err| {
+                format!("Failed to write '{}': {}", to.display(), err)
+            })?;
+        }
+        info!("Batch: input '{}' done, score {}", name, score);
+        Ok((score, output))
+    }
+}
+
+// Schedule file and log of the result of an input
+fn output_paths(
+    output_dir: &Path,
+    name: &str,
+    score: Score,
+) -> (PathBuf, PathBuf) {
+    let output = output_dir.join(format!("{}_{}.txt", name, score));
+    let log = output.with_extension("log");
+    (output, log)
+}
+
+// Score of a schedule file written for an input file
+fn score_output(input: &Path, output: &Path) -> Result<Score, String> {
+    let simulation: Simulation = std::fs::read_to_string(input)
+        .map_err(|err| {
+            format!("Failed to read '{}': {}", input.display(), err)
+        })?
+        .parse()?;
+    let output = output.to_string_lossy();
+    match score_files(&simulation, &[&output]).pop().unwrap().result {
+        ScoreResult::Scored { score, .. } => Ok(score),
+        ScoreResult::Failed { error } => Err(error),
+    }
+}
+
+fn num_cores() -> usize {
+    std::thread::available_parallelism()
+        .map(|cores| cores.get())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{temp_dir, EXAMPLE, EXAMPLE_SCHEDULE};
+    use std::fs::{read_dir, write};
+
+    #[test]
+    fn output_names() {
+        let (output, log) = output_paths(Path::new("out"), "a", 1002);
+        assert_eq!(output, Path::new("out/a_1002.txt"));
+        assert_eq!(log, Path::new("out/a_1002.log"));
+    }
+
+    #[test]
+    fn output_scores() {
+        let dir = temp_dir("batch-scores");
+        let input = dir.join("a.txt");
+        let output = dir.join("a_out.txt");
+        write(&input, EXAMPLE).unwrap();
+        write(&output, EXAMPLE_SCHEDULE).unwrap();
+        assert_eq!(score_output(&input, &output), Ok(1002));
+
+        // Missing or invalid files
+        assert!(score_output(&input, &dir.join("b_out.txt"))
+            .unwrap_err()
+            .starts_with("Failed to read"));
+        assert!(score_output(&dir.join("b.txt"), &output)
+            .unwrap_err()
+            .starts_with("Failed to read"));
+        write(&output, "1\n0\n1\nrue-de-paris 1\n").unwrap();
+        assert!(score_output(&input, &output).is_err());
+    }
+
+    // Results of a batch of the example run by the given command
+    fn run_batch(
+        exe: &str,
+        dir_name: &str,
+        abort: bool,
+    ) -> (Vec<BatchResult>, Vec<String>) {
+        let dir = temp_dir(dir_name);
+        write(dir.join("a.txt"), EXAMPLE).unwrap();
+        let output_dir = dir.join("output");
+        let mut runner = BatchRunner::new(Path::new(exe), vec![]);
+        runner.set_input_dir(&dir);
+        runner.set_output_dir(&output_dir);
+        runner.set_jobs(1);
+        let abort_flag = Arc::new(AtomicBool::new(abort));
+        let results = runner.run(abort_flag, &["a"]);
+        let mut files: Vec<String> = read_dir(&output_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort_unstable();
+        (results, files)
+    }
+
+    #[test]
+    fn failed_pipelines() {
+        let log = format!("a_running_{}.log", std::process::id());
+
+        // The child fails: its log is kept
+        let (results, files) = run_batch("false", "batch-false", false);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].name, "a");
+        let error = results[0].result.as_ref().unwrap_err();
+        assert!(error.starts_with("Pipeline failed"), "{}", error);
+        assert_eq!(files, vec![log.clone()]);
+
+        // The child succeeds without writing a schedule
+        let (results, files) = run_batch("true", "batch-true", false);
+        let error = results[0].result.as_ref().unwrap_err();
+        assert!(error.starts_with("Failed to read"), "{}", error);
+        assert_eq!(files, vec![log]);
+
+        // The child can't be run
+        let (results, _) =
+            run_batch("/nonexistent/hashcode2021", "batch-missing", false);
+        let error = results[0].result.as_ref().unwrap_err();
+        assert!(error.starts_with("Failed to run"), "{}", error);
+
+        // Termination requested before starting
+        let (results, files) = run_batch("true", "batch-abort", true);
+        assert_eq!(
+            results[0].result,
+            Err("Not started (termination requested)".to_string())
+        );
+        assert!(files.is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+use super::*;
+use crate::sched::{Schedule, Scheduler};
+use crate::seed::derive_seed;
+use log::{info, warn};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Seed of the index-th of several parallel runs; the first run uses the seed
+// itself, so that a single run gives the same result as a plain run
+pub fn run_seed(seed: u64, index: usize) -> u64 {
+    if index == 0 {
+        seed
+    } else {
+        derive_seed(seed, index as u64)
+    }
+}
+
+// Run a scheduler several times in parallel, each run with a scheduler built
+// from its own seed, and return the k best schedules with the index of their
+// run and their scores, best first; runs that haven't started when
+// termination is requested are skipped (the first run always completes)
+pub fn best_schedules<'a, F>(
+    abort_flag: Arc<AtomicBool>,
+    simulation: &'a Simulation,
+    runs: u32,
+    k: usize,
+    seed: u64,
+    build_scheduler: F,
+) -> Vec<(usize, Schedule<'a>, Score)>
+where
+    F: Fn(u64) -> Box<dyn Scheduler> + Sync,
+{
+    let mut results: Vec<(usize, Schedule<'a>, Score)> = (0..runs as usize)
+        .into_par_iter()
+        .filter_map(|idx| {
+            if idx > 0 && abort_flag.load(Ordering::SeqCst) {
+                return None;
+            }
+            let scheduler = build_scheduler(run_seed(seed, idx));
+            let schedule = scheduler.schedule(simulation);
+            let score = schedule.score().unwrap_or(0);
+            info!("Schedule {}/{}: score {}", idx + 1, runs, score);
+            Some((idx, schedule, score))
+        })
+        .collect();
+    if results.len() < runs as usize {
+        warn!(
+            "Termination request received after {} schedules",
+            results.len()
+        );
+    }
+
+    results.sort_unstable_by_key(|&(idx, _, score)| (Reverse(score), idx));
+    results.truncate(k.max(1));
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{random_schedule, random_simulation};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Random schedule of a fixed simulation for each seed
+    struct RandomScheduler(u64);
+
+    impl Scheduler for RandomScheduler {
+        fn schedule<'a>(&self, simulation: &'a Simulation) -> Schedule<'a> {
+            random_schedule(simulation, &mut StdRng::seed_from_u64(self.0))
+        }
+    }
+
+    #[test]
+    fn best_runs() {
+        let simulation = random_simulation(&mut StdRng::seed_from_u64(2021));
+        let build_scheduler = |seed: u64| -> Box<dyn Scheduler> {
+            Box::new(RandomScheduler(seed))
+        };
+        assert_eq!(run_seed(2021, 0), 2021);
+
+        let abort_flag = Arc::new(AtomicBool::new(false));
+        let best = best_schedules(
+            abort_flag,
+            &simulation,
+            20,
+            5,
+            2021,
+            build_scheduler,
+        );
+        assert_eq!(best.len(), 5);
+        for pair in best.windows(2) {
+            assert!(
+                (Reverse(pair[0].2), pair[0].0)
+                    < (Reverse(pair[1].2), pair[1].0)
+            );
+        }
+
+        // Each schedule is the one built by the seed of its run
+        let scores: Vec<Score> = (0..20)
+            .map(|idx| {
+                let scheduler = build_scheduler(run_seed(2021, idx));
+                scheduler.schedule(&simulation).score().unwrap()
+            })
+            .collect();
+        assert_eq!(best[0].2, *scores.iter().max().unwrap());
+        for (idx, schedule, score) in best.iter() {
+            let scheduler = build_scheduler(run_seed(2021, *idx));
+            let expected = scheduler.schedule(&simulation);
+            assert_eq!(schedule.to_string(), expected.to_string());
+            assert_eq!(*score, scores[*idx]);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use log::warn;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Wall-clock time budget of a run, split into stages
+pub struct TimeBudget {
+    limit: Option<Duration>,
+    start: Instant,
+    stage_start: Instant,
+    stages: Vec<(String, Duration)>,
+}
+
+impl TimeBudget {
+    pub fn new(limit: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            start: now,
+            stage_start: now,
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<Duration> {
+        self.limit
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.limit
+            .map(|limit| limit.checked_sub(self.elapsed()).unwrap_or_default())
+    }
+
+    // Set the abort flag once the time limit is reached, if there is one
+    pub fn start_timer(&self, abort_flag: Arc<AtomicBool>) {
+        let (limit, remaining) = match (self.limit, self.remaining()) {
+            (Some(limit), Some(remaining)) => (limit, remaining),
+            _ => return,
+        };
+        thread::spawn(move || {
+            thread::sleep(remaining);
+            warn!("Time limit of {} reached", format_duration(limit));
+            abort_flag.store(true, Ordering::SeqCst);
+        });
+    }
+
+    // Record the time spent since the previous stage ended
+    pub fn end_stage(&mut self, name: &str) {
+        let now = Instant::now();
+        self.stages
+            .push((name.to_string(), now.duration_since(self.stage_start)));
+        self.stage_start = now;
+    }
+}
+
+impl Display for TimeBudget {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let total = self.elapsed();
+        let budget = self.limit.unwrap_or(total).as_secs_f64();
+        let percent = |duration: Duration| {
+            if budget > 0.0 {
+                100.0 * duration.as_secs_f64() / budget
+            } else {
+                0.0
+            }
+        };
+        let width = self
+            .stages
+            .iter()
+            .map(|(name, _)| name.len())
+            .chain(vec!["Time limit".len(), "Total".len()])
+            .max()
+            .unwrap();
+
+        if let Some(limit) = self.limit {
+            writeln!(
+                f,
+                "{:width$}: {}",
+                "Time limit",
+                format_duration(limit),
+                width = width,
+            )?;
+        }
+        for (name, duration) in self.stages.iter() {
+            writeln!(
+                f,
+                "{:width$}: {} ({:.1}%)",
+                name,
+                format_duration(*duration),
+                percent(*duration),
+                width = width,
+            )?;
+        }
+        write!(
+            f,
+            "{:width$}: {} ({:.1}%)",
+            "Total",
+            format_duration(total),
+            percent(total),
+            width = width,
+        )
+    }
+}
+
+// Parse durations such as "90", "90s", "10m", "1h30m" or "2.5h"
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("Empty duration".to_string());
+    }
+
+    let mut secs = 0.0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return Err(format!("Invalid duration unit '{}'", c)),
+        };
+        secs += parse_number(&number)? * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        // Seconds by default
+        secs += parse_number(&number)?;
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+fn parse_number(number: &str) -> Result<f64, String> {
+    number
+        .parse()
+        .map_err(|_| format!("Invalid duration value '{}'", number))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    let hours = (secs / 3600.0).floor();
+    let mins = ((secs - hours * 3600.0) / 60.0).floor();
+    let secs = secs - hours * 3600.0 - mins * 60.0;
+    if hours > 0.0 {
+        format!("{}h {}m {:.1}s", hours, mins, secs)
+    } else if mins > 0.0 {
+        format!("{}m {:.1}s", mins, secs)
+    } else {
+        format!("{:.1}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        for (s, secs) in [
+            ("90", 90.0),
+            ("90s", 90.0),
+            ("10m", 600.0),
+            ("1h30m", 5400.0),
+            ("2.5h", 9000.0),
+            ("1m30", 90.0),
+            (" 45s ", 45.0),
+        ] {
+            assert_eq!(parse_duration(s), Ok(Duration::from_secs_f64(secs)));
+        }
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        for s in ["", "  ", "10x", "m", "1.2.3s", "-5s"] {
+            assert!(parse_duration(s).is_err(), "'{}' parsed", s);
+        }
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1.5s");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1m 30.0s");
+        assert_eq!(format_duration(Duration::from_secs(5430)), "1h 30m 30.0s");
+    }
+
+    #[test]
+    fn remaining_time() {
+        assert_eq!(TimeBudget::new(None).remaining(), None);
+        let budget = TimeBudget::new(Some(Duration::from_secs(3600)));
+        assert!(budget.remaining().unwrap() <= Duration::from_secs(3600));
+        let budget = TimeBudget::new(Some(Duration::ZERO));
+        assert_eq!(budget.remaining(), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,359 @@
+use super::*;
+use crate::engine::{run, FixedLights, Observer};
+use crate::sched::Schedule;
+use serde::Serialize;
+use std::io::{self, Write};
+
+// Journey of a car through the city during a simulation
+#[derive(Serialize)]
+pub struct CarTrace<'a> {
+    pub car_id: CarId,
+    pub status: CarStatus,
+    // Time the car reached the end of its path, if it did
+    pub arrival: Option<Time>,
+    pub points: Score,
+    // Streets the car entered, in order
+    pub streets: Vec<StreetVisit<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct StreetVisit<'a> {
+    pub street_id: StreetId,
+    pub street_name: &'a str,
+    // Time the car entered the street (cars start at the end of their first
+    // street, at time 0)
+    pub entered: Time,
+    // Time the car reached the traffic light at the end of the street
+    pub reached_light: Option<Time>,
+    // Time the car crossed the intersection at the end of the street
+    pub crossed: Option<Time>,
+    // Seconds the car waited at the traffic light (until the end of the
+    // simulation if it never crossed)
+    pub wait_time: Option<Time>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CarStatus {
+    // Car reached the end of its path
+    Arrived,
+    // Car can't reach the end of its path in time even if all lights are green
+    TooFar,
+    // Car was waiting at a traffic light that is never green
+    NoGreenLight,
+    // Car was waiting at a traffic light when the simulation ended
+    Waiting,
+    // Car was driving along a street when the simulation ended
+    Driving,
+}
+
+impl CarStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CarStatus::Arrived => "arrived",
+            CarStatus::TooFar => "too_far",
+            CarStatus::NoGreenLight => "no_green_light",
+            CarStatus::Waiting => "waiting",
+            CarStatus::Driving => "driving",
+        }
+    }
+}
+
+pub fn trace_cars<'a>(schedule: &Schedule<'a>) -> Vec<CarTrace<'a>> {
+    let simulation = schedule.simulation;
+    let mut policy = FixedLights::new(schedule);
+    let mut tracer = CarTracer::new(simulation);
+    run(simulation, &mut policy, &mut tracer);
+
+    tracer
+        .visits
+        .into_iter()
+        .zip(tracer.arrivals)
+        .enumerate()
+        .map(|(car_id, (visits, arrival))| {
+            let streets: Vec<StreetVisit> = visits
+                .into_iter()
+                .zip(simulation.car_paths[car_id].iter())
+                .filter_map(
+                    |((entered, reached_light, crossed), &street_id)| {
+                        let entered = entered?;
+                        let wait_time = reached_light.map(|reached| {
+                            crossed.unwrap_or(simulation.duration + 1) - reached
+                        });
+                        Some(StreetVisit {
+                            street_id,
+                            street_name: &simulation.streets[street_id].name,
+                            entered,
+                            reached_light,
+                            crossed,
+                            wait_time,
+                        })
+                    },
+                )
+                .collect();
+
+            let points = arrival.map_or(0, |time| {
+                simulation.bonus + (simulation.duration - time)
+            });
+            let status =
+                car_status(schedule, car_id, arrival, streets.last().unwrap());
+
+            CarTrace {
+                car_id,
+                status,
+                arrival,
+                points,
+                streets,
+            }
+        })
+        .collect()
+}
+
+fn car_status(
+    schedule: &Schedule,
+    car_id: CarId,
+    arrival: Option<Time>,
+    last_visit: &StreetVisit,
+) -> CarStatus {
+    let simulation = schedule.simulation;
+    if arrival.is_some() {
+        return CarStatus::Arrived;
+    }
+
+    let min_travel_time: Time = simulation.car_paths[car_id]
+        .iter()
+        .skip(1)
+        .map(|&street_id| simulation.streets[street_id].travel_time)
+        .sum();
+    if min_travel_time > simulation.duration {
+        return CarStatus::TooFar;
+    }
+
+    if last_visit.reached_light.is_none() {
+        return CarStatus::Driving;
+    }
+
+    let street_id = last_visit.street_id;
+    let inter_id = simulation.streets[street_id].end_intersection;
+    let street_time = schedule
+        .intersections
+        .get(&inter_id)
+        .and_then(|inter| inter.get_street_time(street_id))
+        .unwrap_or(0);
+    if street_time == 0 {
+        CarStatus::NoGreenLight
+    } else {
+        CarStatus::Waiting
+    }
+}
+
+pub fn write_csv<W: Write>(
+    traces: &[CarTrace],
+    writer: &mut W,
+) -> io::Result<()> {
+    writeln!(
+        writer,
+        "car_id,status,arrival,points,position,street_id,street_name,\
+        entered,reached_light,crossed,wait_time"
+    )?;
+    for trace in traces.iter() {
+        for (position, visit) in trace.streets.iter().enumerate() {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                trace.car_id,
+                trace.status.as_str(),
+                optional(trace.arrival),
+                trace.points,
+                position,
+                visit.street_id,
+                visit.street_name,
+                visit.entered,
+                optional(visit.reached_light),
+                optional(visit.crossed),
+                optional(visit.wait_time),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_json<W: Write>(
+    traces: &[CarTrace],
+    writer: &mut W,
+) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, traces)?;
+    writeln!(writer)
+}
+
+fn optional(value: Option<Time>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+// For each street in a car's path: the time the car entered the street,
+// reached the light at its end and crossed the intersection
+type Visit = (Option<Time>, Option<Time>, Option<Time>);
+
+struct CarTracer {
+    visits: Vec<Vec<Visit>>,
+    arrivals: Vec<Option<Time>>,
+}
+
+impl CarTracer {
+    fn new(simulation: &Simulation) -> Self {
+        let visits = simulation
+            .car_paths
+            .iter()
+            .map(|path| {
+                let mut visits = vec![(None, None, None); path.len()];
+                visits[0].0 = Some(0);
+                visits
+            })
+            .collect();
+
+        Self {
+            visits,
+            arrivals: vec![None; simulation.car_paths.len()],
+        }
+    }
+}
+
+impl Observer for CarTracer {
+    fn car_queued(
+        &mut self,
+        time: Time,
+        car_id: CarId,
+        position: usize,
+        _street_id: StreetId,
+        _queue_len: usize,
+    ) {
+        self.visits[car_id][position].1 = Some(time);
+    }
+
+    fn car_crossed(
+        &mut self,
+        time: Time,
+        car_id: CarId,
+        position: usize,
+        _street_id: StreetId,
+        _queue_len: usize,
+    ) {
+        let visits = &mut self.visits[car_id];
+        visits[position].2 = Some(time);
+        visits[position + 1].0 = Some(time);
+    }
+
+    fn car_arrived(&mut self, time: Time, car_id: CarId) {
+        self.arrivals[car_id] = Some(time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{example, simulation, EXAMPLE_SCHEDULE};
+
+    // Street name, entry, crossing and wait times of a visit
+    type VisitTimes<'a> = (&'a str, Time, Option<Time>, Option<Time>);
+
+    #[test]
+    fn example_traces() {
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        let traces = trace_cars(&schedule);
+
+        // The first car waits a second at rue-d-amsterdam and is still on
+        // rue-de-rome at the end; the second car arrives at 4
+        assert_eq!(traces[0].status, CarStatus::Driving);
+        assert_eq!(traces[0].arrival, None);
+        assert_eq!(traces[0].points, 0);
+        assert_eq!(traces[1].status, CarStatus::Arrived);
+        assert_eq!(traces[1].arrival, Some(4));
+        assert_eq!(traces[1].points, 1002);
+        let visits: Vec<Vec<VisitTimes>> = traces
+            .iter()
+            .map(|trace| {
+                trace
+                    .streets
+                    .iter()
+                    .map(|visit| {
+                        (
+                            visit.street_name,
+                            visit.entered,
+                            visit.crossed,
+                            visit.wait_time,
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            visits,
+            vec![
+                vec![
+                    ("rue-de-londres", 0, Some(0), Some(0)),
+                    ("rue-d-amsterdam", 0, Some(2), Some(1)),
+                    ("rue-de-moscou", 2, Some(5), Some(0)),
+                    ("rue-de-rome", 5, None, None),
+                ],
+                vec![
+                    ("rue-d-athenes", 0, Some(0), Some(0)),
+                    ("rue-de-moscou", 0, Some(3), Some(0)),
+                    ("rue-de-londres", 3, None, None),
+                ],
+            ],
+        );
+
+        let mut csv = Vec::new();
+        write_csv(&traces, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 1 + 4 + 3);
+        assert_eq!(lines[2], "0,driving,,0,1,1,rue-d-amsterdam,0,1,2,1");
+        assert_eq!(lines[7], "1,arrived,4,1002,2,0,rue-de-londres,3,,,");
+
+        let mut json = Vec::new();
+        write_json(&traces, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[1]["status"], "arrived");
+        assert_eq!(json[1]["arrival"], 4);
+        assert!(json[0]["arrival"].is_null());
+        assert_eq!(json[0]["streets"][1]["wait_time"], 1);
+        assert_eq!(json[0]["streets"][1]["reached_light"], 1);
+    }
+
+    #[test]
+    fn statuses() {
+        // Cars queued in order of car ID at a light green every second, the
+        // last one on a street that is never green
+        let simulation = simulation(
+            3,
+            3,
+            &[(0, 1, 1), (1, 0, 1), (2, 1, 1), (1, 2, 10), (1, 0, 3)],
+            &[&[0, 1], &[0, 3], &[0, 4], &[0, 1], &[0, 1], &[2, 1]],
+            100,
+        );
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 0, 1);
+        let traces = trace_cars(&schedule);
+        let statuses: Vec<CarStatus> =
+            traces.iter().map(|trace| trace.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                CarStatus::Arrived,
+                CarStatus::TooFar,
+                CarStatus::Driving,
+                CarStatus::Driving,
+                CarStatus::Waiting,
+                CarStatus::NoGreenLight,
+            ],
+        );
+        assert_eq!(traces[0].points, 100 + 2);
+        // Cars still waiting count until the end of the simulation
+        assert_eq!(traces[4].streets[0].wait_time, Some(4));
+        assert_eq!(traces[4].streets[0].crossed, None);
+        assert_eq!(traces[5].streets[0].wait_time, Some(4));
+    }
+}
@@ -0,0 +1,225 @@
+use super::*;
+use crate::improve::Improver;
+use crate::sched::Schedule;
+use log::info;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Improver that runs other improvers one after another: each round is run by
+// the current stage, which hands over to the next one when it reaches its
+// round limit or stops improving the schedule; in round-robin mode the first
+// stage follows the last one, until no stage improves the schedule
+#[derive(Default)]
+pub struct ChainImprover {
+    stages: Vec<Stage>,
+    round_robin: bool,
+    // Current stage and number of rounds it has run in its current turn
+    current: Cell<(usize, u32)>,
+}
+
+struct Stage {
+    name: String,
+    improver: Box<dyn Improver>,
+    max_rounds: Option<u32>,
+}
+
+impl ChainImprover {
+    pub fn add_stage(
+        &mut self,
+        name: &str,
+        improver: Box<dyn Improver>,
+        max_rounds: Option<u32>,
+    ) {
+        self.stages.push(Stage {
+            name: name.to_string(),
+            improver,
+            max_rounds,
+        });
+    }
+
+    pub fn set_round_robin(&mut self, round_robin: bool) {
+        self.round_robin = round_robin;
+    }
+
+    // Hand over to the stage after the given one
+    fn next_stage(&self, idx: usize) {
+        let mut next = idx + 1;
+        if self.round_robin && next == self.stages.len() {
+            next = 0;
+        }
+        if let Some(stage) = self.stages.get(next) {
+            info!("Chain improver: stage {} ({})", next + 1, stage.name);
+        }
+        self.current.set((next, 0));
+    }
+}
+
+impl Improver for ChainImprover {
+    fn improve<'a>(
+        &self,
+        abort_flag: Arc<AtomicBool>,
+        schedule: Schedule<'a>,
+    ) -> Option<(Schedule<'a>, Score)> {
+        // Number of stages in a row that did not improve the schedule
+        let mut stalled = 0;
+        while stalled < self.stages.len() {
+            let (idx, rounds) = self.current.get();
+            let stage = self.stages.get(idx)?;
+            if abort_flag.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            match stage.improver.improve(abort_flag.clone(), schedule.clone()) {
+                Some(result) => {
+                    let rounds = rounds + 1;
+                    if stage.max_rounds.is_some_and(|max| rounds >= max) {
+                        info!(
+                            "Chain improver: {} reached {} rounds",
+                            stage.name, rounds,
+                        );
+                        self.next_stage(idx);
+                    } else {
+                        self.current.set((idx, rounds));
+                    }
+                    return Some(result);
+                }
+                None => {
+                    info!("Chain improver: {} stopped improving", stage.name);
+                    self.next_stage(idx);
+                    stalled += 1;
+                }
+            }
+        }
+
+        // No stage improved the schedule
+        None
+    }
+}
+
+// Parse a list of improvers such as "greedy:10,phased,shuffle", where each
+// improver may be followed by its maximum number of rounds per turn
+pub fn parse_stages(s: &str) -> Result<Vec<(String, Option<u32>)>, String> {
+    s.split(',')
+        .map(|stage| {
+            let (name, max_rounds) = match stage.split_once(':') {
+                Some((name, rounds)) => {
+                    let rounds: u32 = rounds.parse().map_err(|_| {
+                        format!("Invalid number of rounds '{}'", rounds)
+                    })?;
+                    if rounds == 0 {
+                        return Err(format!("Stage '{}' has 0 rounds", stage));
+                    }
+                    (name, Some(rounds))
+                }
+                None => (stage, None),
+            };
+            if name.is_empty() {
+                return Err(format!("Missing improver in '{}'", s));
+            }
+            Ok((name.to_string(), max_rounds))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::example;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Improver that improves the schedule a number of times, logging the
+    // rounds it runs
+    struct Scripted {
+        name: &'static str,
+        improvements: Cell<u32>,
+        log: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Improver for Scripted {
+        fn improve<'a>(
+            &self,
+            _abort_flag: Arc<AtomicBool>,
+            schedule: Schedule<'a>,
+        ) -> Option<(Schedule<'a>, Score)> {
+            self.log.borrow_mut().push(self.name);
+            let improvements = self.improvements.get();
+            if improvements == 0 {
+                return None;
+            }
+            self.improvements.set(improvements - 1);
+            Some((schedule, 0))
+        }
+    }
+
+    // Names of the stages run by successive rounds of a chain, until it
+    // stops improving the schedule
+    fn run_chain(
+        stages: &[(&'static str, u32, Option<u32>)],
+        round_robin: bool,
+    ) -> Vec<&'static str> {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut chain = ChainImprover::default();
+        for &(name, improvements, max_rounds) in stages.iter() {
+            let improver = Scripted {
+                name,
+                improvements: Cell::new(improvements),
+                log: log.clone(),
+            };
+            chain.add_stage(name, Box::new(improver), max_rounds);
+        }
+        chain.set_round_robin(round_robin);
+
+        let simulation = example();
+        let abort_flag = Arc::new(AtomicBool::new(false));
+        let mut rounds = 0;
+        while chain
+            .improve(abort_flag.clone(), Schedule::new(&simulation))
+            .is_some()
+        {
+            rounds += 1;
+            assert!(rounds < 100);
+        }
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_stages("greedy:10,phased,shuffle:1"),
+            Ok(vec![
+                ("greedy".to_string(), Some(10)),
+                ("phased".to_string(), None),
+                ("shuffle".to_string(), Some(1)),
+            ]),
+        );
+        assert_eq!(
+            parse_stages("greedy"),
+            Ok(vec![("greedy".to_string(), None)])
+        );
+        assert!(parse_stages("").is_err());
+        assert!(parse_stages("greedy,").is_err());
+        assert!(parse_stages(":10").is_err());
+        assert!(parse_stages("greedy:0").is_err());
+        assert!(parse_stages("greedy:x").is_err());
+        assert!(parse_stages("greedy:-1").is_err());
+    }
+
+    #[test]
+    fn stages_in_order() {
+        assert_eq!(
+            run_chain(&[("a", 3, Some(2)), ("b", 1, None)], false),
+            vec!["a", "a", "b", "b"],
+        );
+        assert_eq!(
+            run_chain(&[("a", 3, Some(2)), ("b", 1, None)], true),
+            vec!["a", "a", "b", "b", "a", "a", "b"],
+        );
+        assert_eq!(
+            run_chain(&[("a", 0, None), ("b", 0, None)], true),
+            vec!["a", "b"],
+        );
+    }
+}
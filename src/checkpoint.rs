@@ -0,0 +1,240 @@
+use super::*;
+use crate::sched::Schedule;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Configuration of an improvement run; a run can only be resumed from
+// checkpoints written with the same configuration
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub input: String,
+    pub improver: String,
+    pub options: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckpointMeta {
+    pub config: RunConfig,
+    pub round: u32,
+    pub score: Score,
+    // Seconds since the UNIX epoch at which the checkpoint was written
+    pub created: u64,
+}
+
+pub struct Checkpointer {
+    dir: PathBuf,
+    interval: Duration,
+    config: RunConfig,
+}
+
+impl Checkpointer {
+    pub fn new(dir: &Path, interval: Duration, config: RunConfig) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            interval,
+            config,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    // Write a schedule (in the submission format) and its metadata to the
+    // checkpoint directory, removing the previous checkpoint of this run
+    pub fn save(
+        &self,
+        schedule: &Schedule,
+        round: u32,
+        score: Score,
+        previous: Option<&Path>,
+    ) -> Result<PathBuf, String> {
+        create_dir_all(&self.dir).map_err(|err| {
+            format!("Failed to create '{}': {}", self.dir.display(), err)
+        })?;
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let meta = CheckpointMeta {
+            config: self.config.clone(),
+            round,
+            score,
+            created,
+        };
+
+        let stem = Path::new(&self.config.input)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("schedule");
+        let path = self
+            .dir
+            .join(format!("{}_{}_{}_{}.txt", stem, created, round, score));
+        write_atomic(&path, &schedule.to_string())?;
+        let meta_json = serde_json::to_string_pretty(&meta)
+            .map_err(|err| format!("Failed to encode metadata: {}", err))?;
+        write_atomic(&meta_path(&path), &meta_json)?;
+        info!(
+            "Checkpoint written to '{}' (round {}, score {})",
+            path.display(),
+            round,
+            score,
+        );
+
+        if let Some(previous) = previous.filter(|&prev| prev != path) {
+            for prev_path in [previous.to_path_buf(), meta_path(previous)] {
+                if let Err(err) = remove_file(&prev_path) {
+                    warn!(
+                        "Failed to remove old checkpoint '{}': {}",
+                        prev_path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+// Newest checkpoint in a directory written by a run with the given
+// configuration, if any
+pub fn find_latest(
+    dir: &Path,
+    config: &RunConfig,
+) -> Result<Option<(PathBuf, CheckpointMeta)>, String> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+
+    let mut latest: Option<(PathBuf, CheckpointMeta)> = None;
+    for entry in entries {
+        let path = entry
+            .map_err(|err| {
+                format!("Failed to read '{}': {}", dir.display(), err)
+            })?
+            .path();
+        if path.extension().is_none_or(|ext| ext != "txt") {
+            continue;
+        }
+        let meta: CheckpointMeta = match read_to_string(meta_path(&path))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+        {
+            Some(meta) => meta,
+            None => continue,
+        };
+        if meta.config != *config {
+            continue;
+        }
+        let is_newer = latest.as_ref().is_none_or(|(_, latest_meta)| {
+            (meta.created, meta.round)
+                > (latest_meta.created, latest_meta.round)
+        });
+        if is_newer {
+            latest = Some((path, meta));
+        }
+    }
+    Ok(latest)
+}
+
+fn meta_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+// Write to a temporary file first so that a killed process never leaves a
+// truncated checkpoint behind
+fn write_atomic(path: &Path, contents: &str) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)
+        .and_then(|_| rename(&tmp_path, path))
+        .map_err(|err| format!("Failed to write '{}': {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{example, temp_dir, EXAMPLE_SCHEDULE};
+
+    fn run_config(improver: &str) -> RunConfig {
+        RunConfig {
+            input: "input/a.txt".to_string(),
+            improver: improver.to_string(),
+            options: vec![("max-shuffles".to_string(), "10".to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn resume_same_config() {
+        let dir = temp_dir("resume_same_config");
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+
+        let checkpointer = Checkpointer::new(
+            &dir,
+            Duration::from_secs(1),
+            run_config("phased"),
+        );
+        let path = checkpointer.save(&schedule, 3, 1002, None).unwrap();
+
+        let (found, meta) =
+            find_latest(&dir, &run_config("phased")).unwrap().unwrap();
+        assert_eq!(found, path);
+        assert_eq!((meta.round, meta.score), (3, 1002));
+        assert_eq!(read_to_string(&found).unwrap(), schedule.to_string());
+        // No temporary file left behind
+        assert!(read_dir(&dir).unwrap().all(|entry| {
+            entry.unwrap().path().extension().unwrap() != "tmp"
+        }));
+    }
+
+    #[test]
+    fn skip_other_configs() {
+        let dir = temp_dir("skip_other_configs");
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        Checkpointer::new(&dir, Duration::from_secs(1), run_config("phased"))
+            .save(&schedule, 1, 1002, None)
+            .unwrap();
+
+        assert!(find_latest(&dir, &run_config("greedy")).unwrap().is_none());
+        let mut config = run_config("phased");
+        config.options.insert("seed".to_string(), "1".to_string());
+        assert!(find_latest(&dir, &config).unwrap().is_none());
+        let missing = dir.join("missing");
+        assert!(find_latest(&missing, &config).unwrap().is_none());
+    }
+
+    #[test]
+    fn replace_previous_checkpoint() {
+        let dir = temp_dir("replace_previous_checkpoint");
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+
+        let checkpointer = Checkpointer::new(
+            &dir,
+            Duration::from_secs(1),
+            run_config("phased"),
+        );
+        let first = checkpointer.save(&schedule, 1, 1000, None).unwrap();
+        let second =
+            checkpointer.save(&schedule, 2, 1002, Some(&first)).unwrap();
+        assert!(!first.exists() && !meta_path(&first).exists());
+        assert!(second.exists() && meta_path(&second).exists());
+
+        let (found, meta) =
+            find_latest(&dir, &run_config("phased")).unwrap().unwrap();
+        assert_eq!((found, meta.round), (second, 2));
+    }
+}
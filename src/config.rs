@@ -0,0 +1,485 @@
+use super::*;
+use crate::adapt::AdaptiveScheduler;
+use crate::anneal::SimulatedAnnealingImprover;
+use crate::arrival::ArrivalScheduler;
+use crate::budget::parse_duration;
+use crate::greedy::GreedyImprover;
+use crate::intersect::QueueOrder;
+use crate::phased::PhasedImprover;
+use crate::shuffle::ShuffleImprover;
+use crate::traffic::TrafficScheduler;
+use crate::triage::Triage;
+use crate::wave::GreenWaveImprover;
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+use std::path::Path;
+
+// Run configuration file (TOML, or JSON if the file name ends in .json), with
+// a section for the scheduler and for each improver; every value is optional
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub seed: Option<u64>,
+    pub best_of: Option<u32>,
+    pub top_k: Option<u32>,
+    pub incremental_rounds: Option<u32>,
+    pub time_limit: Option<String>,
+    pub adaptive: AdaptiveConfig,
+    pub arrival: ArrivalConfig,
+    pub traffic: TrafficConfig,
+    pub triage: TriageConfig,
+    pub greedy: GreedyConfig,
+    pub phased: PhasedConfig,
+    pub shuffle: ShuffleConfig,
+    pub anneal: AnnealConfig,
+    pub wave: WaveConfig,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveConfig {
+    pub queue_order: Option<QueueOrder>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArrivalConfig {
+    pub max_scale: Option<Time>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafficConfig {
+    pub min_log_base: Option<f32>,
+    pub max_log_base: Option<f32>,
+    pub queue_order: Option<QueueOrder>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TriageConfig {
+    pub max_rounds: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GreedyConfig {
+    pub min_wait_time: Option<Time>,
+    pub max_streets: Option<usize>,
+    pub max_add_time: Option<Time>,
+    pub queue_order: Option<QueueOrder>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhasedConfig {
+    pub max_add_time: Option<Time>,
+    pub max_sub_time: Option<Time>,
+    pub max_shuffles: Option<usize>,
+    pub max_streets_per_inter: Option<usize>,
+    pub exact_max_time: Option<Time>,
+    pub exact_max_configs: Option<usize>,
+    pub queue_order: Option<QueueOrder>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShuffleConfig {
+    pub min_wait_time: Option<Time>,
+    pub max_streets: Option<usize>,
+    pub max_shuffles: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnealConfig {
+    pub initial_temp: Option<f64>,
+    pub min_temp: Option<f64>,
+    pub cooling_rate: Option<f64>,
+    pub max_add_time: Option<Time>,
+    pub max_sub_time: Option<Time>,
+    pub queue_order: Option<QueueOrder>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WaveConfig {
+    pub max_corridors: Option<usize>,
+    pub min_cars: Option<usize>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = read_to_string(path).map_err(|err| {
+            format!("Failed to read '{}': {}", path.display(), err)
+        })?;
+        let config: Self = if path.extension().is_some_and(|ext| ext == "json")
+        {
+            serde_json::from_str(&data).map_err(|err| err.to_string())?
+        } else {
+            toml::from_str(&data).map_err(|err| err.to_string())?
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Check values that would be rejected or misbehave at run time
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if self.best_of == Some(0) {
+            errors.push("best_of must be at least 1".to_string());
+        }
+        if self.top_k == Some(0) {
+            errors.push("top_k must be at least 1".to_string());
+        }
+        if let (Some(top_k), Some(best_of)) = (self.top_k, self.best_of) {
+            if top_k > best_of {
+                errors.push("top_k is above best_of".to_string());
+            }
+        }
+        if self.incremental_rounds == Some(0) {
+            errors.push("incremental_rounds must be at least 1".to_string());
+        }
+        if let Some(time_limit) = self.time_limit.as_ref() {
+            if let Err(err) = parse_duration(time_limit) {
+                errors.push(format!("time_limit: {}", err));
+            }
+        }
+
+        let traffic = &self.traffic;
+        for (name, base) in [
+            ("min_log_base", traffic.min_log_base),
+            ("max_log_base", traffic.max_log_base),
+        ] {
+            if base.is_some_and(|base| base <= 1.0) {
+                errors.push(format!("traffic.{} must be above 1", name));
+            }
+        }
+        if let (Some(min_base), Some(max_base)) =
+            (traffic.min_log_base, traffic.max_log_base)
+        {
+            if min_base > max_base {
+                errors.push(
+                    "traffic.min_log_base is above traffic.max_log_base"
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.arrival.max_scale == Some(0) {
+            errors.push("arrival.max_scale must be at least 1".to_string());
+        }
+        if self.triage.max_rounds == Some(0) {
+            errors.push("triage.max_rounds must be at least 1".to_string());
+        }
+        if self.greedy.max_streets == Some(0) {
+            errors.push("greedy.max_streets must be at least 1".to_string());
+        }
+        if self.shuffle.max_streets == Some(0) {
+            errors.push("shuffle.max_streets must be at least 1".to_string());
+        }
+
+        let anneal = &self.anneal;
+        for (name, temp) in [
+            ("initial_temp", anneal.initial_temp),
+            ("min_temp", anneal.min_temp),
+        ] {
+            if temp.is_some_and(|temp| temp <= 0.0) {
+                errors.push(format!("anneal.{} must be above 0", name));
+            }
+        }
+        if let (Some(initial_temp), Some(min_temp)) =
+            (anneal.initial_temp, anneal.min_temp)
+        {
+            if min_temp >= initial_temp {
+                errors.push(
+                    "anneal.min_temp must be below anneal.initial_temp"
+                        .to_string(),
+                );
+            }
+        }
+        if anneal
+            .cooling_rate
+            .is_some_and(|rate| rate <= 0.0 || rate >= 1.0)
+        {
+            errors.push(
+                "anneal.cooling_rate must be between 0 and 1".to_string(),
+            );
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+impl AdaptiveConfig {
+    pub fn configure(&self, scheduler: &mut AdaptiveScheduler) {
+        if let Some(value) = self.queue_order {
+            scheduler.set_queue_order(value);
+        }
+    }
+}
+
+impl ArrivalConfig {
+    pub fn configure(&self, scheduler: &mut ArrivalScheduler) {
+        if let Some(value) = self.max_scale {
+            scheduler.set_max_scale(value);
+        }
+    }
+}
+
+impl TrafficConfig {
+    pub fn configure(&self, scheduler: &mut TrafficScheduler) {
+        if let Some(value) = self.min_log_base {
+            scheduler.set_min_base(value);
+        }
+        if let Some(value) = self.max_log_base {
+            scheduler.set_max_base(value);
+        }
+        if let Some(value) = self.queue_order {
+            scheduler.set_queue_order(value);
+        }
+    }
+}
+
+impl TriageConfig {
+    pub fn configure(&self, triage: &mut Triage) {
+        if let Some(value) = self.max_rounds {
+            triage.set_max_rounds(value);
+        }
+    }
+}
+
+impl GreedyConfig {
+    pub fn configure(&self, greedy: &mut GreedyImprover) {
+        if let Some(value) = self.min_wait_time {
+            greedy.set_min_wait_time(value);
+        }
+        if let Some(value) = self.max_streets {
+            greedy.set_max_streets(value);
+        }
+        if let Some(value) = self.max_add_time {
+            greedy.set_max_add_time(value);
+        }
+        if let Some(value) = self.queue_order {
+            greedy.set_queue_order(value);
+        }
+    }
+}
+
+impl PhasedConfig {
+    pub fn configure(&self, phased: &mut PhasedImprover) {
+        if let Some(value) = self.max_add_time {
+            phased.set_max_add_time(value);
+        }
+        if let Some(value) = self.max_sub_time {
+            phased.set_max_sub_time(value);
+        }
+        if let Some(value) = self.max_shuffles {
+            phased.set_max_shuffles(value);
+        }
+        if let Some(value) = self.max_streets_per_inter {
+            phased.set_max_streets_per_inter(value);
+        }
+        if let Some(value) = self.exact_max_time {
+            phased.set_exact_max_time(value);
+        }
+        if let Some(value) = self.exact_max_configs {
+            phased.set_exact_max_configs(value);
+        }
+        if let Some(value) = self.queue_order {
+            phased.set_queue_order(value);
+        }
+    }
+}
+
+impl ShuffleConfig {
+    pub fn configure(&self, shuffle: &mut ShuffleImprover) {
+        if let Some(value) = self.min_wait_time {
+            shuffle.set_min_wait_time(value);
+        }
+        if let Some(value) = self.max_streets {
+            shuffle.set_max_streets(value);
+        }
+        if let Some(value) = self.max_shuffles {
+            shuffle.set_max_shuffles(value);
+        }
+    }
+}
+
+impl AnnealConfig {
+    pub fn configure(&self, anneal: &mut SimulatedAnnealingImprover) {
+        if let Some(value) = self.initial_temp {
+            anneal.set_initial_temp(value);
+        }
+        if let Some(value) = self.min_temp {
+            anneal.set_min_temp(value);
+        }
+        if let Some(value) = self.cooling_rate {
+            anneal.set_cooling_rate(value);
+        }
+        if let Some(value) = self.max_add_time {
+            anneal.set_max_add_time(value);
+        }
+        if let Some(value) = self.max_sub_time {
+            anneal.set_max_sub_time(value);
+        }
+        if let Some(value) = self.queue_order {
+            anneal.set_queue_order(value);
+        }
+    }
+}
+
+impl WaveConfig {
+    pub fn configure(&self, wave: &mut GreenWaveImprover) {
+        if let Some(value) = self.max_corridors {
+            wave.set_max_corridors(value);
+        }
+        if let Some(value) = self.min_cars {
+            wave.set_min_cars(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use std::fs::write;
+
+    fn parse(s: &str) -> Config {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn load_files() {
+        let dir = temp_dir("config_load_files");
+        let toml_path = dir.join("run.toml");
+        write(
+            &toml_path,
+            "\
+            seed = 42\n\
+            time_limit = \"10m\"\n\
+            [phased]\n\
+            queue_order = \"oldest\"\n\
+            [wave]\n\
+            min_cars = 3\n",
+        )
+        .unwrap();
+        let config = Config::load(&toml_path).unwrap();
+        assert_eq!(config.seed, Some(42));
+        assert_eq!(config.time_limit.as_deref(), Some("10m"));
+        assert_eq!(config.phased.queue_order, Some(QueueOrder::Oldest));
+        assert_eq!(config.wave.min_cars, Some(3));
+        assert_eq!(config.best_of, None);
+        assert_eq!(config.greedy.queue_order, None);
+
+        let json_path = dir.join("run.json");
+        write(&json_path, r#"{"best_of": 4, "anneal": {"min_temp": 0.5}}"#)
+            .unwrap();
+        let config = Config::load(&json_path).unwrap();
+        assert_eq!(config.best_of, Some(4));
+        assert_eq!(config.anneal.min_temp, Some(0.5));
+
+        // Unknown keys, wrong types and invalid values are all rejected
+        for (file, data) in [
+            ("unknown.toml", "[greedy]\nmax_street = 3\n"),
+            ("type.toml", "best_of = \"4\"\n"),
+            ("order.toml", "[greedy]\nqueue_order = \"shortest\"\n"),
+            ("invalid.json", r#"{"best_of": 0}"#),
+        ] {
+            let path = dir.join(file);
+            write(&path, data).unwrap();
+            assert!(Config::load(&path).is_err(), "{}", file);
+        }
+        assert!(Config::load(&dir.join("missing.toml")).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(Config::default().validate(), Ok(()));
+        assert_eq!(
+            parse(
+                "\
+                best_of = 8\n\
+                top_k = 2\n\
+                time_limit = \"1h30m\"\n\
+                [traffic]\n\
+                min_log_base = 1.5\n\
+                max_log_base = 2.5\n\
+                [anneal]\n\
+                initial_temp = 10.0\n\
+                min_temp = 0.1\n\
+                cooling_rate = 0.99\n"
+            )
+            .validate(),
+            Ok(()),
+        );
+
+        for (s, error) in [
+            ("best_of = 0", "best_of must be at least 1"),
+            ("top_k = 0", "top_k must be at least 1"),
+            ("best_of = 2\ntop_k = 3", "top_k is above best_of"),
+            (
+                "incremental_rounds = 0",
+                "incremental_rounds must be at least 1",
+            ),
+            (
+                "[traffic]\nmin_log_base = 1.0",
+                "traffic.min_log_base must be above 1",
+            ),
+            (
+                "[traffic]\nmin_log_base = 3.0\nmax_log_base = 2.0",
+                "traffic.min_log_base is above traffic.max_log_base",
+            ),
+            (
+                "[arrival]\nmax_scale = 0",
+                "arrival.max_scale must be at least 1",
+            ),
+            (
+                "[triage]\nmax_rounds = 0",
+                "triage.max_rounds must be at least 1",
+            ),
+            (
+                "[greedy]\nmax_streets = 0",
+                "greedy.max_streets must be at least 1",
+            ),
+            (
+                "[shuffle]\nmax_streets = 0",
+                "shuffle.max_streets must be at least 1",
+            ),
+            (
+                "[anneal]\nmin_temp = -1.0",
+                "anneal.min_temp must be above 0",
+            ),
+            (
+                "[anneal]\ninitial_temp = 1.0\nmin_temp = 2.0",
+                "anneal.min_temp must be below anneal.initial_temp",
+            ),
+            (
+                "[anneal]\ncooling_rate = 1.0",
+                "anneal.cooling_rate must be between 0 and 1",
+            ),
+        ] {
+            assert_eq!(parse(s).validate(), Err(error.to_string()), "{}", s);
+        }
+
+        // Every error is reported
+        let errors = parse("best_of = 0\n[anneal]\ncooling_rate = 0.0")
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            "best_of must be at least 1; anneal.cooling_rate must be between \
+             0 and 1",
+        );
+        assert!(parse("time_limit = \"soon\"")
+            .validate()
+            .unwrap_err()
+            .starts_with("time_limit: "));
+    }
+}
@@ -0,0 +1,286 @@
+use super::*;
+use crate::sched::Schedule;
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+use std::iter::once;
+
+// Differences between two schedules of the same simulation
+pub struct ScheduleDiff<'a> {
+    simulation: &'a Simulation,
+    pub intersections: Vec<IntersectionDiff>,
+}
+
+// Differences in the lights of an intersection
+pub struct IntersectionDiff {
+    pub inter_id: IntersectionId,
+    // Streets only in the new schedule, with their green time
+    pub added: Vec<(StreetId, Time)>,
+    // Streets only in the old schedule, with their green time
+    pub removed: Vec<(StreetId, Time)>,
+    // Streets in both schedules whose green time changed (old, new)
+    pub changed: Vec<(StreetId, Time, Time)>,
+    // Order of the streets in both schedules, if it changed (old, new)
+    pub reordered: Option<(Vec<StreetId>, Vec<StreetId>)>,
+    // Change in score when only this intersection changes, if computed
+    pub score_delta: Option<i64>,
+}
+
+impl<'a> ScheduleDiff<'a> {
+    pub fn new(old: &Schedule<'a>, new: &Schedule<'a>) -> Self {
+        let inter_ids: BTreeSet<IntersectionId> = old
+            .intersections
+            .keys()
+            .chain(new.intersections.keys())
+            .copied()
+            .collect();
+        let intersections = inter_ids
+            .into_iter()
+            .filter_map(|inter_id| {
+                IntersectionDiff::new(
+                    inter_id,
+                    turns(old, inter_id),
+                    turns(new, inter_id),
+                )
+            })
+            .collect();
+
+        Self {
+            simulation: old.simulation,
+            intersections,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intersections.is_empty()
+    }
+
+    // Compute the score delta of each intersection, by applying its changes
+    // alone to the old schedule
+    pub fn compute_score_deltas(&mut self, old: &Schedule, new: &Schedule) {
+        let trace = old.trace();
+        let old_score = i64::from(old.score().unwrap_or(0));
+        self.intersections.par_iter_mut().for_each(|inter_diff| {
+            let inter_id = inter_diff.inter_id;
+            let mut schedule = old.clone();
+            schedule.copy_intersection(new, inter_id);
+            let score = schedule.rescore(&trace, once(inter_id));
+            inter_diff.score_delta = Some(i64::from(score) - old_score);
+        });
+    }
+}
+
+impl IntersectionDiff {
+    fn new(
+        inter_id: IntersectionId,
+        old_turns: &[(StreetId, Time)],
+        new_turns: &[(StreetId, Time)],
+    ) -> Option<Self> {
+        let find = |turns: &[(StreetId, Time)], street_id| {
+            turns
+                .iter()
+                .find(|&&(id, _)| id == street_id)
+                .map(|&(_, time)| time)
+        };
+
+        let added: Vec<(StreetId, Time)> = new_turns
+            .iter()
+            .filter(|&&(street_id, _)| find(old_turns, street_id).is_none())
+            .copied()
+            .collect();
+        let removed: Vec<(StreetId, Time)> = old_turns
+            .iter()
+            .filter(|&&(street_id, _)| find(new_turns, street_id).is_none())
+            .copied()
+            .collect();
+        let changed: Vec<(StreetId, Time, Time)> = old_turns
+            .iter()
+            .filter_map(|&(street_id, old_time)| {
+                find(new_turns, street_id)
+                    .filter(|&new_time| new_time != old_time)
+                    .map(|new_time| (street_id, old_time, new_time))
+            })
+            .collect();
+
+        // Relative order of the streets present in both schedules
+        let old_order: Vec<StreetId> = old_turns
+            .iter()
+            .map(|&(street_id, _)| street_id)
+            .filter(|&street_id| find(new_turns, street_id).is_some())
+            .collect();
+        let new_order: Vec<StreetId> = new_turns
+            .iter()
+            .map(|&(street_id, _)| street_id)
+            .filter(|&street_id| find(old_turns, street_id).is_some())
+            .collect();
+        let reordered = if old_order == new_order {
+            None
+        } else {
+            Some((old_order, new_order))
+        };
+
+        if added.is_empty()
+            && removed.is_empty()
+            && changed.is_empty()
+            && reordered.is_none()
+        {
+            return None;
+        }
+
+        Some(Self {
+            inter_id,
+            added,
+            removed,
+            changed,
+            reordered,
+            score_delta: None,
+        })
+    }
+}
+
+impl Display for ScheduleDiff<'_> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let streets = &self.simulation.streets;
+        let names = |order: &[StreetId]| {
+            order
+                .iter()
+                .map(|&street_id| streets[street_id].name.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+
+        for inter_diff in self.intersections.iter() {
+            write!(f, "Intersection {}", inter_diff.inter_id)?;
+            if let Some(delta) = inter_diff.score_delta {
+                write!(f, " ({:+})", delta)?;
+            }
+            writeln!(f)?;
+            for &(street_id, time) in inter_diff.added.iter() {
+                writeln!(f, "  + {} {}", streets[street_id].name, time)?;
+            }
+            for &(street_id, time) in inter_diff.removed.iter() {
+                writeln!(f, "  - {} {}", streets[street_id].name, time)?;
+            }
+            for &(street_id, old_time, new_time) in inter_diff.changed.iter() {
+                writeln!(
+                    f,
+                    "  ~ {} {} -> {}",
+                    streets[street_id].name, old_time, new_time,
+                )?;
+            }
+            if let Some((old_order, new_order)) = &inter_diff.reordered {
+                writeln!(
+                    f,
+                    "  order: {} -> {}",
+                    names(old_order),
+                    names(new_order),
+                )?;
+            }
+        }
+        write!(f, "{} intersections changed", self.intersections.len())
+    }
+}
+
+fn turns<'s>(
+    schedule: &'s Schedule,
+    inter_id: IntersectionId,
+) -> &'s [(StreetId, Time)] {
+    schedule
+        .intersections
+        .get(&inter_id)
+        .map_or(&[], |inter| inter.turns.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{example, EXAMPLE_SCHEDULE};
+
+    // Example schedule with intersection 0 removed, the streets of
+    // intersection 1 swapped and one of them given more time, and
+    // intersection 3 added
+    const NEW_SCHEDULE: &str = "\
+3
+1
+2
+rue-d-amsterdam 1
+rue-d-athenes 3
+2
+1
+rue-de-moscou 1
+3
+1
+rue-de-rome 1
+";
+
+    #[test]
+    fn same_schedule() {
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        let diff = ScheduleDiff::new(&schedule, &schedule.clone());
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "0 intersections changed");
+    }
+
+    #[test]
+    fn changed_intersections() {
+        let simulation = example();
+        let mut old = Schedule::new(&simulation);
+        old.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        let mut new = Schedule::new(&simulation);
+        new.load_from_str(NEW_SCHEDULE).unwrap();
+
+        let diff = ScheduleDiff::new(&old, &new);
+        let inter_ids: Vec<IntersectionId> = diff
+            .intersections
+            .iter()
+            .map(|inter_diff| inter_diff.inter_id)
+            .collect();
+        assert_eq!(inter_ids, vec![0, 1, 3]);
+
+        let (removed, changed, added) = (
+            &diff.intersections[0],
+            &diff.intersections[1],
+            &diff.intersections[2],
+        );
+        assert_eq!(removed.removed, vec![(0, 2)]);
+        assert!(removed.added.is_empty() && removed.reordered.is_none());
+        assert_eq!(changed.changed, vec![(2, 2, 3)]);
+        assert_eq!(changed.reordered, Some((vec![2, 1], vec![1, 2])));
+        assert!(changed.added.is_empty() && changed.removed.is_empty());
+        assert_eq!(added.added, vec![(3, 1)]);
+
+        assert_eq!(
+            diff.to_string(),
+            "\
+            Intersection 0\n  \
+            - rue-de-londres 2\n\
+            Intersection 1\n  \
+            ~ rue-d-athenes 2 -> 3\n  \
+            order: rue-d-athenes rue-d-amsterdam -> \
+            rue-d-amsterdam rue-d-athenes\n\
+            Intersection 3\n  \
+            + rue-de-rome 1\n\
+            3 intersections changed"
+        );
+    }
+
+    #[test]
+    fn score_deltas() {
+        let simulation = example();
+        let mut old = Schedule::new(&simulation);
+        old.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        let mut new = Schedule::new(&simulation);
+        new.load_from_str(NEW_SCHEDULE).unwrap();
+
+        let mut diff = ScheduleDiff::new(&old, &new);
+        diff.compute_score_deltas(&old, &new);
+        let old_score = i64::from(old.score().unwrap());
+        for inter_diff in diff.intersections.iter() {
+            let mut schedule = old.clone();
+            schedule.copy_intersection(&new, inter_diff.inter_id);
+            let score = i64::from(schedule.score().unwrap());
+            assert_eq!(inter_diff.score_delta, Some(score - old_score));
+        }
+    }
+}
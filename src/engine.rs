@@ -0,0 +1,639 @@
+use super::*;
+use crate::sched::{
+    Intersection, Schedule, ScheduleStats, GREEN, LIGHT_GRAY, LIGHT_GREEN, RED,
+    WHITE,
+};
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::iter::once;
+use std::mem::take;
+
+// Green light windows of a street, as offsets within its intersection's cycle
+#[derive(Clone, Default, PartialEq)]
+pub struct Light {
+    cycle: Time,
+    windows: Vec<(Time, Time)>,
+}
+
+impl Light {
+    pub fn next_green(&self, from: Time) -> Option<Time> {
+        if self.cycle == 0 {
+            return None;
+        }
+        let mod_time = from % self.cycle;
+        self.windows
+            .iter()
+            .map(|&(start, end)| {
+                if mod_time < start {
+                    from + (start - mod_time)
+                } else if mod_time < end {
+                    from
+                } else {
+                    from + (self.cycle - mod_time) + start
+                }
+            })
+            .min()
+    }
+
+    pub fn is_green(&self, at_time: Time) -> bool {
+        if self.cycle == 0 {
+            return false;
+        }
+        let mod_time = at_time % self.cycle;
+        self.windows
+            .iter()
+            .any(|&(start, end)| mod_time >= start && mod_time < end)
+    }
+
+    // Next time after the given time at which the light may change colour
+    pub fn next_change(&self, after: Time) -> Option<Time> {
+        if self.cycle == 0 {
+            return None;
+        }
+        let mod_time = after % self.cycle;
+        self.windows
+            .iter()
+            .flat_map(|&(start, end)| once(start).chain(once(end % self.cycle)))
+            .map(|bound| {
+                if bound > mod_time {
+                    after + (bound - mod_time)
+                } else {
+                    after + (self.cycle - mod_time) + bound
+                }
+            })
+            .min()
+    }
+}
+
+pub fn intersection_lights(
+    intersection: &Intersection,
+) -> Vec<(StreetId, Light)> {
+    let cycle = intersection.cycle();
+    let mut lights: Vec<(StreetId, Light)> = Vec::new();
+    let mut acc_time = 0;
+    for &(street_id, time) in intersection.turns.iter() {
+        if time > 0 {
+            let window = (acc_time, acc_time + time);
+            if let Some((_, light)) =
+                lights.iter_mut().find(|(id, _)| *id == street_id)
+            {
+                light.windows.push(window);
+            } else {
+                let windows = vec![window];
+                lights.push((street_id, Light { cycle, windows }));
+            }
+        }
+        acc_time += time;
+    }
+    lights
+}
+
+pub fn street_lights(schedule: &Schedule) -> Vec<Light> {
+    let mut lights = vec![Light::default(); schedule.simulation.streets.len()];
+    for intersection in schedule.intersections.values() {
+        for (street_id, light) in intersection_lights(intersection) {
+            lights[street_id] = light;
+        }
+    }
+    lights
+}
+
+// For each street in a car's path: the time the car reached the end of the
+// street and the time it crossed the intersection at the end
+pub type CarTimes = Vec<(Option<Time>, Option<Time>)>;
+
+// Record of a simulation run, used as a baseline to re-simulate changes to a
+// few intersections without running the whole simulation again
+pub struct SimulationTrace {
+    pub intersections: HashMap<IntersectionId, Intersection>,
+    pub lights: Vec<Light>,
+    pub car_times: Vec<CarTimes>,
+    // For each street: the cars that joined its queue as (time, car ID, path
+    // position), in queue order
+    pub queues: Vec<Vec<(Time, CarId, usize)>>,
+    pub score: Score,
+}
+
+// Decides when the car at the head of a queue may cross its intersection
+pub trait LightPolicy {
+    // Earliest time, not before the given time, at which the car at the head
+    // of the street's queue may cross; the car crosses if that is the given
+    // time, otherwise it asks again at the returned time
+    fn next_green(&mut self, street_id: StreetId, time: Time) -> Option<Time>;
+
+    // Order in which queues whose head cars are ready to cross at the given
+    // time are given the chance to do so
+    fn prioritize(&mut self, _time: Time, _queues: &mut [WaitingQueue]) {}
+}
+
+// Hooks called as the simulation progresses
+pub trait Observer {
+    fn car_queued(
+        &mut self,
+        _time: Time,
+        _car_id: CarId,
+        _position: usize,
+        _street_id: StreetId,
+        _queue_len: usize,
+    ) {
+    }
+
+    fn car_crossed(
+        &mut self,
+        _time: Time,
+        _car_id: CarId,
+        _position: usize,
+        _street_id: StreetId,
+        _queue_len: usize,
+    ) {
+    }
+
+    fn car_arrived(&mut self, _time: Time, _car_id: CarId) {}
+
+    fn finished(&mut self) {}
+}
+
+impl Observer for () {}
+
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn car_queued(
+        &mut self,
+        time: Time,
+        car_id: CarId,
+        position: usize,
+        street_id: StreetId,
+        queue_len: usize,
+    ) {
+        self.0
+            .car_queued(time, car_id, position, street_id, queue_len);
+        self.1
+            .car_queued(time, car_id, position, street_id, queue_len);
+    }
+
+    fn car_crossed(
+        &mut self,
+        time: Time,
+        car_id: CarId,
+        position: usize,
+        street_id: StreetId,
+        queue_len: usize,
+    ) {
+        self.0
+            .car_crossed(time, car_id, position, street_id, queue_len);
+        self.1
+            .car_crossed(time, car_id, position, street_id, queue_len);
+    }
+
+    fn car_arrived(&mut self, time: Time, car_id: CarId) {
+        self.0.car_arrived(time, car_id);
+        self.1.car_arrived(time, car_id);
+    }
+
+    fn finished(&mut self) {
+        self.0.finished();
+        self.1.finished();
+    }
+}
+
+// A queue whose head car is ready to cross its intersection
+pub struct WaitingQueue {
+    pub street_id: StreetId,
+    pub queue_len: usize,
+    pub car_id: CarId,
+    pub position: usize,
+    pub queued_since: Time,
+}
+
+// Event-driven simulation: instead of stepping through every car and queue
+// on every second of the simulation, events are kept in per-second buckets
+// (cars reaching the end of a street and queues whose head car may cross) and
+// only those are processed; at each second cars first join queues (in order
+// of car ID) and then cross intersections (in the order chosen by the light
+// policy)
+pub fn run<P, O>(simulation: &Simulation, policy: &mut P, observer: &mut O)
+where
+    P: LightPolicy + ?Sized,
+    O: Observer + ?Sized,
+{
+    let duration = simulation.duration;
+    let num_seconds = usize::try_from(duration).unwrap() + 1;
+
+    // Queues of (car ID, path position, time joined) at the end of streets
+    let mut queues: Vec<VecDeque<(CarId, usize, Time)>> =
+        vec![VecDeque::new(); simulation.streets.len()];
+
+    // Cars reaching the end of a street at the given position of their path
+    let mut arrivals: Vec<Vec<(CarId, usize)>> = vec![Vec::new(); num_seconds];
+    // Streets whose head car is ready to cross
+    let mut ready_streets: Vec<Vec<StreetId>> = vec![Vec::new(); num_seconds];
+
+    // All cars start at the end of their first street
+    arrivals[0] = (0..simulation.car_paths.len())
+        .map(|car_id| (car_id, 0))
+        .collect();
+
+    let mut ready: Vec<WaitingQueue> = Vec::new();
+    for time in 0..=duration {
+        let second = usize::try_from(time).unwrap();
+        let mut streets = take(&mut ready_streets[second]);
+
+        let mut cars = take(&mut arrivals[second]);
+        cars.sort_unstable();
+        for (car_id, position) in cars.into_iter() {
+            let path = &simulation.car_paths[car_id];
+            if position + 1 == path.len() {
+                // Car reached the end of its journey
+                observer.car_arrived(time, car_id);
+                continue;
+            }
+
+            // Join traffic light queue
+            let street_id = path[position];
+            let queue = &mut queues[street_id];
+            queue.push_back((car_id, position, time));
+            observer.car_queued(time, car_id, position, street_id, queue.len());
+            if queue.len() == 1 {
+                streets.push(street_id);
+            }
+        }
+
+        if streets.is_empty() {
+            continue;
+        }
+        ready.extend(streets.into_iter().map(|street_id| {
+            let queue = &queues[street_id];
+            let &(car_id, position, queued_since) = queue.front().unwrap();
+            WaitingQueue {
+                street_id,
+                queue_len: queue.len(),
+                car_id,
+                position,
+                queued_since,
+            }
+        }));
+
+        // Let cars at the top of the queues cross intersections
+        policy.prioritize(time, &mut ready);
+        for waiting in ready.drain(..) {
+            let street_id = waiting.street_id;
+            let green = policy
+                .next_green(street_id, time)
+                .filter(|&t| t <= duration);
+            match green {
+                Some(green_time) if green_time == time => {
+                    let queue = &mut queues[street_id];
+                    let (car_id, position, _) = queue.pop_front().unwrap();
+                    observer.car_crossed(
+                        time,
+                        car_id,
+                        position,
+                        street_id,
+                        queue.len(),
+                    );
+                    if !queue.is_empty() && time < duration {
+                        ready_streets[second + 1].push(street_id);
+                    }
+
+                    // Drive to the end of the next street
+                    let path = &simulation.car_paths[car_id];
+                    let arrival = time
+                        + simulation.streets[path[position + 1]].travel_time;
+                    if arrival <= duration {
+                        let arrival = usize::try_from(arrival).unwrap();
+                        arrivals[arrival].push((car_id, position + 1));
+                    }
+                }
+                Some(green_time) => {
+                    assert!(green_time > time);
+                    let green_time = usize::try_from(green_time).unwrap();
+                    ready_streets[green_time].push(street_id);
+                }
+                None => {
+                    // Light will not be green again before the end
+                }
+            }
+        }
+    }
+
+    observer.finished();
+}
+
+pub struct FixedLights {
+    lights: Vec<Light>,
+}
+
+impl FixedLights {
+    pub fn new(schedule: &Schedule) -> Self {
+        Self {
+            lights: street_lights(schedule),
+        }
+    }
+}
+
+impl LightPolicy for FixedLights {
+    fn next_green(&mut self, street_id: StreetId, time: Time) -> Option<Time> {
+        self.lights[street_id].next_green(time)
+    }
+}
+
+pub fn simulate(schedule: &Schedule, build_image: bool) -> ScheduleStats {
+    let mut policy = FixedLights::new(schedule);
+    let mut recorder = StatsRecorder::new(schedule, build_image);
+    run(schedule.simulation, &mut policy, &mut recorder);
+    recorder.stats
+}
+
+pub fn simulate_with_trace(
+    schedule: &Schedule,
+) -> (ScheduleStats, SimulationTrace) {
+    let mut policy = FixedLights::new(schedule);
+    let mut recorder = (
+        StatsRecorder::new(schedule, false),
+        TraceRecorder::new(schedule.simulation),
+    );
+    run(schedule.simulation, &mut policy, &mut recorder);
+    let (stats_recorder, trace_recorder) = recorder;
+    let trace = SimulationTrace {
+        intersections: schedule.intersections.clone(),
+        lights: policy.lights,
+        car_times: trace_recorder.car_times,
+        queues: trace_recorder.queues,
+        score: stats_recorder.stats.score,
+    };
+    (stats_recorder.stats, trace)
+}
+
+pub struct ScoreCounter<'a> {
+    simulation: &'a Simulation,
+    pub score: Score,
+}
+
+impl<'a> ScoreCounter<'a> {
+    pub fn new(simulation: &'a Simulation) -> Self {
+        Self {
+            simulation,
+            score: 0,
+        }
+    }
+}
+
+impl Observer for ScoreCounter<'_> {
+    fn car_arrived(&mut self, time: Time, _car_id: CarId) {
+        self.score += self.simulation.bonus + (self.simulation.duration - time);
+    }
+}
+
+struct StatsRecorder<'s, 'a> {
+    simulation: &'a Simulation,
+    stats: ScheduleStats,
+    // Time each queue last became non-empty, if it still is
+    busy_since: Vec<Option<Time>>,
+    image: Option<ImageBuilder<'s, 'a>>,
+}
+
+impl<'s, 'a> StatsRecorder<'s, 'a> {
+    fn new(schedule: &'s Schedule<'a>, build_image: bool) -> Self {
+        let mut stats = ScheduleStats::new(schedule);
+        let image = if build_image {
+            Some(ImageBuilder::new(schedule, &mut stats))
+        } else {
+            None
+        };
+        Self {
+            simulation: schedule.simulation,
+            stats,
+            busy_since: vec![None; schedule.simulation.streets.len()],
+            image,
+        }
+    }
+
+    fn add_wait_time(&mut self, street_id: StreetId, wait_time: Time) {
+        if wait_time > 0 {
+            *self.stats.total_wait_time.entry(street_id).or_insert(0) +=
+                wait_time;
+        }
+    }
+}
+
+impl Observer for StatsRecorder<'_, '_> {
+    fn car_queued(
+        &mut self,
+        time: Time,
+        _car_id: CarId,
+        _position: usize,
+        street_id: StreetId,
+        queue_len: usize,
+    ) {
+        if queue_len == 1 {
+            self.busy_since[street_id] = Some(time);
+            if let Some(image) = self.image.as_mut() {
+                image.queue_started(street_id, time);
+            }
+        }
+    }
+
+    fn car_crossed(
+        &mut self,
+        time: Time,
+        _car_id: CarId,
+        _position: usize,
+        street_id: StreetId,
+        queue_len: usize,
+    ) {
+        self.stats.crossed_streets.insert(street_id);
+        if queue_len == 0 {
+            // Add the time the queue was not empty to the total wait time
+            let busy_since = self.busy_since[street_id].take().unwrap();
+            self.add_wait_time(street_id, time - busy_since);
+        }
+        if let Some(image) = self.image.as_mut() {
+            image.car_crossed(&mut self.stats, street_id, time);
+        }
+    }
+
+    fn car_arrived(&mut self, time: Time, _car_id: CarId) {
+        let stats = &mut self.stats;
+        stats.num_arrived_cars += 1;
+        stats.score +=
+            self.simulation.bonus + (self.simulation.duration - time);
+        if stats.earliest_arrival == 0 || time < stats.earliest_arrival {
+            stats.earliest_arrival = time;
+        }
+        stats.latest_arrival = max(stats.latest_arrival, time);
+    }
+
+    fn finished(&mut self) {
+        // Queues that are still not empty at the end of the simulation
+        let end = self.simulation.duration + 1;
+        for street_id in 0..self.busy_since.len() {
+            if let Some(busy_since) = self.busy_since[street_id] {
+                self.add_wait_time(street_id, end - busy_since);
+                if let Some(image) = self.image.as_mut() {
+                    image.queue_stopped(&mut self.stats, street_id);
+                }
+            }
+        }
+        if let Some(image) = self.image.as_ref() {
+            image.paint_separators(&mut self.stats);
+        }
+    }
+}
+
+struct TraceRecorder {
+    car_times: Vec<CarTimes>,
+    queues: Vec<Vec<(Time, CarId, usize)>>,
+}
+
+impl TraceRecorder {
+    fn new(simulation: &Simulation) -> Self {
+        Self {
+            car_times: simulation
+                .car_paths
+                .iter()
+                .map(|path| vec![(None, None); path.len()])
+                .collect(),
+            queues: vec![Vec::new(); simulation.streets.len()],
+        }
+    }
+}
+
+impl Observer for TraceRecorder {
+    fn car_queued(
+        &mut self,
+        time: Time,
+        car_id: CarId,
+        position: usize,
+        street_id: StreetId,
+        _queue_len: usize,
+    ) {
+        self.car_times[car_id][position].0 = Some(time);
+        self.queues[street_id].push((time, car_id, position));
+    }
+
+    fn car_crossed(
+        &mut self,
+        time: Time,
+        car_id: CarId,
+        position: usize,
+        _street_id: StreetId,
+        _queue_len: usize,
+    ) {
+        self.car_times[car_id][position].1 = Some(time);
+    }
+
+    fn car_arrived(&mut self, time: Time, car_id: CarId) {
+        let car_times = &mut self.car_times[car_id];
+        car_times.last_mut().unwrap().0 = Some(time);
+    }
+}
+
+struct ImageBuilder<'s, 'a> {
+    schedule: &'s Schedule<'a>,
+    inter_start_col: HashMap<IntersectionId, u32>,
+    // Next row to paint for each street whose queue is not empty
+    next_row: Vec<Time>,
+}
+
+impl<'s, 'a> ImageBuilder<'s, 'a> {
+    fn new(schedule: &'s Schedule<'a>, stats: &mut ScheduleStats) -> Self {
+        for pixel in stats.image.pixels_mut() {
+            *pixel = WHITE;
+        }
+
+        // Lay out intersections side by side in order of intersection ID
+        let mut inter_ids: Vec<IntersectionId> =
+            schedule.intersections.keys().copied().collect();
+        inter_ids.sort_unstable();
+        let mut inter_start_col = HashMap::new();
+        let mut next_start_col = 0;
+        for inter_id in inter_ids.into_iter() {
+            inter_start_col.insert(inter_id, next_start_col);
+            next_start_col +=
+                1 + schedule.intersections.get(&inter_id).unwrap().cycle();
+        }
+
+        Self {
+            schedule,
+            inter_start_col,
+            next_row: vec![0; schedule.simulation.streets.len()],
+        }
+    }
+
+    fn queue_started(&mut self, street_id: StreetId, time: Time) {
+        self.next_row[street_id] = time;
+    }
+
+    fn car_crossed(
+        &mut self,
+        stats: &mut ScheduleStats,
+        street_id: StreetId,
+        time: Time,
+    ) {
+        // Rows in which the queue was waiting for a green light
+        let first_row = self.next_row[street_id];
+        self.paint_rows(stats, street_id, first_row, time, Some(time));
+        self.next_row[street_id] = time + 1;
+    }
+
+    fn queue_stopped(
+        &mut self,
+        stats: &mut ScheduleStats,
+        street_id: StreetId,
+    ) {
+        let first_row = self.next_row[street_id];
+        let last_row = self.schedule.simulation.duration;
+        self.paint_rows(stats, street_id, first_row, last_row, None);
+    }
+
+    fn paint_rows(
+        &self,
+        stats: &mut ScheduleStats,
+        street_id: StreetId,
+        first_row: Time,
+        last_row: Time,
+        green_row: Option<Time>,
+    ) {
+        let inter_id = self.schedule.get_intersection_id(street_id).unwrap();
+        let intersection = match self.schedule.intersections.get(&inter_id) {
+            Some(inter) => inter,
+            None => return,
+        };
+        let street_time = intersection.get_street_time(street_id).unwrap_or(0);
+        if street_time == 0 {
+            return;
+        }
+
+        let inter_col = *self.inter_start_col.get(&inter_id).unwrap();
+        let street_col = inter_col
+            + intersection
+                .turns
+                .iter()
+                .take_while(|(id, _)| *id != street_id)
+                .map(|(_, t)| t)
+                .sum::<u32>();
+
+        for row in first_row..=last_row {
+            let color = if Some(row) == green_row {
+                LIGHT_GREEN
+            } else {
+                RED
+            };
+            for col in street_col..(street_col + street_time) {
+                stats.image.put_pixel(col, row, color);
+            }
+        }
+
+        if let Some(row) = green_row {
+            let col = inter_col + (row % intersection.cycle());
+            stats.image.put_pixel(col, row, GREEN);
+        }
+    }
+
+    fn paint_separators(&self, stats: &mut ScheduleStats) {
+        for &col in self.inter_start_col.values().filter(|&col| *col > 0) {
+            for row in 0..stats.image.height() {
+                stats.image.put_pixel(col - 1, row, LIGHT_GRAY);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use super::*;
+use crate::engine::SimulationTrace;
+use crate::sched::{Intersection, Schedule};
+use std::collections::BTreeSet;
+use std::iter::once;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Exhaustive search of the schedule of a single intersection, holding the
+// rest of the city fixed: every incoming street that is scheduled or crossed
+// by cars gets a green time between 0 (left out of the schedule) and
+// max_time, in every order; each configuration is scored with an exact
+// (incremental) simulation, so the result is the best configuration within
+// these bounds
+pub struct ExactOptimizer {
+    max_time: Time,
+    // Configurations above which intersections are skipped
+    max_configs: usize,
+}
+
+impl Default for ExactOptimizer {
+    fn default() -> Self {
+        Self {
+            max_time: 3,
+            max_configs: 2000,
+        }
+    }
+}
+
+impl ExactOptimizer {
+    pub fn set_max_time(&mut self, max_time: Time) {
+        self.max_time = max_time;
+    }
+
+    pub fn set_max_configs(&mut self, max_configs: usize) {
+        self.max_configs = max_configs;
+    }
+
+    // Number of configurations of an intersection with the given number of
+    // streets, if not above the limit: for each number k of scheduled streets,
+    // C(n, k) choices of streets, max_time^k green times and k! orders
+    pub fn num_configs(&self, num_streets: usize) -> Option<usize> {
+        let max_time = self.max_time as usize;
+        let mut total: usize = 0;
+        // C(n, k) * max_time^k * k!, updated incrementally
+        let mut configs: usize = 1;
+        for k in 1..=num_streets {
+            configs = configs
+                .checked_mul(num_streets - k + 1)?
+                .checked_mul(max_time)?;
+            total = total.checked_add(configs)?;
+            if total > self.max_configs {
+                return None;
+            }
+        }
+        Some(total)
+    }
+
+    // Best configuration of an intersection of a schedule whose simulation is
+    // recorded in the trace, with its score, given the streets that cars
+    // cross (see crossed_streets); None if the intersection has no candidate
+    // streets or too many configurations, or if the search was interrupted
+    pub fn optimize<'a>(
+        &self,
+        abort_flag: &AtomicBool,
+        schedule: &Schedule<'a>,
+        trace: &SimulationTrace,
+        crossed: &HashMap<IntersectionId, BTreeSet<StreetId>>,
+        inter_id: IntersectionId,
+    ) -> Option<(Schedule<'a>, Score)> {
+        let streets = candidate_streets(schedule, crossed, inter_id);
+        if streets.is_empty() {
+            return None;
+        }
+        self.num_configs(streets.len())?;
+
+        let mut search = Search {
+            abort_flag,
+            trace,
+            inter_id,
+            schedule: schedule.clone(),
+            turns: Vec::with_capacity(streets.len()),
+            best: None,
+        };
+        for times in self.green_times(streets.len()) {
+            if abort_flag.load(Ordering::SeqCst) {
+                return None;
+            }
+            let mut active: Vec<(StreetId, Time)> = streets
+                .iter()
+                .copied()
+                .zip(times)
+                .filter(|&(_, time)| time > 0)
+                .collect();
+            search.permute(&mut active);
+        }
+        if abort_flag.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let (turns, score) = search.best?;
+        let mut new_schedule = schedule.clone();
+        new_schedule
+            .intersections
+            .insert(inter_id, new_intersection(&turns));
+        Some((new_schedule, score))
+    }
+
+    // Green times of the streets of an intersection, with at least one street
+    // scheduled
+    fn green_times(&self, num_streets: usize) -> Vec<Vec<Time>> {
+        let mut all_times: Vec<Vec<Time>> = vec![Vec::new()];
+        for _ in 0..num_streets {
+            all_times = all_times
+                .into_iter()
+                .flat_map(|times| {
+                    (0..=self.max_time).map(move |time| {
+                        let mut times = times.clone();
+                        times.push(time);
+                        times
+                    })
+                })
+                .collect();
+        }
+        all_times.retain(|times| times.iter().any(|&time| time > 0));
+        all_times
+    }
+}
+
+// Streets that cars cross, by the intersection at their end
+pub fn crossed_streets(
+    simulation: &Simulation,
+) -> HashMap<IntersectionId, BTreeSet<StreetId>> {
+    let mut crossed: HashMap<IntersectionId, BTreeSet<StreetId>> =
+        HashMap::new();
+    for path in simulation.car_paths.iter() {
+        // Cars don't cross the intersection at the end of their last street
+        for &street_id in path[..path.len() - 1].iter() {
+            let inter_id = simulation.streets[street_id].end_intersection;
+            crossed.entry(inter_id).or_default().insert(street_id);
+        }
+    }
+    crossed
+}
+
+// Incoming streets of an intersection searched by the optimizer: the
+// scheduled ones (in schedule order), then the other ones that cars cross (in
+// ID order)
+pub fn candidate_streets(
+    schedule: &Schedule,
+    crossed: &HashMap<IntersectionId, BTreeSet<StreetId>>,
+    inter_id: IntersectionId,
+) -> Vec<StreetId> {
+    let mut streets: Vec<StreetId> = schedule
+        .intersections
+        .get(&inter_id)
+        .map(|inter| {
+            inter
+                .turns
+                .iter()
+                .map(|&(street_id, _)| street_id)
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(crossed) = crossed.get(&inter_id) {
+        for &street_id in crossed.iter() {
+            if !streets.contains(&street_id) {
+                streets.push(street_id);
+            }
+        }
+    }
+    streets
+}
+
+struct Search<'s, 'a> {
+    abort_flag: &'s AtomicBool,
+    trace: &'s SimulationTrace,
+    inter_id: IntersectionId,
+    // Schedule being searched, and turns of the intersection so far
+    schedule: Schedule<'a>,
+    turns: Vec<(StreetId, Time)>,
+    best: Option<(Vec<(StreetId, Time)>, Score)>,
+}
+
+impl Search<'_, '_> {
+    // Score every order of the remaining streets after the current turns
+    fn permute(&mut self, remaining: &mut Vec<(StreetId, Time)>) {
+        if remaining.is_empty() {
+            self.score();
+            return;
+        }
+        for idx in 0..remaining.len() {
+            if self.abort_flag.load(Ordering::SeqCst) {
+                return;
+            }
+            let turn = remaining.remove(idx);
+            self.turns.push(turn);
+            self.permute(remaining);
+            self.turns.pop();
+            remaining.insert(idx, turn);
+        }
+    }
+
+    fn score(&mut self) {
+        self.schedule
+            .intersections
+            .insert(self.inter_id, new_intersection(&self.turns));
+        let score = self.schedule.rescore(self.trace, once(self.inter_id));
+        // Keep the first configuration found on ties
+        if self.best.as_ref().is_none_or(|&(_, best)| score > best) {
+            self.best = Some((self.turns.clone(), score));
+        }
+    }
+}
+
+fn new_intersection(turns: &[(StreetId, Time)]) -> Intersection {
+    let (&(street_id, time), rest) = turns.split_first().unwrap();
+    let mut inter = Intersection::new(street_id, time);
+    for &(street_id, time) in rest.iter() {
+        inter.add_street(street_id, time);
+    }
+    inter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::simulation;
+
+    #[test]
+    fn unscheduled_streets() {
+        // Two streets into intersection 1, only the first one scheduled
+        let simulation = simulation(
+            10,
+            3,
+            &[(0, 1, 1), (2, 1, 1), (1, 0, 1)],
+            &[&[0, 2], &[1, 2]],
+            100,
+        );
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 0, 1);
+        let trace = schedule.trace();
+        assert_eq!(trace.score, 100 + 9);
+
+        let crossed = crossed_streets(&simulation);
+        assert_eq!(crossed.len(), 1);
+        assert_eq!(candidate_streets(&schedule, &crossed, 1), vec![0, 1]);
+        assert!(candidate_streets(&schedule, &crossed, 0).is_empty());
+
+        let optimizer = ExactOptimizer::default();
+        assert_eq!(optimizer.num_configs(2), Some(2 * 3 + 2 * 3 * 3));
+        let abort_flag = AtomicBool::new(false);
+        let (new_schedule, score) = optimizer
+            .optimize(&abort_flag, &schedule, &trace, &crossed, 1)
+            .unwrap();
+        assert_eq!(score, 2 * 100 + 9 + 8);
+        assert_eq!(new_schedule.score(), Ok(score));
+        assert_eq!(new_schedule.num_streets_in_intersection(1), 2);
+        assert!(optimizer
+            .optimize(&abort_flag, &schedule, &trace, &crossed, 0)
+            .is_none());
+    }
+}
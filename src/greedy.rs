@@ -1,9 +1,13 @@
 use super::*;
 use crate::improve::Improver;
-use crate::intersect::reorder_intersection;
+use crate::intersect::{reorder_intersection, QueueOrder};
 use crate::sched::Schedule;
+use crate::seed::SeedSource;
 use log::info;
-use std::collections::HashSet;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cmp::Reverse;
+use std::iter::once;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -11,6 +15,8 @@ pub struct GreedyImprover {
     min_wait_time: Time,
     max_streets: usize,
     max_add_time: Time,
+    queue_order: QueueOrder,
+    seeds: SeedSource,
 }
 
 impl Default for GreedyImprover {
@@ -19,6 +25,8 @@ impl Default for GreedyImprover {
             min_wait_time: 10,
             max_streets: 10,
             max_add_time: 1,
+            queue_order: QueueOrder::Random,
+            seeds: SeedSource::default(),
         }
     }
 }
@@ -35,6 +43,14 @@ impl GreedyImprover {
     pub fn set_max_add_time(&mut self, max_add_time: Time) {
         self.max_add_time = max_add_time;
     }
+
+    pub fn set_queue_order(&mut self, queue_order: QueueOrder) {
+        self.queue_order = queue_order;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seeds = SeedSource::new(seed);
+    }
 }
 
 impl Improver for GreedyImprover {
@@ -43,6 +59,8 @@ impl Improver for GreedyImprover {
         abort_flag: Arc<AtomicBool>,
         schedule: Schedule<'a>,
     ) -> Option<(Schedule<'a>, Score)> {
+        let mut rng = StdRng::seed_from_u64(self.seeds.next_seed());
+
         // Sort streets by total wait time
         let stats = schedule.stats(false).unwrap();
         let mut wait_times: Vec<(StreetId, Time)> = stats
@@ -53,16 +71,17 @@ impl Improver for GreedyImprover {
                     && !schedule.is_street_always_green(street_id)
             })
             .collect();
-        wait_times.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        wait_times.sort_unstable_by_key(|&(id, time)| (Reverse(time), id));
         wait_times.truncate(self.max_streets);
 
-        // Collect IDs of all intersections
-        let inter_ids: HashSet<IntersectionId> = wait_times
-            .iter()
-            .map(|&(street_id, _)| {
-                schedule.get_intersection_id(street_id).unwrap()
-            })
-            .collect();
+        // Collect IDs of all intersections, in order of their busiest street
+        let mut inter_ids: Vec<IntersectionId> = Vec::new();
+        for &(street_id, _) in wait_times.iter() {
+            let inter_id = schedule.get_intersection_id(street_id).unwrap();
+            if !inter_ids.contains(&inter_id) {
+                inter_ids.push(inter_id);
+            }
+        }
 
         info!(
             "Greedy improver: {} minimum wait time, {} max additional time, \
@@ -74,18 +93,27 @@ impl Improver for GreedyImprover {
             inter_ids.len(),
         );
 
+        // Accepted moves add up; each candidate is scored incrementally
+        // against the trace of the schedule with the moves so far
         let mut best_count = 0;
         let mut best_score = stats.score;
-        let mut best_sched = None;
+        let mut best_sched = schedule.clone();
+        let mut trace = schedule.trace();
 
         // First, try to improve each intersection by reordering streets
-        // without changing their times
+        // without changing their times (reordering runs a simulation of its
+        // own to assign the slots)
         for &inter_id in inter_ids.iter() {
             if abort_flag.load(Ordering::SeqCst) {
                 break;
             }
-            let mut new_schedule = schedule.clone();
-            let new_score = reorder_intersection(&mut new_schedule, inter_id);
+            let mut new_schedule = best_sched.clone();
+            reorder_intersection(
+                &mut new_schedule,
+                inter_id,
+                self.queue_order.policy(&mut rng),
+            );
+            let new_score = new_schedule.rescore(&trace, once(inter_id));
             if new_score <= best_score {
                 continue;
             }
@@ -95,15 +123,16 @@ impl Improver for GreedyImprover {
             );
             best_count += 1;
             best_score = new_score;
-            best_sched = Some(new_schedule.clone());
+            best_sched = new_schedule;
             if best_count >= 5 {
                 break;
             }
+            trace = best_sched.trace();
         }
 
-        if let Some(sched) = best_sched {
+        if best_count > 0 {
             // If a better schedule was found, return it
-            return Some((sched, best_score));
+            return Some((best_sched, best_score));
         }
         if abort_flag.load(Ordering::SeqCst) {
             return None;
@@ -116,10 +145,9 @@ impl Improver for GreedyImprover {
                     break 'outer;
                 }
                 let inter_id = schedule.get_intersection_id(street_id).unwrap();
-                let mut new_schedule = schedule.clone();
+                let mut new_schedule = best_sched.clone();
                 new_schedule.add_street_time(street_id, add_time);
-                let new_score =
-                    reorder_intersection(&mut new_schedule, inter_id);
+                let new_score = new_schedule.rescore(&trace, once(inter_id));
                 if new_score <= best_score {
                     continue;
                 }
@@ -136,10 +164,11 @@ impl Improver for GreedyImprover {
                 );
                 best_count += 1;
                 best_score = new_score;
-                best_sched = Some(new_schedule.clone());
+                best_sched = new_schedule;
                 if best_count >= 5 {
                     break 'outer;
                 }
+                trace = best_sched.trace();
             }
             if best_count > 0 {
                 // If a better schedule was found, return it
@@ -147,6 +176,48 @@ impl Improver for GreedyImprover {
             }
         }
 
-        best_sched.map(|sched| (sched, best_score))
+        if best_count > 0 {
+            Some((best_sched, best_score))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{random_schedule, random_simulation};
+    use rand::Rng;
+
+    #[test]
+    fn rescored_moves() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        let mut improved = 0;
+        for _ in 0..50 {
+            let simulation = random_simulation(&mut rng);
+            // Greedy expects every intersection to be scheduled
+            let mut schedule = random_schedule(&simulation, &mut rng);
+            for (street_id, street) in simulation.streets.iter().enumerate() {
+                let inter_id = street.end_intersection;
+                if !schedule.intersections.contains_key(&inter_id) {
+                    schedule.add_street(inter_id, street_id, 1);
+                }
+            }
+            let score = schedule.score().unwrap();
+            let mut greedy = GreedyImprover::default();
+            greedy.set_seed(rng.gen());
+            greedy.set_min_wait_time(1);
+            greedy.set_max_add_time(2);
+            let abort_flag = Arc::new(AtomicBool::new(false));
+            if let Some((new_schedule, new_score)) =
+                greedy.improve(abort_flag, schedule)
+            {
+                assert!(new_score > score);
+                assert_eq!(new_schedule.score(), Ok(new_score));
+                improved += 1;
+            }
+        }
+        assert!(improved > 0);
     }
 }
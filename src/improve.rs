@@ -1,8 +1,11 @@
 use super::*;
+use crate::checkpoint::Checkpointer;
 use crate::sched::Schedule;
 use log::{info, warn};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 pub trait Improver {
     fn improve<'a>(
@@ -14,6 +17,10 @@ pub trait Improver {
 
 pub struct IncrementalImprover {
     max_rounds: Option<u32>,
+    first_round: u32,
+    checkpointer: Option<Checkpointer>,
+    // Prefix of log messages
+    log_prefix: String,
     abort_flag: Arc<AtomicBool>,
 }
 
@@ -21,6 +28,9 @@ impl IncrementalImprover {
     pub fn new(abort_flag: Arc<AtomicBool>) -> Self {
         Self {
             max_rounds: None,
+            first_round: 1,
+            checkpointer: None,
+            log_prefix: String::new(),
             abort_flag,
         }
     }
@@ -31,19 +41,42 @@ impl IncrementalImprover {
         self.max_rounds = Some(rounds);
     }
 
+    // Number of the first round, when resuming a previous run
+    pub fn set_first_round(&mut self, round: u32) {
+        self.first_round = round;
+    }
+
+    pub fn set_checkpointer(&mut self, checkpointer: Checkpointer) {
+        self.checkpointer = Some(checkpointer);
+    }
+
+    // Name of the run in log messages, when several runs improve schedules in
+    // parallel
+    pub fn set_name(&mut self, name: &str) {
+        self.log_prefix = format!("{}: ", name);
+    }
+
     pub fn improve<'a>(
         &self,
         initial_schedule: &'a Schedule,
         improver: &dyn Improver,
     ) -> Schedule<'a> {
         if let Some(rounds) = self.max_rounds {
-            info!("Incremental improver: max {} rounds", rounds);
+            info!(
+                "{}Incremental improver: max {} rounds",
+                self.log_prefix, rounds
+            );
         } else {
-            info!("Incremental improver: continuous rounds");
+            info!("{}Incremental improver: continuous rounds", self.log_prefix);
         };
 
         let mut schedule = initial_schedule.clone();
-        for round in 1.. {
+        let mut score = 0;
+        let mut last_round = self.first_round - 1;
+        let mut last_checkpoint = Instant::now();
+        let mut checkpoint_path: Option<PathBuf> = None;
+        let mut saved_round = last_round;
+        for round in self.first_round.. {
             if self.max_rounds.map(|max| round > max).unwrap_or(false) {
                 break;
             }
@@ -52,17 +85,68 @@ impl IncrementalImprover {
                 improver.improve(self.abort_flag.clone(), schedule.clone())
             {
                 schedule = new_schedule;
-                info!("Round {}, new score {}", round, new_score);
+                score = new_score;
+                last_round = round;
+                info!(
+                    "{}Round {}, new score {}",
+                    self.log_prefix, round, new_score
+                );
             } else {
-                info!("Round {}, no improvement", round);
+                info!("{}Round {}, no improvement", self.log_prefix, round);
                 break;
             }
 
             if self.abort_flag.load(Ordering::SeqCst) {
-                warn!("Termination request received after {} rounds", round);
+                warn!(
+                    "{}Termination request received after {} rounds",
+                    self.log_prefix, round
+                );
                 break;
             }
+
+            if let Some(checkpointer) = self.checkpointer.as_ref() {
+                if last_checkpoint.elapsed() >= checkpointer.interval() {
+                    checkpoint_path = save_checkpoint(
+                        checkpointer,
+                        &schedule,
+                        round,
+                        score,
+                        checkpoint_path,
+                    );
+                    last_checkpoint = Instant::now();
+                    saved_round = round;
+                }
+            }
+        }
+
+        // Save the final schedule if it wasn't saved yet
+        if let Some(checkpointer) = self.checkpointer.as_ref() {
+            if saved_round < last_round {
+                save_checkpoint(
+                    checkpointer,
+                    &schedule,
+                    last_round,
+                    score,
+                    checkpoint_path,
+                );
+            }
         }
         schedule
     }
 }
+
+fn save_checkpoint(
+    checkpointer: &Checkpointer,
+    schedule: &Schedule,
+    round: u32,
+    score: Score,
+    previous: Option<PathBuf>,
+) -> Option<PathBuf> {
+    match checkpointer.save(schedule, round, score, previous.as_deref()) {
+        Ok(path) => Some(path),
+        Err(err) => {
+            warn!("Failed to write checkpoint: {}", err);
+            previous
+        }
+    }
+}
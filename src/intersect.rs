@@ -1,122 +1,246 @@
 use super::*;
-use crate::sched::{Car, CarState, Schedule};
-use crate::sums::AllSums;
-use std::collections::VecDeque;
+use crate::engine::{
+    self, street_lights, Light, LightPolicy, ScoreCounter, WaitingQueue,
+};
+use crate::sched::Schedule;
+use crate::seed::mix;
+use crate::sums::SubsetSums;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::iter::once;
 use std::ops::{RangeBounds, RangeInclusive};
+use std::str::FromStr;
+
+// Order in which waiting queues whose head cars are ready to cross at the same
+// time get the chance to do so (and thereby claim the free slots of their
+// intersections)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    // Fixed arbitrary order, by hash of the street ID
+    Hash,
+    // Most cars waiting first
+    LongestQueue,
+    // Head car waiting the longest first
+    OldestCar,
+    // Head car with the most points still to earn first
+    MostValue,
+    // Arbitrary order that changes with the seed
+    Random(u64),
+}
+
+impl QueuePolicy {
+    pub fn sort(
+        &self,
+        simulation: &Simulation,
+        time: Time,
+        queues: &mut [WaitingQueue],
+    ) {
+        match *self {
+            Self::Hash => {
+                queues.sort_unstable_by_key(|queue| mix(queue.street_id as u64))
+            }
+            // Unstable sorts, as before queue policies could be selected:
+            // ties end up in an arbitrary but deterministic order
+            Self::LongestQueue => {
+                queues.sort_unstable_by_key(|queue| Reverse(queue.queue_len))
+            }
+            Self::OldestCar => {
+                queues.sort_unstable_by_key(|queue| queue.queued_since)
+            }
+            Self::MostValue => queues.sort_by_cached_key(|queue| {
+                Reverse(car_value(simulation, time, queue))
+            }),
+            Self::Random(seed) => queues.sort_unstable_by_key(|queue| {
+                mix(seed ^ queue.street_id as u64)
+            }),
+        }
+    }
+}
+
+// Points the head car of a queue earns if it crosses now and is not delayed
+// any further
+fn car_value(
+    simulation: &Simulation,
+    time: Time,
+    queue: &WaitingQueue,
+) -> Score {
+    let path = &simulation.car_paths[queue.car_id];
+    let arrival = path[queue.position + 1..]
+        .iter()
+        .map(|&street_id| simulation.streets[street_id].travel_time)
+        .sum::<Time>()
+        + time;
+    if arrival <= simulation.duration {
+        simulation.bonus + simulation.duration - arrival
+    } else {
+        0
+    }
+}
+
+// Queue policy chosen by an improver or scheduler on each call to
+// reorder_intersection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueOrder {
+    Hash,
+    Longest,
+    Oldest,
+    Value,
+    // Random order with a new seed on every call
+    Random,
+    // Any of the policies above, picked at random on every call
+    Mixed,
+}
+
+pub const QUEUE_ORDERS: [&str; 6] =
+    ["hash", "longest", "oldest", "value", "random", "mixed"];
+
+impl QueueOrder {
+    pub fn policy<R: Rng + ?Sized>(&self, rng: &mut R) -> QueuePolicy {
+        match self {
+            Self::Hash => QueuePolicy::Hash,
+            Self::Longest => QueuePolicy::LongestQueue,
+            Self::Oldest => QueuePolicy::OldestCar,
+            Self::Value => QueuePolicy::MostValue,
+            Self::Random => QueuePolicy::Random(rng.gen()),
+            Self::Mixed => match rng.gen_range(0..5) {
+                0 => QueuePolicy::Hash,
+                1 => QueuePolicy::LongestQueue,
+                2 => QueuePolicy::OldestCar,
+                3 => QueuePolicy::MostValue,
+                _ => QueuePolicy::Random(rng.gen()),
+            },
+        }
+    }
+}
+
+impl FromStr for QueueOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(Self::Hash),
+            "longest" => Ok(Self::Longest),
+            "oldest" => Ok(Self::Oldest),
+            "value" => Ok(Self::Value),
+            "random" => Ok(Self::Random),
+            "mixed" => Ok(Self::Mixed),
+            _ => Err(format!(
+                "Invalid queue order '{}', possible values: {}",
+                s,
+                QUEUE_ORDERS.join(", ")
+            )),
+        }
+    }
+}
 
 pub fn reorder_intersection(
     schedule: &mut Schedule,
     inter_id: IntersectionId,
+    queue_policy: QueuePolicy,
 ) -> Score {
-    reorder_intersections(schedule, once(inter_id))
+    reorder_intersections(schedule, once(inter_id), queue_policy)
 }
 
-pub fn reorder_all_intersections(schedule: &mut Schedule) -> Score {
+pub fn reorder_all_intersections(
+    schedule: &mut Schedule,
+    queue_policy: QueuePolicy,
+) -> Score {
     let inter_ids: Vec<IntersectionId> =
         schedule.intersections.keys().copied().collect();
-    reorder_intersections(schedule, inter_ids.into_iter())
+    reorder_intersections(schedule, inter_ids.into_iter(), queue_policy)
 }
 
-pub fn reorder_intersections<I>(schedule: &mut Schedule, inter_ids: I) -> Score
+pub fn reorder_intersections<I>(
+    schedule: &mut Schedule,
+    inter_ids: I,
+    queue_policy: QueuePolicy,
+) -> Score
 where
     I: Iterator<Item = IntersectionId>,
 {
-    let mut score = 0;
-    let mut open_intersections: HashMap<IntersectionId, OpenIntersection> =
-        inter_ids
+    let simulation = schedule.simulation;
+    let mut policy = OpenLights::new(schedule, inter_ids, queue_policy);
+    let mut score_counter = ScoreCounter::new(simulation);
+    engine::run(simulation, &mut policy, &mut score_counter);
+
+    for (&inter_id, intersection) in policy.open_intersections.iter_mut() {
+        intersection.assign_remaining_streets();
+        intersection.update_schedule(schedule, inter_id);
+    }
+
+    score_counter.score
+}
+
+// Light policy in which the lights of open intersections are decided as cars
+// reach them, while the rest of the intersections keep their schedule
+struct OpenLights<'a> {
+    simulation: &'a Simulation,
+    open_intersections: HashMap<IntersectionId, OpenIntersection>,
+    street_inters: Vec<IntersectionId>,
+    lights: Vec<Light>,
+    queue_policy: QueuePolicy,
+}
+
+impl<'a> OpenLights<'a> {
+    fn new<I>(
+        schedule: &Schedule<'a>,
+        inter_ids: I,
+        queue_policy: QueuePolicy,
+    ) -> Self
+    where
+        I: Iterator<Item = IntersectionId>,
+    {
+        let open_intersections = inter_ids
             .map(|inter_id| {
                 let inter = schedule.intersections.get(&inter_id).unwrap();
                 (inter_id, OpenIntersection::from(&inter.turns))
             })
             .collect();
+        let street_inters = schedule
+            .simulation
+            .streets
+            .iter()
+            .map(|street| street.end_intersection)
+            .collect();
 
-    // All cars that haven't reached their end yet
-    let mut moving_cars: HashMap<CarId, Car> = schedule
-        .simulation
-        .car_paths
-        .iter()
-        .map(|path| Car::new(path))
-        .enumerate()
-        .collect();
-
-    // Queues of cars at the end of streets
-    let mut queues: HashMap<StreetId, VecDeque<CarId>> = HashMap::new();
-
-    // Add cars to the queues of their starting street (in order of car ID)
-    for car_id in 0..schedule.simulation.car_paths.len() {
-        let street_id = moving_cars.get_mut(&car_id).unwrap().start();
-        queues
-            .entry(street_id)
-            .and_modify(|cars| cars.push_back(car_id))
-            .or_insert_with(|| vec![car_id].into_iter().collect());
+        Self {
+            simulation: schedule.simulation,
+            open_intersections,
+            street_inters,
+            lights: street_lights(schedule),
+            queue_policy,
+        }
     }
+}
 
-    for time in 0..=schedule.simulation.duration {
-        // Let cars move if possible
-        for (&car_id, car) in moving_cars.iter_mut() {
-            if car.state == CarState::Ready {
-                if let Some(next_street_id) = car.move_forward() {
-                    queues
-                        .entry(next_street_id)
-                        .and_modify(|cars| cars.push_back(car_id))
-                        .or_insert_with(|| vec![car_id].into_iter().collect());
+impl LightPolicy for OpenLights<'_> {
+    fn next_green(&mut self, street_id: StreetId, time: Time) -> Option<Time> {
+        let inter_id = self.street_inters[street_id];
+        match self.open_intersections.get_mut(&inter_id) {
+            Some(open_inter) => {
+                if !open_inter.has_street(street_id) {
+                    // Street is always red
+                    None
+                } else if open_inter.is_or_set_green(street_id, time) {
+                    Some(time)
+                } else {
+                    // Lights may still be set later on
+                    Some(time + 1)
                 }
             }
+            None => self.lights[street_id].next_green(time),
         }
+    }
 
+    fn prioritize(&mut self, time: Time, queues: &mut [WaitingQueue]) {
         // Sorting queues by number of cars waiting tend to produce better
         // schedules, however, it also tends to produce the same results, so
-        // less chance of improving schedules on incremental rounds
-        // // Sort queues by number of cars waiting
-        // let mut queue_order: Vec<(StreetId, usize)> = queues
-        //     .iter()
-        //     .map(|(&street_id, cars)| (street_id, cars.len()))
-        //     .collect();
-        // queue_order.sort_unstable_by(|a, b| b.1.cmp(&a.1));
-
-        // Let cars at the top of the queue cross intersections
-        for (&street_id, cars) in queues.iter_mut() {
-            let inter_id = schedule.get_intersection_id(street_id).unwrap();
-
-            let is_green;
-            if let Some(open_inter) = open_intersections.get_mut(&inter_id) {
-                is_green = open_inter.is_or_set_green(street_id, time);
-            } else {
-                is_green = schedule.is_green(inter_id, street_id, time);
-            }
-
-            if is_green {
-                let car_id = cars.pop_front().unwrap();
-                moving_cars
-                    .get_mut(&car_id)
-                    .unwrap()
-                    .cross_intersection(schedule.simulation);
-            }
-        }
-
-        // Drop empty traffic light queues
-        queues.retain(|_, cars| !cars.is_empty());
-
-        // Update score for cars that reached their end
-        let arrived_cars = moving_cars
-            .iter()
-            .filter(|(_, car)| car.state == CarState::Arrived)
-            .count();
-        score += (schedule.simulation.bonus
-            + (schedule.simulation.duration - time))
-            * Score::try_from(arrived_cars).unwrap();
-
-        // Remove cars that reached their end
-        moving_cars.retain(|_, car| car.state != CarState::Arrived);
-    }
-
-    for (&inter_id, intersection) in open_intersections.iter_mut() {
-        intersection.assign_remaining_streets();
-        intersection.update_schedule(schedule, inter_id);
+        // less chance of improving schedules on incremental rounds; callers
+        // pick the policy (random by default) to trade one for the other
+        self.queue_policy.sort(self.simulation, time, queues);
     }
-
-    score
 }
 
 struct OpenIntersection {
@@ -143,6 +267,12 @@ impl OpenIntersection {
         }
     }
 
+    fn has_street(&self, street_id: StreetId) -> bool {
+        self.cycle > 0
+            && (self.streets.contains_key(&street_id)
+                || self.slots.iter().any(|&(id, _)| id == Some(street_id)))
+    }
+
     fn is_or_set_green(&mut self, street_id: StreetId, at_time: Time) -> bool {
         if self.cycle == 0 {
             return false;
@@ -208,15 +338,13 @@ impl OpenIntersection {
         target_offset: Time,
     ) -> Option<usize> {
         // Try an inner swap first
-        let exclude_range;
-        match self.inner_swap(slot_idx, target_time, target_offset) {
-            Ok(target_idx) => {
-                return Some(target_idx);
-            }
-            Err(range) => {
-                exclude_range = range;
-            }
-        }
+        let exclude_range =
+            match self.inner_swap(slot_idx, target_time, target_offset) {
+                Ok(target_idx) => {
+                    return Some(target_idx);
+                }
+                Err(range) => range,
+            };
 
         // Now try an outer swap
         let target_idx = self.outer_swap(
@@ -240,7 +368,7 @@ impl OpenIntersection {
         target_offset: Time,
     ) -> Result<usize, RangeInclusive<usize>> {
         let mut target_found = false;
-        let mut all_sums = AllSums::default();
+        let mut sums = SubsetSums::new(self.cycle);
 
         let mut max_offset = target_offset;
         let mut min_offset = if max_offset >= target_time {
@@ -252,7 +380,7 @@ impl OpenIntersection {
         // Expand region to the right
         let mut end_idx = slot_idx;
         for idx in slot_idx..self.slots.len() {
-            if target_found && all_sums.contains_any(min_offset..=max_offset) {
+            if target_found && sums.contains_any(min_offset..=max_offset) {
                 // Viable swap found
                 break;
             }
@@ -266,14 +394,14 @@ impl OpenIntersection {
             if !target_found && curr_time == target_time {
                 target_found = true;
             } else {
-                all_sums.add(curr_time);
+                sums.add(curr_time);
             }
         }
 
         // Expand region to the left if necessary
         let mut start_idx = slot_idx;
         for idx in (0..slot_idx).rev() {
-            if target_found && all_sums.contains_any(min_offset..=max_offset) {
+            if target_found && sums.contains_any(min_offset..=max_offset) {
                 // Viable swap found
                 break;
             }
@@ -293,18 +421,18 @@ impl OpenIntersection {
             if !target_found && curr_time == target_time {
                 target_found = true;
             } else {
-                all_sums.add(curr_time);
+                sums.add(curr_time);
             }
         }
 
-        if !target_found || !all_sums.contains_any(min_offset..=max_offset) {
+        if !target_found || !sums.contains_any(min_offset..=max_offset) {
             // No viable swap found: return the examined region
             return Err(start_idx..=end_idx);
         }
 
         let mut slots_copy = self.slots[start_idx..=end_idx].to_vec();
 
-        for time in all_sums
+        for time in sums
             .get_min_sum_values(min_offset..=max_offset)
             .unwrap()
             .into_iter()
@@ -393,7 +521,7 @@ impl OpenIntersection {
         for start_idx in 0..self.slots.len() {
             let mut acc_time = 0;
             let mut target_found = false;
-            let mut all_sums = AllSums::default();
+            let mut sums = SubsetSums::new(self.cycle);
 
             // Skip if start_idx is inside exclude range
             if exclude_range.contains(&start_idx) {
@@ -425,17 +553,17 @@ impl OpenIntersection {
                 if !target_found && curr_time == target_time {
                     target_found = true;
                 } else {
-                    all_sums.add(curr_time);
+                    sums.add(curr_time);
                 }
 
                 if acc_time == total_time {
                     if !target_found
-                        || !all_sums.contains_any(min_offset..=max_offset)
+                        || !sums.contains_any(min_offset..=max_offset)
                     {
                         break;
                     }
 
-                    let offset_slots = all_sums
+                    let offset_slots = sums
                         .get_min_sum_values(min_offset..=max_offset)
                         .unwrap();
                     let target_delta = offset_slots.len();
@@ -509,9 +637,9 @@ impl OpenIntersection {
         let mid_start = left_start + right.len();
         let new_right_start = mid_start + middle.len();
 
-        self.slots[left_start..mid_start].copy_from_slice(&right);
-        self.slots[mid_start..new_right_start].copy_from_slice(&middle);
-        self.slots[new_right_start..=right_end].copy_from_slice(&left);
+        self.slots[left_start..mid_start].copy_from_slice(right);
+        self.slots[mid_start..new_right_start].copy_from_slice(middle);
+        self.slots[new_right_start..=right_end].copy_from_slice(left);
     }
 
     fn assign_remaining_streets(&mut self) {
@@ -519,11 +647,14 @@ impl OpenIntersection {
             if slot_street.is_some() {
                 continue;
             }
+            // Lowest ID among matching streets, so that the result does not
+            // depend on the order of the hash map
             let street_id: StreetId = self
                 .streets
                 .iter()
-                .find(|(_, &time)| time == *slot_time)
+                .filter(|(_, &time)| time == *slot_time)
                 .map(|(&id, _)| id)
+                .min()
                 .unwrap();
             self.streets.remove(&street_id);
             *slot_street = Some(street_id);
@@ -541,3 +672,100 @@ impl OpenIntersection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::simulation;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn policies_of_orders() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        let salt: u64 = StdRng::seed_from_u64(2021).gen();
+        assert_eq!(
+            QueueOrder::Random.policy(&mut rng),
+            QueuePolicy::Random(salt)
+        );
+        assert_eq!(
+            QueueOrder::Longest.policy(&mut rng),
+            QueuePolicy::LongestQueue
+        );
+        for (s, order) in QUEUE_ORDERS.iter().zip([
+            QueueOrder::Hash,
+            QueueOrder::Longest,
+            QueueOrder::Oldest,
+            QueueOrder::Value,
+            QueueOrder::Random,
+            QueueOrder::Mixed,
+        ]) {
+            assert_eq!(s.parse::<QueueOrder>(), Ok(order));
+        }
+        assert!("shortest".parse::<QueueOrder>().is_err());
+    }
+
+    // Intersection 0 where the car on street 0 takes the first 4 seconds,
+    // while cars queue on streets 4 (one car from 1), 5 (three cars from 2)
+    // and 6 (two cars from 3); the car of street 6 earns the most points, then
+    // the car of street 4, then those of street 5
+    fn contested_slot() -> Simulation {
+        simulation(
+            20,
+            6,
+            &[
+                (5, 0, 1),
+                (5, 1, 1),
+                (5, 2, 1),
+                (5, 3, 1),
+                (1, 0, 1),
+                (2, 0, 2),
+                (3, 0, 3),
+                (0, 4, 1),
+                (0, 4, 2),
+                (0, 4, 3),
+            ],
+            &[
+                &[0, 7],
+                &[1, 4, 8],
+                &[2, 5, 9],
+                &[2, 5, 9],
+                &[2, 5, 9],
+                &[3, 6, 7],
+                &[3, 6, 7],
+            ],
+            100,
+        )
+    }
+
+    #[test]
+    fn contested_slot_order() {
+        let simulation = contested_slot();
+        let mut schedule = Schedule::new(&simulation);
+        for inter_id in 1..4 {
+            schedule.add_street(inter_id, inter_id as StreetId, 1);
+        }
+        schedule.add_street(0, 0, 4);
+        for street_id in 4..7 {
+            schedule.add_street(0, street_id, 1);
+        }
+
+        // Streets 4, 5 and 6 contend for the slot free at 4
+        for (queue_policy, order) in [
+            (QueuePolicy::LongestQueue, [5, 6, 4]),
+            (QueuePolicy::OldestCar, [4, 5, 6]),
+            (QueuePolicy::MostValue, [6, 4, 5]),
+        ] {
+            let mut reordered = schedule.clone();
+            let score = reorder_intersection(&mut reordered, 0, queue_policy);
+            let turns = &reordered.intersections.get(&0).unwrap().turns;
+            assert_eq!(
+                turns,
+                &vec![(0, 4), (order[0], 1), (order[1], 1), (order[2], 1)],
+                "{:?}",
+                queue_policy,
+            );
+            assert_eq!(reordered.score(), Ok(score));
+        }
+    }
+}
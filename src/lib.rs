@@ -5,15 +5,38 @@ use std::num::ParseIntError;
 use std::str::FromStr;
 
 pub mod adapt;
+pub mod anneal;
+pub mod arrival;
+pub mod batch;
+pub mod bestof;
+pub mod budget;
+pub mod cartrace;
+pub mod chain;
+pub mod checkpoint;
+pub mod config;
+pub mod diff;
+pub mod engine;
+pub mod exact;
 pub mod greedy;
 pub mod improve;
 pub mod intersect;
+pub mod merge;
 pub mod naive;
 pub mod phased;
+pub mod registry;
+pub mod rescore;
 pub mod sched;
+pub mod scoring;
+pub mod seed;
 pub mod shuffle;
 pub mod sums;
+#[cfg(test)]
+mod testing;
+pub mod timeline;
 pub mod traffic;
+pub mod triage;
+pub mod validate;
+pub mod wave;
 
 pub type Time = u32;
 pub type CarId = usize;
@@ -29,6 +52,7 @@ pub struct Simulation {
     pub bonus: Score,
 }
 
+#[derive(Clone)]
 pub struct Street {
     pub name: String,
     pub start_insersection: IntersectionId,
@@ -48,11 +72,7 @@ impl Simulation {
                         .skip(1)
                         .map(|&street_id| self.streets[street_id].travel_time)
                         .sum();
-                    if min_travel_time <= self.duration {
-                        self.duration - min_travel_time
-                    } else {
-                        0
-                    }
+                    self.duration.saturating_sub(min_travel_time)
                 })
                 .sum::<Score>()
     }
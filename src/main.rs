@@ -1,23 +1,227 @@
-use clap::{crate_description, value_t, App, Arg};
+use clap::{
+    crate_description, value_t, App, AppSettings, Arg, ArgMatches, SubCommand,
+};
 use ctrlc::set_handler;
 use hashcode2021::adapt::AdaptiveScheduler;
+use hashcode2021::anneal::SimulatedAnnealingImprover;
+use hashcode2021::arrival::ArrivalScheduler;
+use hashcode2021::batch::BatchRunner;
+use hashcode2021::bestof::{best_schedules, run_seed};
+use hashcode2021::budget::{format_duration, parse_duration, TimeBudget};
+use hashcode2021::cartrace::{write_csv, write_json};
+use hashcode2021::chain::{parse_stages, ChainImprover};
+use hashcode2021::checkpoint::{find_latest, Checkpointer, RunConfig};
+use hashcode2021::config::Config;
+use hashcode2021::diff::ScheduleDiff;
 use hashcode2021::greedy::GreedyImprover;
-use hashcode2021::improve::IncrementalImprover;
+use hashcode2021::improve::{Improver, IncrementalImprover};
+use hashcode2021::intersect::{QueueOrder, QUEUE_ORDERS};
+use hashcode2021::merge::merge_schedules;
 use hashcode2021::naive::NaiveScheduler;
 use hashcode2021::phased::PhasedImprover;
+use hashcode2021::registry::{EntryStatus, Registry};
 use hashcode2021::sched::{Schedule, Scheduler};
+use hashcode2021::scoring::{self, score_files, ScoreResult};
 use hashcode2021::shuffle::ShuffleImprover;
+use hashcode2021::timeline::QueueTimeline;
 use hashcode2021::traffic::TrafficScheduler;
-use hashcode2021::{Simulation, Time};
+use hashcode2021::triage::{Triage, TriageReport};
+use hashcode2021::validate::validate_schedule;
+use hashcode2021::wave::GreenWaveImprover;
+use hashcode2021::{Score, Simulation, Time};
 use image::ImageFormat;
-use log::info;
-use std::fs::{read_to_string, write};
+use log::{info, warn};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write, File};
+use std::io::BufWriter;
+use std::path::Path;
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+const IMPROVERS: [&str; 5] = ["shuffle", "phased", "greedy", "anneal", "wave"];
+
+// Options that configure improvers; checkpoints can only be resumed by runs
+// with the same values for these options
+const IMPROVER_OPTIONS: [&str; 13] = [
+    "round-robin",
+    "queue-order",
+    "min-wait-time",
+    "max-add-time",
+    "max-sub-time",
+    "max-streets-per-inter",
+    "max-streets-per-round",
+    "max-shuffles",
+    "anneal-initial-temp",
+    "anneal-min-temp",
+    "anneal-cooling-rate",
+    "wave-max-corridors",
+    "wave-min-cars",
+];
 
 fn main() {
     let args = App::new(crate_description!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Run the same scheduler and improver over several inputs")
+                .usage(
+                    "hashcode2021 batch [OPTIONS] -- <scheduler> [improver] \
+                     [pipeline options]",
+                )
+                .arg(
+                    Arg::with_name("inputs")
+                        .help("Comma-separated names of the inputs to run")
+                        .long("inputs")
+                        .default_value("a,b,c,d,e,f"),
+                )
+                .arg(
+                    Arg::with_name("input-dir")
+                        .help("Directory with the simulation files")
+                        .long("input-dir")
+                        .default_value("input"),
+                )
+                .arg(
+                    Arg::with_name("output-dir")
+                        .help("Directory where schedules and logs are written")
+                        .long("output-dir")
+                        .default_value("output"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .help(
+                            "Number of inputs run at the same time (number of \
+                             cores by default), sharing the cores between them",
+                        )
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pipeline")
+                        .value_name("pipeline")
+                        .help(
+                            "Scheduler, improver and options of every run, as \
+                             given to a single run",
+                        )
+                        .required(true)
+                        .multiple(true)
+                        .last(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show the changes between two schedules and their effect on the score")
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("simulation file")
+                        .help("File with simulation input")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("old")
+                        .value_name("old schedule file")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .value_name("new schedule file")
+                        .required(true)
+                        .index(3),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge schedules by taking the best intersections from each one")
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("simulation file")
+                        .help("File with simulation input")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("schedules")
+                        .value_name("schedule files")
+                        .required(true)
+                        .min_values(2)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("output file")
+                        .help("File to save merged schedule")
+                        .short("o")
+                        .long("output")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("registry")
+                .about(
+                    "Check the scores of the solutions in an output directory \
+                     and report the best ones",
+                )
+                .arg(
+                    Arg::with_name("input-dir")
+                        .help("Directory with the simulation files")
+                        .long("input-dir")
+                        .default_value("input"),
+                )
+                .arg(
+                    Arg::with_name("output-dir")
+                        .help("Directory with solutions named <input>_<score>.txt")
+                        .long("output-dir")
+                        .default_value("output"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("score")
+                .about("Score schedule files without running any scheduler or improver")
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("simulation file")
+                        .help("File with simulation input")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("schedules")
+                        .value_name("schedule files")
+                        .required(true)
+                        .multiple(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .help("Print scores as JSON")
+                        .long("json"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check schedule files against the rules of the problem statement")
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("simulation file")
+                        .help("File with simulation input")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("schedules")
+                        .value_name("schedule files")
+                        .required(true)
+                        .multiple(true)
+                        .index(2),
+                ),
+        )
         .arg(
             Arg::with_name("input")
                 .value_name("simulation file")
@@ -29,16 +233,31 @@ fn main() {
             Arg::with_name("scheduler")
                 .help("Load schedule from file or run scheduler algorithm")
                 .required(true)
-                .possible_values(&["load", "naive", "adaptive", "traffic"])
+                .possible_values(&[
+                    "load", "naive", "adaptive", "traffic", "arrival",
+                ])
                 .index(2),
         )
         .arg(
             Arg::with_name("improver")
                 .value_name("incremental improver")
-                .help("Incremental improver algorithm")
-                .possible_values(&["shuffle", "phased", "greedy"])
+                .help(
+                    "Incremental improver algorithm (shuffle, phased, greedy, \
+                     anneal or wave), or comma-separated improvers run one \
+                     after another, each one optionally limited to a number \
+                     of rounds (e.g. greedy:10,phased,shuffle)",
+                )
                 .index(3),
         )
+        .arg(
+            Arg::with_name("round-robin")
+                .help(
+                    "Go back to the first improver after the last one, until \
+                     none of them improves the schedule",
+                )
+                .long("round-robin")
+                .requires("improver"),
+        )
         .arg(
             Arg::with_name("schedule")
                 .value_name("schedule file")
@@ -56,6 +275,13 @@ fn main() {
                 .long("output")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("save-if-better")
+                .value_name("output dir")
+                .help("Save schedule as <dir>/<input>_<score>.txt if it beats the best one there")
+                .long("save-if-better")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("traffic-min-log-base")
                 .help("The minimum logarithm base used by the traffic scheduler")
@@ -68,13 +294,97 @@ fn main() {
                 .long("traffic-max-log-base")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("arrival-max-scale")
+                .help(
+                    "Maximum green time given by the arrival scheduler to the \
+                     busiest street of an intersection",
+                )
+                .long("arrival-max-scale")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("best-of")
-                .help("Run scheduler multiple times, keep best schedule")
+                .help("Run scheduler multiple times in parallel, keep best schedule")
                 .short("b")
                 .long("best-of")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("top-k")
+                .help("Improve the K best schedules of --best-of in parallel, keep the best result")
+                .long("top-k")
+                .takes_value(true)
+                .requires("improver")
+                .conflicts_with("resume"),
+        )
+        .arg(
+            Arg::with_name("time-limit")
+                .help("Stop schedulers and improvers after some time (e.g. 90s, 10m, 1h30m)")
+                .short("t")
+                .long("time-limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .help(
+                    "TOML or JSON file configuring the scheduler and \
+                     improvers (flags override it)",
+                )
+                .long("config")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("triage")
+                .help(
+                    "Reschedule without the cars that don't arrive or cost \
+                     other cars more points than they earn, while it raises \
+                     the score",
+                )
+                .long("triage")
+                .conflicts_with("resume"),
+        )
+        .arg(
+            Arg::with_name("triage-report")
+                .help("Save the cars sacrificed by --triage and their values as JSON")
+                .long("triage-report")
+                .takes_value(true)
+                .requires("triage"),
+        )
+        .arg(
+            Arg::with_name("queue-order")
+                .help(
+                    "Order in which adaptive scheduling and reordering of \
+                     intersections let waiting cars claim slots",
+                )
+                .long("queue-order")
+                .takes_value(true)
+                .possible_values(&QUEUE_ORDERS),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .help("Seed of the random number generators (random by default)")
+                .long("seed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint-dir")
+                .help("Directory where improvers periodically save the best schedule")
+                .long("checkpoint-dir")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .help("Time between checkpoints (e.g. 90s, 10m, 1h30m; default 5m)")
+                .long("checkpoint-interval")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .help("Resume from the newest checkpoint with the same improver configuration")
+                .long("resume")
+                .requires_all(&["checkpoint-dir", "improver"]),
+        )
         .arg(
             Arg::with_name("incremental-rounds")
                 .help("Number of incremental rounds")
@@ -123,6 +433,57 @@ fn main() {
                 .long("max-shuffles")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("anneal-initial-temp")
+                .help("Initial temperature of the simulated annealing improver")
+                .long("anneal-initial-temp")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("anneal-min-temp")
+                .help("Temperature at which the simulated annealing improver stops")
+                .long("anneal-min-temp")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("anneal-cooling-rate")
+                .help("Factor applied to the temperature after every simulated annealing step")
+                .long("anneal-cooling-rate")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wave-max-corridors")
+                .help("Number of busiest corridors aligned by the green wave improver")
+                .long("wave-max-corridors")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wave-min-cars")
+                .help("Minimum number of cars taking each turn of a green wave corridor")
+                .long("wave-min-cars")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("car-trace")
+                .help("Save trace of every car (JSON if file name ends in .json, CSV otherwise)")
+                .long("car-trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queue-timeline")
+                .help(
+                    "Save queue length of every street over time (JSON if \
+                     file name ends in .json, CSV otherwise)",
+                )
+                .long("queue-timeline")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queue-heatmap")
+                .help("Save PNG image with queue length of every street over time")
+                .long("queue-heatmap")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("png-image")
                 .help("Save PNG image with schedule representation")
@@ -132,14 +493,113 @@ fn main() {
         )
         .get_matches();
 
+    env_logger::init();
+    match args.subcommand() {
+        ("batch", Some(sub_args)) => return batch_command(sub_args),
+        ("diff", Some(sub_args)) => return diff_command(sub_args),
+        ("merge", Some(sub_args)) => return merge_command(sub_args),
+        ("registry", Some(sub_args)) => return registry_command(sub_args),
+        ("score", Some(sub_args)) => return score_command(sub_args),
+        ("validate", Some(sub_args)) => return validate_command(sub_args),
+        _ => (),
+    }
+
+    let config = match args.value_of("config") {
+        Some(path) => Config::load(Path::new(path)).unwrap_or_else(|err| {
+            println!("Invalid configuration '{}': {}", path, err);
+            exit(2);
+        }),
+        None => Config::default(),
+    };
+
     let best_of = if args.is_present("best-of") {
         let value = value_t!(args.value_of("best-of"), u32)
             .unwrap_or_else(|e| e.exit());
         value
     } else {
-        1
+        config.best_of.unwrap_or(1)
     };
 
+    let top_k = if args.is_present("top-k") {
+        value_t!(args.value_of("top-k"), u32).unwrap_or_else(|e| e.exit())
+    } else {
+        config.top_k.unwrap_or(1)
+    };
+    if top_k == 0 || top_k > best_of {
+        clap::Error::with_description(
+            &format!(
+                "Invalid value for '--top-k': must be between 1 and the \
+                number of schedules ({})",
+                best_of
+            ),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
+    if args.is_present("triage") && args.value_of("scheduler") == Some("load") {
+        clap::Error::with_description(
+            "'--triage' needs a scheduler algorithm to run again, not 'load'",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    }
+
+    let time_limit = match args.value_of("time-limit") {
+        Some(value) => Some(parse_duration(value).unwrap_or_else(|err| {
+            clap::Error::with_description(
+                &format!("Invalid value for '--time-limit': {}", err),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        })),
+        // Already validated on load
+        None => config
+            .time_limit
+            .as_ref()
+            .map(|value| parse_duration(value).unwrap()),
+    };
+
+    let improver_stages = args.value_of("improver").map(|value| {
+        let stages = parse_stages(value).unwrap_or_else(|err| {
+            clap::Error::with_description(
+                &format!("Invalid value for '<incremental improver>': {}", err),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        });
+        for (name, _) in stages.iter() {
+            if !IMPROVERS.contains(&name.as_str()) {
+                clap::Error::with_description(
+                    &format!(
+                        "'{}' isn't a valid improver, possible values: {}",
+                        name,
+                        IMPROVERS.join(", "),
+                    ),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            }
+        }
+        stages
+    });
+
+    let checkpoint_interval = args
+        .value_of("checkpoint-interval")
+        .map(|value| {
+            parse_duration(value).unwrap_or_else(|err| {
+                clap::Error::with_description(
+                    &format!(
+                        "Invalid value for '--checkpoint-interval': {}",
+                        err
+                    ),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+        })
+        .unwrap_or_else(|| Duration::from_secs(300));
+
     let traffic_min_log_base = if args.is_present("traffic-min-log-base") {
         let value = value_t!(args.value_of("traffic-min-log-base"), f32)
             .unwrap_or_else(|e| e.exit());
@@ -156,10 +616,39 @@ fn main() {
         None
     };
 
+    let arrival_max_scale = if args.is_present("arrival-max-scale") {
+        let value = value_t!(args.value_of("arrival-max-scale"), Time)
+            .unwrap_or_else(|e| e.exit());
+        if value == 0 {
+            clap::Error::with_description(
+                "Invalid value for '--arrival-max-scale': must be at least 1",
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        }
+        Some(value)
+    } else {
+        None
+    };
+
+    let seed = if args.is_present("seed") {
+        value_t!(args.value_of("seed"), u64).unwrap_or_else(|e| e.exit())
+    } else {
+        config.seed.unwrap_or_else(rand::random)
+    };
+
     let incremental_rounds = if args.is_present("incremental-rounds") {
         let value = value_t!(args.value_of("incremental-rounds"), u32)
             .unwrap_or_else(|e| e.exit());
         Some(value)
+    } else {
+        config.incremental_rounds
+    };
+
+    let queue_order = if args.is_present("queue-order") {
+        let value = value_t!(args.value_of("queue-order"), QueueOrder)
+            .unwrap_or_else(|e| e.exit());
+        Some(value)
     } else {
         None
     };
@@ -212,10 +701,96 @@ fn main() {
         None
     };
 
-    env_logger::init();
+    let anneal_initial_temp = if args.is_present("anneal-initial-temp") {
+        let value = value_t!(args.value_of("anneal-initial-temp"), f64)
+            .unwrap_or_else(|e| e.exit());
+        Some(value)
+    } else {
+        None
+    };
+
+    let anneal_min_temp = if args.is_present("anneal-min-temp") {
+        let value = value_t!(args.value_of("anneal-min-temp"), f64)
+            .unwrap_or_else(|e| e.exit());
+        Some(value)
+    } else {
+        None
+    };
+
+    let anneal_cooling_rate = if args.is_present("anneal-cooling-rate") {
+        let value = value_t!(args.value_of("anneal-cooling-rate"), f64)
+            .unwrap_or_else(|e| e.exit());
+        Some(value)
+    } else {
+        None
+    };
+
+    let wave_max_corridors = if args.is_present("wave-max-corridors") {
+        let value = value_t!(args.value_of("wave-max-corridors"), usize)
+            .unwrap_or_else(|e| e.exit());
+        Some(value)
+    } else {
+        None
+    };
+
+    let wave_min_cars = if args.is_present("wave-min-cars") {
+        let value = value_t!(args.value_of("wave-min-cars"), usize)
+            .unwrap_or_else(|e| e.exit());
+        Some(value)
+    } else {
+        None
+    };
+
+    // Same checks as for the configuration file, so that annealing stops
+    for (name, temp) in [
+        ("anneal-initial-temp", anneal_initial_temp),
+        ("anneal-min-temp", anneal_min_temp),
+    ] {
+        if temp.is_some_and(|temp| temp <= 0.0) {
+            clap::Error::with_description(
+                &format!("Invalid value for '--{}': must be above 0", name),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        }
+    }
+    if let (Some(initial_temp), Some(min_temp)) = (
+        anneal_initial_temp.or(config.anneal.initial_temp),
+        anneal_min_temp.or(config.anneal.min_temp),
+    ) {
+        if min_temp >= initial_temp {
+            clap::Error::with_description(
+                "Invalid value for '--anneal-min-temp': must be below the \
+                initial temperature",
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        }
+    }
+    if anneal_cooling_rate.is_some_and(|rate| rate <= 0.0 || rate >= 1.0) {
+        clap::Error::with_description(
+            "Invalid value for '--anneal-cooling-rate': must be between 0 \
+            and 1",
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
     println!(crate_description!());
+    // Log the seed so that any run can be reproduced with --seed
+    println!("\nRandom seed: {}", seed);
+
+    let mut budget = TimeBudget::new(time_limit);
+
+    let abort_flag = abort_on_ctrl_c();
+
+    if let Some(limit) = time_limit {
+        info!("Time limit: {}", format_duration(limit));
+        budget.start_timer(abort_flag.clone());
+    }
 
     let simulation = load_simulation(args.value_of("input").unwrap());
+    budget.end_stage("Loading");
     println!(
         "\n\
         Simulation\n\
@@ -224,46 +799,164 @@ fn main() {
         simulation
     );
 
-    let schedule = match args.value_of("scheduler").unwrap() {
-        "load" => {
-            let mut schedule = Schedule::new(&simulation);
-            load_schedule(&mut schedule, args.value_of("schedule").unwrap());
-            schedule
+    let run_config = args.value_of("improver").map(|improver| {
+        let mut options: BTreeMap<String, String> = IMPROVER_OPTIONS
+            .iter()
+            .filter(|&name| args.is_present(name))
+            .map(|&name| {
+                // Flags have no value
+                let value = args.value_of(name).unwrap_or("true");
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        if args.is_present("config") {
+            // Values rather than file name, so that edits are detected
+            options.insert(
+                "config".to_string(),
+                serde_json::to_string(&config).unwrap(),
+            );
         }
-        algorithm => {
-            let scheduler: Box<dyn Scheduler> = match algorithm {
-                "adaptive" => Box::new(AdaptiveScheduler::default()),
-                "naive" => Box::new(NaiveScheduler::default()),
-                "traffic" => {
-                    let mut scheduler = TrafficScheduler::default();
-                    if let Some(base) = traffic_min_log_base {
-                        scheduler.set_min_base(base);
-                    }
-                    if let Some(base) = traffic_max_log_base {
-                        scheduler.set_max_base(base);
-                    }
-                    Box::new(scheduler)
-                }
-                _ => unreachable!(),
-            };
+        RunConfig {
+            input: args.value_of("input").unwrap().to_string(),
+            improver: improver.to_string(),
+            options,
+        }
+    });
+
+    let resumed = if args.is_present("resume") {
+        let dir = Path::new(args.value_of("checkpoint-dir").unwrap());
+        match find_latest(dir, run_config.as_ref().unwrap()) {
+            Ok(Some(checkpoint)) => Some(checkpoint),
+            Ok(None) => {
+                warn!(
+                    "No checkpoint with the same configuration found in '{}'",
+                    dir.display()
+                );
+                None
+            }
+            Err(err) => {
+                println!("Failed to read checkpoints: {}", err);
+                exit(2);
+            }
+        }
+    } else {
+        None
+    };
 
-            let mut best_score = 0;
-            let mut best_sched = None;
-            for num in 1..=best_of {
-                let sched = scheduler.schedule(&simulation);
-                let score = sched.score().unwrap_or(0);
-                info!("Schedule {}/{}: score {}", num, best_of, score);
-                if score >= best_score {
-                    best_score = score;
-                    best_sched = Some(sched);
+    let build_scheduler = |seed: u64| -> Box<dyn Scheduler> {
+        match args.value_of("scheduler").unwrap() {
+            "adaptive" => {
+                let mut scheduler = AdaptiveScheduler::default();
+                scheduler.set_seed(seed);
+                config.adaptive.configure(&mut scheduler);
+                if let Some(value) = queue_order {
+                    scheduler.set_queue_order(value);
+                }
+                Box::new(scheduler)
+            }
+            "naive" => Box::new(NaiveScheduler::default()),
+            "arrival" => {
+                let mut scheduler = ArrivalScheduler::default();
+                config.arrival.configure(&mut scheduler);
+                if let Some(value) = arrival_max_scale {
+                    scheduler.set_max_scale(value);
+                }
+                Box::new(scheduler)
+            }
+            "traffic" => {
+                let mut scheduler = TrafficScheduler::default();
+                scheduler.set_seed(seed);
+                config.traffic.configure(&mut scheduler);
+                if let Some(value) = queue_order {
+                    scheduler.set_queue_order(value);
                 }
+                if let Some(base) = traffic_min_log_base {
+                    scheduler.set_min_base(base);
+                }
+                if let Some(base) = traffic_max_log_base {
+                    scheduler.set_max_base(base);
+                }
+                Box::new(scheduler)
             }
-            best_sched.unwrap()
+            _ => unreachable!(),
         }
     };
 
+    let schedules = if let Some((path, meta)) = resumed.as_ref() {
+        info!(
+            "Resuming from checkpoint '{}', round {}, score {}",
+            path.display(),
+            meta.round,
+            meta.score,
+        );
+        let mut schedule = Schedule::new(&simulation);
+        load_schedule(&mut schedule, &path.to_string_lossy());
+        vec![(0, schedule)]
+    } else {
+        match args.value_of("scheduler").unwrap() {
+            "load" => {
+                let mut schedule = Schedule::new(&simulation);
+                load_schedule(
+                    &mut schedule,
+                    args.value_of("schedule").unwrap(),
+                );
+                vec![(0, schedule)]
+            }
+            _ => best_schedules(
+                abort_flag.clone(),
+                &simulation,
+                best_of,
+                top_k as usize,
+                seed,
+                build_scheduler,
+            )
+            .into_iter()
+            .map(|(run_idx, schedule, _)| (run_idx, schedule))
+            .collect(),
+        }
+    };
+    budget.end_stage("Scheduler");
+
+    let (schedules, triage_report) = if args.is_present("triage") {
+        let mut triage = Triage::default();
+        config.triage.configure(&mut triage);
+        // Each schedule is rescheduled with the seed of the run that built it
+        let mut results: Vec<(Schedule, TriageReport)> = schedules
+            .into_par_iter()
+            .map(|(run_idx, schedule)| {
+                let scheduler_seed = run_seed(seed, run_idx);
+                let build = || build_scheduler(scheduler_seed);
+                triage.run(&abort_flag, build, schedule)
+            })
+            .collect();
+        budget.end_stage("Triage");
+        // Best schedule after triage first, in scheduler order on ties
+        results.sort_by_key(|(_, report)| Reverse(report.score_after));
+        let (schedules, mut reports): (Vec<Schedule>, Vec<TriageReport>) =
+            results.into_iter().unzip();
+        (schedules, Some(reports.swap_remove(0)))
+    } else {
+        let schedules = schedules
+            .into_iter()
+            .map(|(_, schedule)| schedule)
+            .collect();
+        (schedules, None)
+    };
+    if let Some(report) = triage_report.as_ref() {
+        println!(
+            "\n\
+            Triage\n\
+            ------\n\
+            {}",
+            report
+        );
+        if let Some(filename) = args.value_of("triage-report") {
+            write_triage_report(filename, report);
+        }
+    }
+
     let build_image = args.value_of("png-image").is_some();
-    let sched_stats = match schedule.stats(build_image) {
+    let sched_stats = match schedules[0].stats(build_image) {
         Ok(score) => score,
         Err(err) => {
             println!("\nError: {}", err);
@@ -274,72 +967,175 @@ fn main() {
         "\n\
         Schedule\n\
         --------\n\
-        {}",
+        {}\n\
+        Schedule hash   : {:016x}",
         sched_stats,
+        schedules[0].canonical_hash(),
     );
 
-    // Setup Ctrl-C handler
-    let abort_flag = Arc::new(AtomicBool::new(false));
-    let abort_clone = abort_flag.clone();
-    set_handler(move || {
-        eprintln!("Received termination request");
-        abort_clone.store(true, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
-
     let (final_schedule, final_stats) = match args.value_of("improver") {
-        Some(algorithm_name) => {
-            let mut improver = IncrementalImprover::new(abort_flag);
-            if let Some(rounds) = incremental_rounds {
-                improver.set_max_rounds(rounds);
-            }
+        Some(_) => {
+            let build_incremental = |idx: usize| -> IncrementalImprover {
+                let mut improver = IncrementalImprover::new(abort_flag.clone());
+                if schedules.len() > 1 {
+                    improver.set_name(&format!("Schedule {}", idx + 1));
+                }
+                if let Some(rounds) = incremental_rounds {
+                    improver.set_max_rounds(rounds);
+                }
+                if let Some((_, meta)) = resumed.as_ref() {
+                    improver.set_first_round(meta.round + 1);
+                }
+                if let Some(dir) = args.value_of("checkpoint-dir") {
+                    improver.set_checkpointer(Checkpointer::new(
+                        Path::new(dir),
+                        checkpoint_interval,
+                        run_config.clone().unwrap(),
+                    ));
+                }
+                improver
+            };
 
-            let improved_schedule = match algorithm_name {
-                "greedy" => {
-                    let mut greedy = GreedyImprover::default();
-                    if let Some(value) = min_wait_time {
-                        greedy.set_min_wait_time(value);
+            let build_improver = |name: &str, seed: u64| -> Box<dyn Improver> {
+                match name {
+                    "greedy" => {
+                        let mut greedy = GreedyImprover::default();
+                        greedy.set_seed(seed);
+                        config.greedy.configure(&mut greedy);
+                        if let Some(value) = queue_order {
+                            greedy.set_queue_order(value);
+                        }
+                        if let Some(value) = min_wait_time {
+                            greedy.set_min_wait_time(value);
+                        }
+                        if let Some(value) = max_add_time {
+                            greedy.set_max_add_time(value);
+                        }
+                        if let Some(value) = max_streets_per_round {
+                            greedy.set_max_streets(value);
+                        }
+                        Box::new(greedy)
                     }
-                    if let Some(value) = max_add_time {
-                        greedy.set_max_add_time(value);
+                    "phased" => {
+                        let mut phased = PhasedImprover::default();
+                        phased.set_seed(seed);
+                        config.phased.configure(&mut phased);
+                        if let Some(value) = queue_order {
+                            phased.set_queue_order(value);
+                        }
+                        if let Some(value) = max_add_time {
+                            phased.set_max_add_time(value);
+                        }
+                        if let Some(value) = max_sub_time {
+                            phased.set_max_sub_time(value);
+                        }
+                        if let Some(value) = max_shuffles {
+                            phased.set_max_shuffles(value);
+                        }
+                        if let Some(value) = max_streets_per_inter {
+                            phased.set_max_streets_per_inter(value);
+                        }
+                        Box::new(phased)
                     }
-                    if let Some(value) = max_streets_per_round {
-                        greedy.set_max_streets(value);
+                    "shuffle" => {
+                        let mut shuffle = ShuffleImprover::default();
+                        shuffle.set_seed(seed);
+                        config.shuffle.configure(&mut shuffle);
+                        if let Some(value) = min_wait_time {
+                            shuffle.set_min_wait_time(value);
+                        }
+                        if let Some(value) = max_streets_per_round {
+                            shuffle.set_max_streets(value);
+                        }
+                        if let Some(value) = max_shuffles {
+                            shuffle.set_max_shuffles(value);
+                        }
+                        Box::new(shuffle)
                     }
-                    improver.improve(&schedule, &greedy)
-                }
-                "phased" => {
-                    let mut phased = PhasedImprover::default();
-                    if let Some(value) = max_add_time {
-                        phased.set_max_add_time(value);
-                    }
-                    if let Some(value) = max_sub_time {
-                        phased.set_max_sub_time(value);
-                    }
-                    if let Some(value) = max_shuffles {
-                        phased.set_max_shuffles(value);
+                    "anneal" => {
+                        let mut anneal = SimulatedAnnealingImprover::default();
+                        anneal.set_seed(seed);
+                        config.anneal.configure(&mut anneal);
+                        if let Some(value) = queue_order {
+                            anneal.set_queue_order(value);
+                        }
+                        if let Some(value) = anneal_initial_temp {
+                            anneal.set_initial_temp(value);
+                        }
+                        if let Some(value) = anneal_min_temp {
+                            anneal.set_min_temp(value);
+                        }
+                        if let Some(value) = anneal_cooling_rate {
+                            anneal.set_cooling_rate(value);
+                        }
+                        if let Some(value) = max_add_time {
+                            anneal.set_max_add_time(value);
+                        }
+                        if let Some(value) = max_sub_time {
+                            anneal.set_max_sub_time(value);
+                        }
+                        Box::new(anneal)
                     }
-                    if let Some(value) = max_streets_per_inter {
-                        phased.set_max_streets_per_inter(value);
+                    "wave" => {
+                        let mut wave = GreenWaveImprover::default();
+                        config.wave.configure(&mut wave);
+                        if let Some(value) = wave_max_corridors {
+                            wave.set_max_corridors(value);
+                        }
+                        if let Some(value) = wave_min_cars {
+                            wave.set_min_cars(value);
+                        }
+                        Box::new(wave)
                     }
-                    improver.improve(&schedule, &phased)
+                    _ => unreachable!(),
                 }
-                "shuffle" => {
-                    let mut shuffle = ShuffleImprover::default();
-                    if let Some(value) = min_wait_time {
-                        shuffle.set_min_wait_time(value);
-                    }
-                    if let Some(value) = max_streets_per_round {
-                        shuffle.set_max_streets(value);
+            };
+
+            let stages = improver_stages.as_ref().unwrap();
+            let improve = |idx: usize, schedule| {
+                let improver = build_incremental(idx);
+                let seed = run_seed(seed, idx);
+                match stages.as_slice() {
+                    [(name, None)] if !args.is_present("round-robin") => {
+                        improver.improve(
+                            schedule,
+                            build_improver(name, seed).as_ref(),
+                        )
                     }
-                    if let Some(value) = max_shuffles {
-                        shuffle.set_max_shuffles(value);
+                    _ => {
+                        let mut chain = ChainImprover::default();
+                        chain.set_round_robin(args.is_present("round-robin"));
+                        for (name, max_rounds) in stages.iter() {
+                            chain.add_stage(
+                                name,
+                                build_improver(name, seed),
+                                *max_rounds,
+                            );
+                        }
+                        improver.improve(schedule, &chain)
                     }
-                    improver.improve(&schedule, &shuffle)
                 }
-                _ => unreachable!(),
             };
 
+            // Improve the best schedules in parallel, each with its own seed
+            let mut improved: Vec<(Schedule, Score)> = schedules
+                .par_iter()
+                .enumerate()
+                .map(|(idx, schedule)| {
+                    let improved_schedule = improve(idx, schedule);
+                    let score = improved_schedule.score().unwrap_or(0);
+                    if schedules.len() > 1 {
+                        info!("Schedule {}: improved to {}", idx + 1, score);
+                    }
+                    (improved_schedule, score)
+                })
+                .collect();
+            // Best score, first schedule on ties
+            let best_idx = (0..improved.len())
+                .max_by_key(|&idx| (improved[idx].1, Reverse(idx)))
+                .unwrap();
+            let (improved_schedule, _) = improved.swap_remove(best_idx);
+
             let improved_stats = match improved_schedule.stats(build_image) {
                 Ok(score) => score,
                 Err(err) => {
@@ -352,16 +1148,46 @@ fn main() {
                 "\n\
                 Improved schedule\n\
                 -----------------\n\
-                {}",
+                {}\n\
+                Schedule hash   : {:016x}",
                 improved_stats,
+                improved_schedule.canonical_hash(),
             );
+            budget.end_stage("Improver");
             (improved_schedule, improved_stats)
         }
-        _ => (schedule, sched_stats),
+        _ => (schedules[0].clone(), sched_stats),
     };
 
     if let Some(filename) = args.value_of("output") {
-        write_output(&filename, &final_schedule);
+        write_output(filename, &final_schedule);
+    }
+
+    if let Some(dir) = args.value_of("save-if-better") {
+        save_if_better(
+            Path::new(dir),
+            args.value_of("input").unwrap(),
+            &final_schedule,
+            final_stats.score,
+        );
+    }
+
+    if let Some(filename) = args.value_of("car-trace") {
+        write_car_trace(filename, &final_schedule);
+    }
+
+    if args.is_present("queue-timeline") || args.is_present("queue-heatmap") {
+        let timeline = final_schedule.queue_timeline();
+        if let Some(filename) = args.value_of("queue-timeline") {
+            write_queue_timeline(filename, &timeline);
+        }
+        if let Some(filename) = args.value_of("queue-heatmap") {
+            info!("Writing queue heatmap to '{}'", filename);
+            timeline
+                .heatmap()
+                .save_with_format(filename, ImageFormat::Png)
+                .expect("Unable to write image file");
+        }
     }
 
     if let Some(filename) = args.value_of("png-image") {
@@ -371,10 +1197,215 @@ fn main() {
             .save_with_format(filename, ImageFormat::Png)
             .expect("Unable to write image file");
     }
+    budget.end_stage("Output");
+
+    if time_limit.is_some() {
+        println!(
+            "\n\
+            Time budget\n\
+            -----------\n\
+            {}",
+            budget,
+        );
+    }
 
     exit(0);
 }
 
+fn batch_command(args: &ArgMatches) {
+    let abort_flag = abort_on_ctrl_c();
+    let exe = std::env::current_exe().expect("Unable to find executable");
+    let pipeline: Vec<String> = args
+        .values_of("pipeline")
+        .unwrap()
+        .map(|value| value.to_string())
+        .collect();
+    let mut runner = BatchRunner::new(&exe, pipeline);
+    runner.set_input_dir(Path::new(args.value_of("input-dir").unwrap()));
+    runner.set_output_dir(Path::new(args.value_of("output-dir").unwrap()));
+    if args.is_present("jobs") {
+        let jobs =
+            value_t!(args.value_of("jobs"), usize).unwrap_or_else(|e| e.exit());
+        runner.set_jobs(jobs);
+    }
+
+    let names: Vec<&str> =
+        args.value_of("inputs").unwrap().split(',').collect();
+    let results = runner.run(abort_flag, &names);
+
+    let mut total_score = 0;
+    let mut failed = false;
+    for batch_result in results.iter() {
+        match &batch_result.result {
+            Ok((score, path)) => {
+                println!(
+                    "{}: {:>10}  {}",
+                    batch_result.name,
+                    score,
+                    path.display()
+                );
+                total_score += u64::from(*score);
+            }
+            Err(err) => {
+                println!("{}: {}", batch_result.name, err);
+                failed = true;
+            }
+        }
+    }
+    println!("Total score: {}", total_score);
+    if failed {
+        exit(1);
+    }
+}
+
+fn diff_command(args: &ArgMatches) {
+    let simulation = load_simulation(args.value_of("input").unwrap());
+    let mut old = Schedule::new(&simulation);
+    load_schedule(&mut old, args.value_of("old").unwrap());
+    let mut new = Schedule::new(&simulation);
+    load_schedule(&mut new, args.value_of("new").unwrap());
+
+    let mut diff = ScheduleDiff::new(&old, &new);
+    diff.compute_score_deltas(&old, &new);
+    let old_score = old.score().unwrap_or(0);
+    let new_score = new.score().unwrap_or(0);
+    let sum_deltas: i64 = diff
+        .intersections
+        .iter()
+        .filter_map(|inter_diff| inter_diff.score_delta)
+        .sum();
+    println!(
+        "{}\n\
+        Old score        : {}\n\
+        New score        : {}\n\
+        Score delta      : {:+}\n\
+        Sum of deltas    : {:+} (each intersection changed on its own)",
+        diff,
+        old_score,
+        new_score,
+        i64::from(new_score) - i64::from(old_score),
+        sum_deltas,
+    );
+}
+
+fn merge_command(args: &ArgMatches) {
+    let abort_flag = abort_on_ctrl_c();
+    let simulation = load_simulation(args.value_of("input").unwrap());
+    let schedules: Vec<Schedule> = args
+        .values_of("schedules")
+        .unwrap()
+        .map(|filename| {
+            let mut schedule = Schedule::new(&simulation);
+            load_schedule(&mut schedule, filename);
+            schedule
+        })
+        .collect();
+
+    match merge_schedules(abort_flag, &schedules) {
+        Ok((merged, score)) => {
+            println!("Merged schedule score: {}", score);
+            write_output(args.value_of("output").unwrap(), &merged);
+        }
+        Err(err) => {
+            println!("Failed to merge schedules: {}", err);
+            exit(4);
+        }
+    }
+}
+
+fn registry_command(args: &ArgMatches) {
+    let dir = Path::new(args.value_of("output-dir").unwrap());
+    let mut registry = Registry::scan(dir).unwrap_or_else(|err| {
+        println!("{}", err);
+        exit(2);
+    });
+    registry.verify_all(Path::new(args.value_of("input-dir").unwrap()));
+
+    let mismatches: Vec<_> = registry
+        .entries
+        .iter()
+        .filter(|entry| entry.is_mismatch())
+        .collect();
+    for entry in mismatches.iter() {
+        match &entry.status {
+            EntryStatus::Verified(score) => println!(
+                "Mismatch: '{}' scores {}",
+                entry.path.display(),
+                score
+            ),
+            EntryStatus::Failed(err) => {
+                println!("Failed: '{}': {}", entry.path.display(), err)
+            }
+            EntryStatus::Unverified => (),
+        }
+    }
+
+    let mut total_score = 0;
+    for (name, entry) in registry.best_entries() {
+        let score = entry.score().unwrap();
+        println!("{}: {:>10}  {}", name, score, entry.path.display());
+        total_score += u64::from(score);
+    }
+    println!("Total score: {}", total_score);
+    if !mismatches.is_empty() {
+        exit(1);
+    }
+}
+
+fn score_command(args: &ArgMatches) {
+    let simulation = load_simulation(args.value_of("input").unwrap());
+    let files: Vec<&str> = args.values_of("schedules").unwrap().collect();
+    let scores = score_files(&simulation, &files);
+
+    let stdout = std::io::stdout();
+    let mut writer = stdout.lock();
+    if args.is_present("json") {
+        scoring::write_json(&scores, &mut writer)
+    } else {
+        scoring::write_table(&scores, &mut writer)
+    }
+    .expect("Unable to write scores");
+
+    let failed = scores.iter().any(|file_score| {
+        matches!(file_score.result, ScoreResult::Failed { .. })
+    });
+    if failed {
+        exit(5);
+    }
+}
+
+fn validate_command(args: &ArgMatches) {
+    let simulation = load_simulation(args.value_of("input").unwrap());
+    let mut num_invalid = 0;
+    for filename in args.values_of("schedules").unwrap() {
+        let violations = validate_schedule(&simulation, &read_file(filename));
+        if violations.is_empty() {
+            println!("{}: OK", filename);
+            continue;
+        }
+        num_invalid += 1;
+        println!("{}: {} violations", filename, violations.len());
+        for violation in violations.iter() {
+            println!("  {}", violation);
+        }
+    }
+    if num_invalid > 0 {
+        exit(1);
+    }
+}
+
+// Flag set when Ctrl-C is pressed, so that long runs can stop gracefully
+fn abort_on_ctrl_c() -> Arc<AtomicBool> {
+    let abort_flag = Arc::new(AtomicBool::new(false));
+    let abort_clone = abort_flag.clone();
+    set_handler(move || {
+        eprintln!("Received termination request");
+        abort_clone.store(true, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+    abort_flag
+}
+
 fn load_simulation(filename: &str) -> Simulation {
     info!("Loading simulation from '{}'", filename);
     match read_file(filename).parse() {
@@ -405,6 +1436,67 @@ fn read_file(filename: &str) -> String {
 }
 
 fn write_output(filename: &str, sched: &Schedule) {
-    info!("Writing schedule to '{}'", filename);
+    info!(
+        "Writing schedule to '{}' (hash {:016x})",
+        filename,
+        sched.canonical_hash()
+    );
     write(filename, sched.to_string()).expect("Unable to write file");
 }
+
+// Write the schedule to the registry directory if it beats the best solution
+// of its input (named after the simulation file, e.g. "d" for "input/d.txt")
+fn save_if_better(dir: &Path, input: &str, sched: &Schedule, score: Score) {
+    let name = Path::new(input)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("schedule");
+    let saved = Registry::scan(dir)
+        .and_then(|mut registry| registry.save_if_better(name, sched, score));
+    match saved {
+        Ok(Some(path)) => {
+            info!("New best schedule written to '{}'", path.display())
+        }
+        Ok(None) => info!(
+            "Schedule not written, score {} does not beat the best in '{}'",
+            score,
+            dir.display()
+        ),
+        Err(err) => {
+            println!("Failed to save schedule: {}", err);
+            exit(2);
+        }
+    }
+}
+
+fn write_car_trace(filename: &str, sched: &Schedule) {
+    info!("Writing car trace to '{}'", filename);
+    let traces = sched.car_traces();
+    let mut writer =
+        BufWriter::new(File::create(filename).expect("Unable to write file"));
+    if filename.ends_with(".json") {
+        write_json(&traces, &mut writer)
+    } else {
+        write_csv(&traces, &mut writer)
+    }
+    .expect("Unable to write file");
+}
+
+fn write_triage_report(filename: &str, report: &TriageReport) {
+    info!("Writing triage report to '{}'", filename);
+    let writer =
+        BufWriter::new(File::create(filename).expect("Unable to write file"));
+    serde_json::to_writer_pretty(writer, report).expect("Unable to write file");
+}
+
+fn write_queue_timeline(filename: &str, timeline: &QueueTimeline) {
+    info!("Writing queue timeline to '{}'", filename);
+    let mut writer =
+        BufWriter::new(File::create(filename).expect("Unable to write file"));
+    if filename.ends_with(".json") {
+        timeline.write_json(&mut writer)
+    } else {
+        timeline.write_csv(&mut writer)
+    }
+    .expect("Unable to write file");
+}
@@ -0,0 +1,162 @@
+use super::*;
+use crate::diff::ScheduleDiff;
+use crate::sched::Schedule;
+use log::{debug, info};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::iter::once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Combine schedules of the same simulation intersection by intersection:
+// starting from the best schedule, greedily copy the intersections of the
+// other schedules that increase the total score, most promising first
+pub fn merge_schedules<'a>(
+    abort_flag: Arc<AtomicBool>,
+    schedules: &[Schedule<'a>],
+) -> Result<(Schedule<'a>, Score), String> {
+    let scores: Vec<Score> = schedules
+        .par_iter()
+        .map(|schedule| schedule.score())
+        .collect::<Result<_, _>>()?;
+    let (best_idx, &best_score) = scores
+        .iter()
+        .enumerate()
+        .max_by_key(|&(idx, &score)| (score, Reverse(idx)))
+        .ok_or_else(|| "No schedules to merge".to_string())?;
+    info!(
+        "Merging {} schedules, starting from schedule {} (score {})",
+        schedules.len(),
+        best_idx + 1,
+        best_score,
+    );
+
+    let mut merged = schedules[best_idx].clone();
+    let mut merged_score = best_score;
+    for round in 1.. {
+        // Intersections of other schedules that improve the merged schedule
+        // on their own
+        let mut candidates: Vec<(i64, usize, IntersectionId)> = Vec::new();
+        for (idx, schedule) in schedules.iter().enumerate() {
+            let mut diff = ScheduleDiff::new(&merged, schedule);
+            if diff.is_empty() {
+                continue;
+            }
+            diff.compute_score_deltas(&merged, schedule);
+            candidates.extend(diff.intersections.iter().filter_map(
+                |inter_diff| {
+                    inter_diff
+                        .score_delta
+                        .filter(|&delta| delta > 0)
+                        .map(|delta| (delta, idx, inter_diff.inter_id))
+                },
+            ));
+        }
+        candidates.sort_unstable_by_key(|&(delta, idx, inter_id)| {
+            (Reverse(delta), idx, inter_id)
+        });
+        info!(
+            "Merge round {}: {} candidate intersections",
+            round,
+            candidates.len(),
+        );
+        if candidates.is_empty() {
+            break;
+        }
+
+        // Candidates were scored against the same merged schedule, so check
+        // each one again as intersections get copied
+        let mut trace = merged.trace();
+        let mut copied = 0;
+        for (delta, idx, inter_id) in candidates {
+            if abort_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            let previous = merged.intersections.get(&inter_id).cloned();
+            merged.copy_intersection(&schedules[idx], inter_id);
+            let new_score = merged.rescore(&trace, once(inter_id));
+            if new_score > merged_score {
+                debug!(
+                    "Copied intersection {} from schedule {} (expected {:+}): \
+                    score {}",
+                    inter_id,
+                    idx + 1,
+                    delta,
+                    new_score,
+                );
+                merged_score = new_score;
+                trace = merged.trace();
+                copied += 1;
+            } else {
+                match previous {
+                    Some(inter) => {
+                        merged.intersections.insert(inter_id, inter);
+                    }
+                    None => merged.reset_intersection(inter_id),
+                }
+            }
+        }
+        info!(
+            "Merge round {}: {} intersections copied, score {}",
+            round, copied, merged_score,
+        );
+
+        if copied == 0 || abort_flag.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+
+    Ok((merged, merged_score))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{random_schedule, random_simulation};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn no_schedules() {
+        let abort_flag = Arc::new(AtomicBool::new(false));
+        assert!(merge_schedules(abort_flag, &[]).is_err());
+    }
+
+    #[test]
+    fn merge_random_schedules() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        let mut improved = 0;
+        for _ in 0..50 {
+            let simulation = random_simulation(&mut rng);
+            let schedules: Vec<Schedule> = (0..3)
+                .map(|_| random_schedule(&simulation, &mut rng))
+                .collect();
+            let best_score = schedules
+                .iter()
+                .map(|schedule| schedule.score().unwrap())
+                .max()
+                .unwrap();
+
+            let abort_flag = Arc::new(AtomicBool::new(false));
+            let (merged, score) =
+                merge_schedules(abort_flag, &schedules).unwrap();
+            assert_eq!(score, merged.score().unwrap());
+            assert!(score >= best_score);
+            if score > best_score {
+                improved += 1;
+            }
+
+            // Intersections only come from the merged schedules
+            for (inter_id, inter) in merged.intersections.iter() {
+                assert!(schedules.iter().any(|schedule| {
+                    schedule
+                        .intersections
+                        .get(inter_id)
+                        .is_some_and(|other| other.turns == inter.turns)
+                }));
+            }
+        }
+        // Merging must beat the best schedule some of the time
+        assert!(improved > 0);
+    }
+}
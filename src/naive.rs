@@ -33,6 +33,11 @@ impl Scheduler for NaiveScheduler {
 
         info!("Naive scheduler: {} ignored cars", ignored_cars);
 
+        // Add streets in order of ID so that the schedule is the same on
+        // every run
+        let mut crossed_streets: Vec<StreetId> =
+            crossed_streets.into_iter().collect();
+        crossed_streets.sort_unstable();
         for &street_id in crossed_streets.iter() {
             let inter_id = simulation.streets[street_id].end_intersection;
             schedule.add_street(inter_id, street_id, 1);
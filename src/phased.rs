@@ -1,13 +1,19 @@
 use super::*;
+use crate::engine::SimulationTrace;
+use crate::exact::{candidate_streets, crossed_streets, ExactOptimizer};
 use crate::improve::Improver;
-use crate::intersect::{reorder_intersection, reorder_intersections};
+use crate::intersect::{
+    reorder_intersection, reorder_intersections, QueueOrder,
+};
 use crate::sched::{Schedule, ScheduleStats};
+use crate::seed::{derive_rng, derive_seed, SeedSource};
 use crate::shuffle::bounded_factorial;
 use log::{debug, info};
-use rand::thread_rng;
+use rand::Rng;
 use rayon::prelude::*;
+use std::cmp::Reverse;
 use std::collections::HashSet;
-use std::iter::{once, repeat};
+use std::iter::{once, repeat_n};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -17,6 +23,9 @@ pub struct PhasedImprover {
     max_streets_per_inter: usize,
     max_shuffles_per_inter: usize,
     max_shuffles_per_thread: usize,
+    exact: ExactOptimizer,
+    queue_order: QueueOrder,
+    seeds: SeedSource,
 }
 
 impl Default for PhasedImprover {
@@ -30,6 +39,9 @@ impl Default for PhasedImprover {
             // shuffles
             max_shuffles_per_inter: 259,
             max_shuffles_per_thread: 26,
+            exact: ExactOptimizer::default(),
+            queue_order: QueueOrder::Random,
+            seeds: SeedSource::default(),
         }
     }
 }
@@ -50,6 +62,25 @@ impl PhasedImprover {
     pub fn set_max_streets_per_inter(&mut self, max_streets_per_inter: usize) {
         self.max_streets_per_inter = max_streets_per_inter;
     }
+
+    // Maximum green time tried by the exact optimisation of intersections
+    // (phase 8); 0 disables it
+    pub fn set_exact_max_time(&mut self, max_time: Time) {
+        self.exact.set_max_time(max_time);
+    }
+
+    // Intersections with more configurations are skipped by phase 8
+    pub fn set_exact_max_configs(&mut self, max_configs: usize) {
+        self.exact.set_max_configs(max_configs);
+    }
+
+    pub fn set_queue_order(&mut self, queue_order: QueueOrder) {
+        self.queue_order = queue_order;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seeds = SeedSource::new(seed);
+    }
 }
 
 impl Improver for PhasedImprover {
@@ -59,6 +90,10 @@ impl Improver for PhasedImprover {
         schedule: Schedule<'a>,
     ) -> Option<(Schedule<'a>, Score)> {
         let stats = schedule.stats(false).unwrap();
+        // Each phase derives its own seed from this one, and each task run in
+        // parallel its own generator (see derive_rng); tasks are searched with
+        // find_map_first, so the improvement found does not depend on timing
+        let seed = self.seeds.next_seed();
 
         // Sum up total wait time by intersection
         let mut inter_wait: HashMap<IntersectionId, Time> = HashMap::new();
@@ -78,7 +113,7 @@ impl Improver for PhasedImprover {
             inter_wait.into_iter().collect();
 
         // Sort intersections by total wait time
-        intersections.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        intersections.sort_unstable_by_key(|&(id, time)| (Reverse(time), id));
 
         // Phase 1
         let result1 = self.phase1(
@@ -86,6 +121,7 @@ impl Improver for PhasedImprover {
             schedule.clone(),
             &stats,
             &intersections,
+            derive_seed(seed, 1),
         );
         if result1.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result1;
@@ -103,11 +139,16 @@ impl Improver for PhasedImprover {
             .collect();
 
         // Sort streets by wait time
-        streets.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        streets.sort_unstable_by_key(|&(id, time)| (Reverse(time), id));
 
         // Phase 2
-        let result2 =
-            self.phase2(abort_flag.clone(), schedule.clone(), &stats, &streets);
+        let result2 = self.phase2(
+            abort_flag.clone(),
+            schedule.clone(),
+            &stats,
+            &streets,
+            derive_seed(seed, 2),
+        );
         if result2.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result2;
         }
@@ -118,6 +159,7 @@ impl Improver for PhasedImprover {
             schedule.clone(),
             stats.score,
             &intersections,
+            derive_seed(seed, 3),
         );
         if result3.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result3;
@@ -129,6 +171,7 @@ impl Improver for PhasedImprover {
             schedule.clone(),
             stats.score,
             &streets,
+            derive_seed(seed, 4),
         );
         if result4.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result4;
@@ -140,6 +183,7 @@ impl Improver for PhasedImprover {
             schedule.clone(),
             &stats,
             &intersections,
+            derive_seed(seed, 5),
         );
         if result5.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result5;
@@ -151,6 +195,7 @@ impl Improver for PhasedImprover {
             schedule.clone(),
             stats.score,
             &intersections,
+            derive_seed(seed, 6),
         );
         if result6.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result6;
@@ -162,11 +207,23 @@ impl Improver for PhasedImprover {
             schedule.clone(),
             &stats,
             &intersections,
+            derive_seed(seed, 7),
         );
         if result7.is_some() || abort_flag.load(Ordering::SeqCst) {
             return result7;
         }
 
+        // Phase 8
+        let result8 = self.phase8(
+            abort_flag.clone(),
+            schedule.clone(),
+            stats.score,
+            &intersections,
+        );
+        if result8.is_some() || abort_flag.load(Ordering::SeqCst) {
+            return result8;
+        }
+
         // No improvement found
         None
     }
@@ -179,6 +236,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_stats: &ScheduleStats,
         intersections: &[(IntersectionId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         info!(
             "Phased improver, phase 1: removing all streets that were never \
@@ -186,6 +244,7 @@ impl PhasedImprover {
             intersections.len(),
         );
 
+        let mut rng = derive_rng(seed, 0);
         let mut new_sched = schedule.clone();
         let mut modified_inter = HashSet::new();
         let mut removed = Vec::new();
@@ -212,8 +271,11 @@ impl PhasedImprover {
             return None;
         }
 
-        let new_score =
-            reorder_intersections(&mut new_sched, modified_inter.into_iter());
+        let new_score = reorder_intersections(
+            &mut new_sched,
+            modified_inter.into_iter(),
+            self.queue_order.policy(&mut rng),
+        );
         if new_score > curr_stats.score {
             info!(
                 "New best score {} after removing {} streets that were never \
@@ -243,7 +305,11 @@ impl PhasedImprover {
             let mut new_sched = schedule.clone();
             let inter = new_sched.intersections.get_mut(&inter_id).unwrap();
             let street_time = inter.remove_street(street_id).unwrap();
-            let new_score = reorder_intersection(&mut new_sched, inter_id);
+            let new_score = reorder_intersection(
+                &mut new_sched,
+                inter_id,
+                self.queue_order.policy(&mut rng),
+            );
             if new_score > curr_stats.score {
                 info!(
                     "New best score {} after removing street {} (time {}, \
@@ -272,6 +338,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_stats: &ScheduleStats,
         streets: &[(StreetId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         info!(
             "Phased improver, phase 2: adding streets with non-zero wait times \
@@ -279,6 +346,8 @@ impl PhasedImprover {
             streets.len(),
         );
 
+        let mut rng = derive_rng(seed, 0);
+
         // Loop through all streets in decreasing order of wait times, add it
         // with time 1 if it's not yet in the schedule, and reorder the
         // intersection; return as soon as an improvement is found
@@ -299,7 +368,11 @@ impl PhasedImprover {
             let mut new_schedule = schedule.clone();
             new_schedule.add_street(inter_id, street_id, 1);
 
-            let new_score = reorder_intersection(&mut new_schedule, inter_id);
+            let new_score = reorder_intersection(
+                &mut new_schedule,
+                inter_id,
+                self.queue_order.policy(&mut rng),
+            );
             if new_score > curr_stats.score {
                 info!(
                     "New best score {} after adding new street {} (previous \
@@ -326,6 +399,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_score: Score,
         intersections: &[(IntersectionId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         info!(
             "Phased improver, phase 3: reordering intersections with non-zero \
@@ -335,9 +409,8 @@ impl PhasedImprover {
 
         // Loop thought all intersections in decreasing order of total wait
         // times, reordering them; return as soon as an improvement is found
-        intersections
-            .par_iter()
-            .find_map_any(|&(inter_id, inter_wait)| {
+        intersections.par_iter().enumerate().find_map_first(
+            |(idx, &(inter_id, inter_wait))| {
                 if abort_flag.load(Ordering::SeqCst) {
                     return None;
                 }
@@ -346,16 +419,19 @@ impl PhasedImprover {
                     curr_score,
                     inter_id,
                     inter_wait,
+                    &mut derive_rng(seed, idx),
                 )
-            })
+            },
+        )
     }
 
-    fn reorder_intersection<'a>(
+    fn reorder_intersection<'a, R: Rng>(
         &self,
         mut schedule: Schedule<'a>,
         curr_score: Score,
         inter_id: IntersectionId,
         inter_wait: Time,
+        rng: &mut R,
     ) -> Option<(Schedule<'a>, Score)> {
         debug!(
             "Phase 3: reordering intersection {}, {} total wait, {} streets",
@@ -364,7 +440,11 @@ impl PhasedImprover {
             schedule.num_streets_in_intersection(inter_id),
         );
 
-        let new_score = reorder_intersection(&mut schedule, inter_id);
+        let new_score = reorder_intersection(
+            &mut schedule,
+            inter_id,
+            self.queue_order.policy(rng),
+        );
         if new_score > curr_score {
             info!(
                 "New best score {} after reordering intersection {} (\
@@ -387,6 +467,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_score: Score,
         streets: &[(StreetId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         if self.max_add_time == 0 {
             info!("Phased improver, phase 4: skipping since max_add_time is 0");
@@ -403,9 +484,8 @@ impl PhasedImprover {
         // Loop through all streets in decreasing order of wait times; add 1 to
         // the street's traffic light and reorder the intersection; return as
         // soon as an improvement is found
-        streets
-            .par_iter()
-            .find_map_any(|&(street_id, street_wait)| {
+        streets.par_iter().enumerate().find_map_first(
+            |(idx, &(street_id, street_wait))| {
                 if abort_flag.load(Ordering::SeqCst) {
                     return None;
                 }
@@ -414,16 +494,19 @@ impl PhasedImprover {
                     curr_score,
                     street_id,
                     street_wait,
+                    &mut derive_rng(seed, idx),
                 )
-            })
+            },
+        )
     }
 
-    fn add_street_time<'a>(
+    fn add_street_time<'a, R: Rng>(
         &self,
         mut schedule: Schedule<'a>,
         curr_score: Score,
         street_id: StreetId,
         street_wait: Time,
+        rng: &mut R,
     ) -> Option<(Schedule<'a>, Score)> {
         let inter_id = schedule.get_intersection_id(street_id).unwrap();
         let num_streets = schedule.num_streets_in_intersection(inter_id);
@@ -448,7 +531,11 @@ impl PhasedImprover {
         );
 
         schedule.add_street_time(street_id, 1);
-        let new_score = reorder_intersection(&mut schedule, inter_id);
+        let new_score = reorder_intersection(
+            &mut schedule,
+            inter_id,
+            self.queue_order.policy(rng),
+        );
         if new_score > curr_score {
             info!(
                 "New best score {} after adding 1 sec to street {} (previous \
@@ -468,6 +555,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_stats: &ScheduleStats,
         intersections: &[(IntersectionId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         self.add_or_sub_time_range(
             5,
@@ -476,6 +564,7 @@ impl PhasedImprover {
             schedule,
             curr_stats,
             intersections,
+            seed,
         )
     }
 
@@ -485,6 +574,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_score: Score,
         intersections: &[(IntersectionId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         info!(
             "Phased improver, phase 6: shuffling intersections with non-zero \
@@ -494,9 +584,10 @@ impl PhasedImprover {
 
         // Loop thought all intersections in decreasing order of total wait
         // times, shuffling them; return as soon as an improvement is found
-        intersections
-            .par_iter()
-            .flat_map_iter(|&(inter_id, inter_wait)| {
+        let trace = schedule.trace();
+        let tasks: Vec<(IntersectionId, Time, usize)> = intersections
+            .iter()
+            .flat_map(|&(inter_id, inter_wait)| {
                 let num_streets =
                     schedule.num_streets_in_intersection(inter_id);
                 let shuffles =
@@ -510,39 +601,47 @@ impl PhasedImprover {
                 let full = shuffles / self.max_shuffles_per_thread;
                 let remain = shuffles - full * self.max_shuffles_per_thread;
 
-                repeat((inter_id, inter_wait, self.max_shuffles_per_thread))
-                    .take(full)
-                    .chain(once((inter_id, inter_wait, remain)))
+                repeat_n(
+                    (inter_id, inter_wait, self.max_shuffles_per_thread),
+                    full,
+                )
+                .chain(once((inter_id, inter_wait, remain)))
             })
-            .find_map_any(|(inter_id, inter_wait, shuffles)| {
+            .collect();
+        tasks.par_iter().enumerate().find_map_first(
+            |(idx, &(inter_id, inter_wait, shuffles))| {
                 if abort_flag.load(Ordering::SeqCst) {
                     return None;
                 }
                 self.shuffle_intersection(
                     schedule.clone(),
+                    &trace,
                     curr_score,
                     inter_id,
                     inter_wait,
                     shuffles,
+                    &mut derive_rng(seed, idx),
                 )
-            })
+            },
+        )
     }
 
-    fn shuffle_intersection<'a>(
+    #[allow(clippy::too_many_arguments)]
+    fn shuffle_intersection<'a, R: Rng>(
         &self,
         mut schedule: Schedule<'a>,
+        trace: &SimulationTrace,
         curr_score: Score,
         inter_id: IntersectionId,
         inter_wait: Time,
         shuffles: usize,
+        rng: &mut R,
     ) -> Option<(Schedule<'a>, Score)> {
-        let mut rng = thread_rng();
-
         // Try to improve intersection by randomly shuffling streets without
         // changing their times, return as soon as improvement is found
         for _ in 1..=shuffles {
-            schedule.shuffle_intersection(inter_id, &mut rng);
-            let new_score = schedule.score().unwrap();
+            schedule.shuffle_intersection(inter_id, rng);
+            let new_score = schedule.rescore(trace, once(inter_id));
             if new_score > curr_score {
                 let num_streets =
                     schedule.num_streets_in_intersection(inter_id);
@@ -565,6 +664,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_stats: &ScheduleStats,
         intersections: &[(IntersectionId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         self.add_or_sub_time_range(
             7,
@@ -573,9 +673,11 @@ impl PhasedImprover {
             schedule,
             curr_stats,
             intersections,
+            seed,
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_or_sub_time_range<'a, I>(
         &self,
         phase: u32,
@@ -584,6 +686,7 @@ impl PhasedImprover {
         schedule: Schedule<'a>,
         curr_stats: &ScheduleStats,
         intersections: &[(IntersectionId, Time)],
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)>
     where
         I: IntoIterator<Item = Time>,
@@ -606,6 +709,7 @@ impl PhasedImprover {
                 intersections,
                 add_time,
                 sub_time,
+                derive_seed(seed, u64::from(time)),
             );
             if result.is_some() || abort_flag.load(Ordering::SeqCst) {
                 return result;
@@ -616,6 +720,7 @@ impl PhasedImprover {
         None
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_or_sub_loop<'a>(
         &self,
         phase: u32,
@@ -625,6 +730,7 @@ impl PhasedImprover {
         intersections: &[(IntersectionId, Time)],
         add_time: Time,
         sub_time: Time,
+        seed: u64,
     ) -> Option<(Schedule<'a>, Score)> {
         assert!(add_time > 0 || sub_time > 0);
         assert!(add_time <= self.max_add_time);
@@ -661,9 +767,8 @@ impl PhasedImprover {
         }
 
         // Loop thought all intersections in decreasing order of total wait
-        intersections
-            .par_iter()
-            .find_map_any(|&(inter_id, inter_wait)| {
+        intersections.par_iter().enumerate().find_map_first(
+            |(idx, &(inter_id, inter_wait))| {
                 if abort_flag.load(Ordering::SeqCst) {
                     return None;
                 }
@@ -675,11 +780,14 @@ impl PhasedImprover {
                     inter_wait,
                     add_time,
                     sub_time,
+                    &mut derive_rng(seed, idx),
                 )
-            })
+            },
+        )
     }
 
-    fn add_or_sub_inter_time<'a>(
+    #[allow(clippy::too_many_arguments)]
+    fn add_or_sub_inter_time<'a, R: Rng>(
         &self,
         phase: u32,
         schedule: Schedule<'a>,
@@ -688,6 +796,7 @@ impl PhasedImprover {
         inter_wait: Time,
         mut add_time: Time,
         sub_time: Time,
+        rng: &mut R,
     ) -> Option<(Schedule<'a>, Score)> {
         let streets = &schedule.intersections.get(&inter_id).unwrap().turns;
         let num_streets = streets.len();
@@ -731,7 +840,11 @@ impl PhasedImprover {
             } else {
                 new_schedule.sub_street_time(street_id, sub_time);
             }
-            let new_score = reorder_intersection(&mut new_schedule, inter_id);
+            let new_score = reorder_intersection(
+                &mut new_schedule,
+                inter_id,
+                self.queue_order.policy(rng),
+            );
             if new_score > best_score {
                 best_score = new_score;
                 best_sched = Some(new_schedule);
@@ -761,11 +874,67 @@ impl PhasedImprover {
             }
         }
 
-        if let Some(best_schedule) = best_sched {
-            Some((best_schedule, best_score))
-        } else {
-            // No improvement found
-            None
-        }
+        best_sched.map(|best_schedule| (best_schedule, best_score))
+    }
+
+    fn phase8<'a>(
+        &self,
+        abort_flag: Arc<AtomicBool>,
+        schedule: Schedule<'a>,
+        curr_score: Score,
+        intersections: &[(IntersectionId, Time)],
+    ) -> Option<(Schedule<'a>, Score)> {
+        let crossed = crossed_streets(schedule.simulation);
+        let selected: Vec<(IntersectionId, Time)> = intersections
+            .iter()
+            .copied()
+            .filter(|&(inter_id, _)| {
+                let num_streets =
+                    candidate_streets(&schedule, &crossed, inter_id).len();
+                self.exact.num_configs(num_streets).is_some_and(|n| n > 0)
+            })
+            .collect();
+        info!(
+            "Phased improver, phase 8: exact optimisation of intersections \
+            with non-zero wait times, {} intersections selected",
+            selected.len(),
+        );
+
+        // Loop thought the selected intersections in decreasing order of
+        // total wait times; return as soon as an improvement is found
+        let trace = schedule.trace();
+        selected
+            .par_iter()
+            .find_map_first(|&(inter_id, inter_wait)| {
+                if abort_flag.load(Ordering::SeqCst) {
+                    return None;
+                }
+                let (new_schedule, new_score) = self.exact.optimize(
+                    &abort_flag,
+                    &schedule,
+                    &trace,
+                    &crossed,
+                    inter_id,
+                )?;
+                if new_score > curr_score {
+                    info!(
+                        "New best score {} after exact optimisation of \
+                         intersection {} (previous total wait time {}, {} \
+                         streets)",
+                        new_score,
+                        inter_id,
+                        inter_wait,
+                        new_schedule.num_streets_in_intersection(inter_id),
+                    );
+                    Some((new_schedule, new_score))
+                } else {
+                    debug!(
+                        "Phase 8: intersection {} ({} total wait) has no \
+                         better configuration",
+                        inter_id, inter_wait,
+                    );
+                    None
+                }
+            })
     }
 }
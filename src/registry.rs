@@ -0,0 +1,290 @@
+use super::*;
+use crate::sched::Schedule;
+use crate::scoring::{score_files, ScoreResult};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+// Solutions kept in an output directory, named "<input>_<score>.txt"
+pub struct Registry {
+    dir: PathBuf,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    // Name of the input, e.g. "b" for "input/b.txt"
+    pub name: String,
+    // Score encoded in the file name
+    pub claimed_score: Score,
+    pub path: PathBuf,
+    pub status: EntryStatus,
+}
+
+pub enum EntryStatus {
+    Unverified,
+    Verified(Score),
+    Failed(String),
+}
+
+impl Entry {
+    // Actual score if verified, score in the file name otherwise
+    pub fn score(&self) -> Option<Score> {
+        match self.status {
+            EntryStatus::Unverified => Some(self.claimed_score),
+            EntryStatus::Verified(score) => Some(score),
+            EntryStatus::Failed(_) => None,
+        }
+    }
+
+    pub fn is_mismatch(&self) -> bool {
+        match self.status {
+            EntryStatus::Unverified => false,
+            EntryStatus::Verified(score) => score != self.claimed_score,
+            EntryStatus::Failed(_) => true,
+        }
+    }
+}
+
+impl Registry {
+    pub fn scan(dir: &Path) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        let dir_entries = match read_dir(dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(_) if !dir.exists() => {
+                return Ok(Self {
+                    dir: dir.to_path_buf(),
+                    entries,
+                })
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Failed to read '{}': {}",
+                    dir.display(),
+                    err
+                ))
+            }
+        };
+
+        for dir_entry in dir_entries {
+            let path = dir_entry
+                .map_err(|err| {
+                    format!("Failed to read '{}': {}", dir.display(), err)
+                })?
+                .path();
+            if path.extension().is_none_or(|ext| ext != "txt") {
+                continue;
+            }
+            let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem,
+                None => continue,
+            };
+            if let Some((name, claimed_score)) = parse_stem(stem) {
+                entries.push(Entry {
+                    name,
+                    claimed_score,
+                    path,
+                    status: EntryStatus::Unverified,
+                });
+            }
+        }
+        entries.sort_unstable_by(|entry1, entry2| {
+            (&entry1.name, entry2.claimed_score)
+                .cmp(&(&entry2.name, entry1.claimed_score))
+        });
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            entries,
+        })
+    }
+
+    // Names of the inputs with solutions, in order
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect();
+        names.dedup();
+        names
+    }
+
+    // Score the solutions of an input with its simulation
+    pub fn verify(&mut self, name: &str, simulation: &Simulation) {
+        let entries: Vec<&mut Entry> = self
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.name == name)
+            .collect();
+        let paths: Vec<String> = entries
+            .iter()
+            .map(|entry| entry.path.to_string_lossy().into_owned())
+            .collect();
+        let files: Vec<&str> = paths.iter().map(|path| path.as_str()).collect();
+        let scores = score_files(simulation, &files);
+        for (entry, file_score) in entries.into_iter().zip(scores) {
+            entry.status = match file_score.result {
+                ScoreResult::Scored { score, .. } => {
+                    EntryStatus::Verified(score)
+                }
+                ScoreResult::Failed { error } => EntryStatus::Failed(error),
+            };
+        }
+    }
+
+    // Score all solutions, reading simulations from "<input dir>/<name>.txt"
+    pub fn verify_all(&mut self, input_dir: &Path) {
+        let names: Vec<String> =
+            self.names().into_iter().map(String::from).collect();
+        for name in names.iter() {
+            let input = input_dir.join(format!("{}.txt", name));
+            let simulation = read_to_string(&input)
+                .map_err(|err| {
+                    format!("Failed to read '{}': {}", input.display(), err)
+                })
+                .and_then(|data| data.parse::<Simulation>());
+            match simulation {
+                Ok(simulation) => self.verify(name, &simulation),
+                Err(err) => {
+                    for entry in self.entries.iter_mut() {
+                        if entry.name == *name {
+                            entry.status = EntryStatus::Failed(err.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Best solution of an input
+    pub fn best(&self, name: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.name == name && entry.score().is_some())
+            .max_by_key(|entry| (entry.score(), entry.claimed_score))
+    }
+
+    // Best solution of every input
+    pub fn best_entries(&self) -> BTreeMap<&str, &Entry> {
+        self.names()
+            .into_iter()
+            .filter_map(|name| self.best(name).map(|entry| (name, entry)))
+            .collect()
+    }
+
+    // Write a schedule as "<dir>/<name>_<score>.txt", only if it beats the
+    // best solution of its input (verified against the simulation); returns
+    // the path written, if any
+    pub fn save_if_better(
+        &mut self,
+        name: &str,
+        schedule: &Schedule,
+        score: Score,
+    ) -> Result<Option<PathBuf>, String> {
+        self.verify(name, schedule.simulation);
+        if let Some(best_score) = self.best(name).and_then(Entry::score) {
+            if score <= best_score {
+                return Ok(None);
+            }
+        }
+
+        create_dir_all(&self.dir).map_err(|err| {
+            format!("Failed to create '{}': {}", self.dir.display(), err)
+        })?;
+        let path = self.dir.join(format!("{}_{}.txt", name, score));
+        write(&path, schedule.to_string()).map_err(|err| {
+            format!("Failed to write '{}': {}", path.display(), err)
+        })?;
+        self.entries.push(Entry {
+            name: name.to_string(),
+            claimed_score: score,
+            path: path.clone(),
+            status: EntryStatus::Verified(score),
+        });
+        Ok(Some(path))
+    }
+}
+
+// Split a file stem such as "b_4570436" into input name and score
+fn parse_stem(stem: &str) -> Option<(String, Score)> {
+    let (name, score) = stem.split_once('_')?;
+    if name.is_empty() {
+        return None;
+    }
+    score.parse().ok().map(|score| (name.to_string(), score))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{example, temp_dir, EXAMPLE_SCHEDULE};
+
+    #[test]
+    fn parse_stems() {
+        assert_eq!(parse_stem("b_4570436"), Some(("b".to_string(), 4570436)));
+        assert_eq!(parse_stem("b"), None);
+        assert_eq!(parse_stem("_4570436"), None);
+        assert_eq!(parse_stem("b_running_1234"), None);
+        assert_eq!(parse_stem("b_-1"), None);
+    }
+
+    #[test]
+    fn missing_dir() {
+        let dir = temp_dir("registry_missing_dir").join("missing");
+        let registry = Registry::scan(&dir).unwrap();
+        assert!(registry.entries.is_empty());
+        assert!(registry.best_entries().is_empty());
+    }
+
+    #[test]
+    fn scan_and_save() {
+        let dir = temp_dir("registry_scan_and_save");
+        for file in ["a_1002.txt", "a_5000.txt", "b_10.txt"] {
+            write(dir.join(file), EXAMPLE_SCHEDULE).unwrap();
+        }
+        for file in ["a_running_1234.txt", "a_1002.log", "notes.txt"] {
+            write(dir.join(file), "").unwrap();
+        }
+
+        let mut registry = Registry::scan(&dir).unwrap();
+        let entries: Vec<(&str, Score)> = registry
+            .entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.claimed_score))
+            .collect();
+        assert_eq!(entries, vec![("a", 5000), ("a", 1002), ("b", 10)]);
+        assert_eq!(registry.names(), vec!["a", "b"]);
+        assert_eq!(registry.best("a").unwrap().score(), Some(5000));
+        assert!(registry.best("c").is_none());
+
+        // The file named after a score of 5000 only scores 1002
+        let simulation = example();
+        registry.verify("a", &simulation);
+        let mismatches: Vec<Score> = registry
+            .entries
+            .iter()
+            .filter(|entry| entry.is_mismatch())
+            .map(|entry| entry.claimed_score)
+            .collect();
+        assert_eq!(mismatches, vec![5000]);
+        assert_eq!(registry.best("a").unwrap().score(), Some(1002));
+        let best: Vec<(&str, Option<Score>)> = registry
+            .best_entries()
+            .into_iter()
+            .map(|(name, entry)| (name, entry.score()))
+            .collect();
+        assert_eq!(best, vec![("a", Some(1002)), ("b", Some(10))]);
+
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        assert_eq!(registry.save_if_better("a", &schedule, 1002), Ok(None));
+        let path = dir.join("c_1002.txt");
+        assert_eq!(
+            registry.save_if_better("c", &schedule, 1002),
+            Ok(Some(path.clone())),
+        );
+        assert_eq!(read_to_string(&path).unwrap(), schedule.to_string());
+        assert_eq!(registry.save_if_better("c", &schedule, 1002), Ok(None));
+        assert_eq!(registry.best("c").unwrap().score(), Some(1002));
+    }
+}
@@ -0,0 +1,435 @@
+use super::*;
+use crate::engine::{intersection_lights, CarTimes, Light, SimulationTrace};
+use crate::sched::Schedule;
+use std::cmp::{max, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashSet};
+use std::ops::Bound::{Excluded, Unbounded};
+
+type QueueEntry = (Time, CarId, usize);
+
+// Re-simulates a schedule that differs from the one recorded in a baseline
+// trace only in the given intersections. Only the queues of streets whose
+// lights changed are re-simulated (from the first second their lights differ)
+// and only cars whose departure times change are propagated downstream.
+pub fn rescore<I>(
+    schedule: &Schedule,
+    baseline: &SimulationTrace,
+    inter_ids: I,
+) -> Score
+where
+    I: IntoIterator<Item = IntersectionId>,
+{
+    let mut rescorer = Rescorer::new(schedule, baseline);
+    for inter_id in inter_ids {
+        rescorer.change_intersection(inter_id);
+    }
+    rescorer.score()
+}
+
+struct Rescorer<'s, 'a> {
+    schedule: &'s Schedule<'a>,
+    simulation: &'a Simulation,
+    baseline: &'s SimulationTrace,
+    // New lights of the streets of changed intersections
+    lights: HashMap<StreetId, Light>,
+    // Copies of the baseline car times and queues that have been modified
+    car_times: HashMap<CarId, CarTimes>,
+    queues: HashMap<StreetId, BTreeSet<QueueEntry>>,
+    // Cars whose arrival at a queue has changed but whose departure from the
+    // queue has not been recomputed yet
+    dirty: HashSet<(CarId, usize)>,
+    // Queues to re-simulate, from a given time; queues of streets whose
+    // lights changed must be re-simulated all the way to the end
+    events: BinaryHeap<Reverse<(Time, StreetId, bool)>>,
+    // Number of queue entries that can still be re-simulated; once changes
+    // spread to a large part of the city a full simulation is cheaper
+    budget: usize,
+    score: Score,
+}
+
+impl<'s, 'a> Rescorer<'s, 'a> {
+    fn new(schedule: &'s Schedule<'a>, baseline: &'s SimulationTrace) -> Self {
+        Self {
+            schedule,
+            simulation: schedule.simulation,
+            baseline,
+            lights: HashMap::new(),
+            car_times: HashMap::new(),
+            queues: HashMap::new(),
+            dirty: HashSet::new(),
+            events: BinaryHeap::new(),
+            budget: baseline
+                .queues
+                .iter()
+                .map(|queue| queue.len())
+                .sum::<usize>()
+                / 4,
+            score: baseline.score,
+        }
+    }
+
+    fn change_intersection(&mut self, inter_id: IntersectionId) {
+        // Streets removed from the intersection are always red
+        if let Some(inter) = self.baseline.intersections.get(&inter_id) {
+            for &(street_id, _) in inter.turns.iter() {
+                self.lights.insert(street_id, Light::default());
+            }
+        }
+        if let Some(inter) = self.schedule.intersections.get(&inter_id) {
+            self.lights.extend(intersection_lights(inter));
+        }
+
+        let streets: Vec<StreetId> = self
+            .lights
+            .keys()
+            .copied()
+            .filter(|&street_id| {
+                self.simulation.streets[street_id].end_intersection == inter_id
+            })
+            .collect();
+        for street_id in streets.into_iter() {
+            let old_light = &self.baseline.lights[street_id];
+            let new_light = self.light(street_id);
+            if let Some(time) = self.first_divergence(old_light, new_light) {
+                self.events.push(Reverse((time, street_id, true)));
+            }
+        }
+    }
+
+    // First second at which two lights differ, if any
+    fn first_divergence(&self, light1: &Light, light2: &Light) -> Option<Time> {
+        if light1 == light2 {
+            return None;
+        }
+        let mut time = 0;
+        while time <= self.simulation.duration {
+            if light1.is_green(time) != light2.is_green(time) {
+                return Some(time);
+            }
+            time = match (light1.next_change(time), light2.next_change(time)) {
+                (Some(t1), Some(t2)) => t1.min(t2),
+                (Some(t), None) | (None, Some(t)) => t,
+                (None, None) => return None,
+            };
+        }
+        None
+    }
+
+    fn light(&self, street_id: StreetId) -> &Light {
+        self.lights
+            .get(&street_id)
+            .unwrap_or(&self.baseline.lights[street_id])
+    }
+
+    // Score of the schedule, falling back to a full simulation when the
+    // re-simulation budget runs out
+    fn score(self) -> Score {
+        let schedule = self.schedule;
+        self.run().unwrap_or_else(|| schedule.score().unwrap())
+    }
+
+    fn run(mut self) -> Option<Score> {
+        while let Some(Reverse((time, street_id, full))) = self.events.pop() {
+            self.resimulate_queue(street_id, time, full);
+            if self.budget == 0 {
+                // Changes have spread to most of the city
+                return None;
+            }
+        }
+        Some(self.score)
+    }
+
+    fn times(&self, car_id: CarId) -> &[(Option<Time>, Option<Time>)] {
+        self.car_times
+            .get(&car_id)
+            .unwrap_or(&self.baseline.car_times[car_id])
+    }
+
+    fn times_mut(&mut self, car_id: CarId) -> &mut CarTimes {
+        let baseline = self.baseline;
+        self.car_times
+            .entry(car_id)
+            .or_insert_with(|| baseline.car_times[car_id].clone())
+    }
+
+    fn queue_mut(&mut self, street_id: StreetId) -> &mut BTreeSet<QueueEntry> {
+        let baseline = self.baseline;
+        self.queues.entry(street_id).or_insert_with(|| {
+            baseline.queues[street_id].iter().copied().collect()
+        })
+    }
+
+    fn departure(&self, entry: &QueueEntry) -> Option<Time> {
+        let &(_, car_id, position) = entry;
+        self.times(car_id)[position].1
+    }
+
+    // Re-simulate a queue from the first car that was still waiting at, or
+    // joined the queue after, the given time
+    fn resimulate_queue(
+        &mut self,
+        street_id: StreetId,
+        time: Time,
+        full: bool,
+    ) {
+        let duration = self.simulation.duration;
+        let first = self.first_affected(street_id, time);
+        let mut next_entry = match first {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        // Departure of the car ahead of the first affected car
+        let mut prev_departure = self
+            .queue_mut(street_id)
+            .range(..next_entry)
+            .next_back()
+            .copied()
+            .map(|entry| self.departure(&entry).unwrap_or(duration + 1));
+
+        loop {
+            if self.budget == 0 {
+                return;
+            }
+            self.budget -= 1;
+
+            let (arrival, car_id, position) = next_entry;
+            let was_dirty = self.dirty.remove(&(car_id, position));
+            let old_departure = self.times(car_id)[position].1;
+            let ready_time =
+                prev_departure.map_or(arrival, |t| max(arrival, t + 1));
+            let new_departure = self
+                .light(street_id)
+                .next_green(ready_time)
+                .filter(|&t| t <= duration);
+
+            if !full
+                && !was_dirty
+                && arrival >= time
+                && new_departure == old_departure
+            {
+                // The rest of the queue is unaffected
+                break;
+            }
+
+            self.set_departure(car_id, position, new_departure);
+            prev_departure = Some(new_departure.unwrap_or(duration + 1));
+
+            next_entry = match self
+                .queue_mut(street_id)
+                .range((Excluded(next_entry), Unbounded))
+                .next()
+            {
+                Some(&entry) => entry,
+                None => break,
+            };
+        }
+    }
+
+    fn first_affected(
+        &mut self,
+        street_id: StreetId,
+        time: Time,
+    ) -> Option<QueueEntry> {
+        self.queue_mut(street_id);
+        let queue = self.queues.get(&street_id).unwrap();
+
+        // Cars that joined earlier but were still waiting at the given time
+        queue
+            .range(..(time, 0, 0))
+            .rev()
+            .take_while(|entry| {
+                self.departure(entry).is_none_or(|dep| dep >= time)
+            })
+            .last()
+            .or_else(|| queue.range((time, 0, 0)..).next())
+            .copied()
+    }
+
+    fn set_departure(
+        &mut self,
+        car_id: CarId,
+        position: usize,
+        departure: Option<Time>,
+    ) {
+        let duration = self.simulation.duration;
+        let path = &self.simulation.car_paths[car_id];
+        let mut position = position;
+        let mut departure = departure;
+        loop {
+            if self.times(car_id)[position].1 == departure {
+                return;
+            }
+            self.times_mut(car_id)[position].1 = departure;
+
+            // Update the time the car reaches the end of the next street
+            position += 1;
+            let next_street_id = path[position];
+            let arrival = departure
+                .map(|t| {
+                    t + self.simulation.streets[next_street_id].travel_time
+                })
+                .filter(|&t| t <= duration);
+            let old_arrival = self.times(car_id)[position].0;
+            if old_arrival == arrival {
+                return;
+            }
+            self.times_mut(car_id)[position].0 = arrival;
+
+            if position + 1 == path.len() {
+                // Car reaches the end of its journey at a different time
+                let bonus = self.simulation.bonus;
+                if let Some(t) = old_arrival {
+                    self.score -= bonus + (duration - t);
+                }
+                if let Some(t) = arrival {
+                    self.score += bonus + (duration - t);
+                }
+                return;
+            }
+
+            if let Some(t) = old_arrival {
+                self.queue_mut(next_street_id)
+                    .remove(&(t, car_id, position));
+                self.events.push(Reverse((t, next_street_id, false)));
+            }
+            if let Some(t) = arrival {
+                self.queue_mut(next_street_id).insert((t, car_id, position));
+                self.dirty.insert((car_id, position));
+                self.events.push(Reverse((t, next_street_id, false)));
+
+                let old_departure = self.times(car_id)[position].1;
+                if old_departure.is_none_or(|dep| dep >= t) {
+                    // The car's old departure will be replaced once its queue
+                    // is re-simulated from the time it now reaches it
+                    return;
+                }
+            }
+
+            // The car no longer leaves the next queue when it used to: remove
+            // it from all queues ahead (all of which happen after this point
+            // in time) until its departure is recomputed
+            departure = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{random_schedule, random_simulation};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::iter::once;
+
+    // Random change to a random intersection of a schedule: shuffle its
+    // turns, add or subtract time, add or remove a street; returns the ID of
+    // the intersection, or None if the change would empty the schedule
+    fn random_change<R: Rng>(
+        schedule: &mut Schedule,
+        rng: &mut R,
+    ) -> Option<IntersectionId> {
+        let simulation = schedule.simulation;
+        let street_id = rng.gen_range(0..simulation.streets.len());
+        let inter_id = simulation.streets[street_id].end_intersection;
+        let inter = match schedule.intersections.get_mut(&inter_id) {
+            Some(inter) => inter,
+            None => {
+                schedule.add_street(inter_id, street_id, rng.gen_range(1..4));
+                return Some(inter_id);
+            }
+        };
+        let scheduled = inter.get_street_time(street_id).is_some();
+        match rng.gen_range(0..4) {
+            0 => inter.turns.shuffle(rng),
+            1 if scheduled => inter.add_street_time(street_id, 1),
+            2 if scheduled && inter.turns.len() > 1 => {
+                inter.sub_street_time(street_id, rng.gen_range(1..3));
+            }
+            _ if !scheduled => inter.add_street(street_id, 1),
+            _ if inter.turns.len() > 1 => {
+                inter.remove_street(street_id);
+            }
+            _ if schedule.intersections.len() > 1 => {
+                schedule.reset_intersection(inter_id);
+            }
+            _ => return None,
+        }
+        Some(inter_id)
+    }
+
+    #[test]
+    fn rescore_matches_score() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..100 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            let trace = schedule.trace();
+            for _ in 0..10 {
+                let mut new_schedule = schedule.clone();
+                if let Some(inter_id) =
+                    random_change(&mut new_schedule, &mut rng)
+                {
+                    assert_eq!(
+                        new_schedule.rescore(&trace, once(inter_id)),
+                        new_schedule.score().unwrap(),
+                        "intersection {} of schedule:\n{}",
+                        inter_id,
+                        new_schedule,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rescore_several_changes() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..100 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            let trace = schedule.trace();
+            let mut new_schedule = schedule.clone();
+            let inter_ids: Vec<IntersectionId> = (0..rng.gen_range(1..5))
+                .filter_map(|_| random_change(&mut new_schedule, &mut rng))
+                .collect();
+            assert_eq!(
+                new_schedule.rescore(&trace, inter_ids.iter().copied()),
+                new_schedule.score().unwrap(),
+                "intersections {:?} of schedule:\n{}",
+                inter_ids,
+                new_schedule,
+            );
+        }
+    }
+
+    #[test]
+    fn rescore_without_budget() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        let mut fallbacks = 0;
+        for _ in 0..100 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            let trace = schedule.trace();
+            let mut new_schedule = schedule.clone();
+            let inter_id = match random_change(&mut new_schedule, &mut rng) {
+                Some(inter_id) => inter_id,
+                None => continue,
+            };
+
+            let rescorer = |budget| {
+                let mut rescorer = Rescorer::new(&new_schedule, &trace);
+                rescorer.budget = budget;
+                rescorer.change_intersection(inter_id);
+                rescorer
+            };
+            if rescorer(1).run().is_none() {
+                fallbacks += 1;
+            }
+            assert_eq!(rescorer(1).score(), new_schedule.score().unwrap());
+        }
+        // The budget must actually run out in some of the cases
+        assert!(fallbacks > 0);
+    }
+}
@@ -1,15 +1,20 @@
 use super::*;
+use crate::cartrace::{trace_cars, CarTrace};
+use crate::diff::ScheduleDiff;
+use crate::engine::{simulate, simulate_with_trace, SimulationTrace};
+use crate::rescore::rescore;
+use crate::timeline::QueueTimeline;
 use image::{ImageBuffer, Rgb, RgbImage};
 use rand::{seq::SliceRandom, Rng};
 use std::collections::{HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 
-const GREEN: Rgb<u8> = Rgb([0, 128, 0]);
-const LIGHT_GRAY: Rgb<u8> = Rgb([211, 211, 211]);
-const LIGHT_GREEN: Rgb<u8> = Rgb([144, 238, 144]);
-const RED: Rgb<u8> = Rgb([255, 0, 0]);
-const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+pub const GREEN: Rgb<u8> = Rgb([0, 128, 0]);
+pub const LIGHT_GRAY: Rgb<u8> = Rgb([211, 211, 211]);
+pub const LIGHT_GREEN: Rgb<u8> = Rgb([144, 238, 144]);
+pub const RED: Rgb<u8> = Rgb([255, 0, 0]);
+pub const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
 
 pub trait Scheduler {
     fn schedule<'a>(&self, simulation: &'a Simulation) -> Schedule<'a>;
@@ -40,7 +45,7 @@ pub struct ScheduleStats {
 }
 
 impl ScheduleStats {
-    fn new(schedule: &Schedule) -> Self {
+    pub fn new(schedule: &Schedule) -> Self {
         let num_intersections = schedule.intersections.len();
         let num_streets = schedule
             .intersections
@@ -150,6 +155,34 @@ impl Intersection {
             .find(|&(id, _)| *id == street_id)
             .map(|&(_, time)| time)
     }
+
+    pub fn cycle(&self) -> Time {
+        self.cycle
+    }
+
+    // Start the cycle with the given turn, keeping the order of the turns;
+    // this shifts the time at which each street goes green (the intersection's
+    // offset) by the total time of the turns moved to the end; rotations
+    // wrap around the number of turns
+    pub fn rotate(&mut self, first: usize) {
+        if self.turns.is_empty() {
+            return;
+        }
+        let first = first % self.turns.len();
+        self.turns.rotate_left(first);
+    }
+
+    // Time within the cycle at which each turn starts
+    pub fn offsets(&self) -> Vec<Time> {
+        self.turns
+            .iter()
+            .scan(0, |acc_time, &(_, time)| {
+                let offset = *acc_time;
+                *acc_time += time;
+                Some(offset)
+            })
+            .collect()
+    }
 }
 
 impl<'a> Schedule<'a> {
@@ -195,12 +228,12 @@ impl<'a> Schedule<'a> {
             .get_street_time(street_id)
     }
 
-    pub fn shuffle_intersection<R: ?Sized>(
+    pub fn shuffle_intersection<R>(
         &mut self,
         inter_id: IntersectionId,
         rng: &mut R,
     ) where
-        R: Rng,
+        R: Rng + ?Sized,
     {
         self.intersections
             .entry(inter_id)
@@ -246,11 +279,83 @@ impl<'a> Schedule<'a> {
         self.intersections.remove(&inter_id);
     }
 
+    // Replace the lights of an intersection with those of another schedule
+    pub fn copy_intersection(
+        &mut self,
+        other: &Schedule,
+        inter_id: IntersectionId,
+    ) {
+        match other.intersections.get(&inter_id) {
+            Some(inter) => {
+                self.intersections.insert(inter_id, inter.clone());
+            }
+            None => self.reset_intersection(inter_id),
+        }
+    }
+
+    // Intersections in order of ID, the order in which they are written
+    pub fn sorted_intersections(&self) -> Vec<(IntersectionId, &Intersection)> {
+        let mut intersections: Vec<(IntersectionId, &Intersection)> = self
+            .intersections
+            .iter()
+            .map(|(&inter_id, inter)| (inter_id, inter))
+            .collect();
+        intersections.sort_unstable_by_key(|&(inter_id, _)| inter_id);
+        intersections
+    }
+
+    // Hash of the schedule as written to a file (FNV-1a), equal for equal
+    // schedules across runs and builds
+    pub fn canonical_hash(&self) -> u64 {
+        self.to_string()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
     pub fn score(&self) -> Result<Score, String> {
         self.stats(false).map(|stats| stats.score)
     }
 
     pub fn stats(&self, build_image: bool) -> Result<ScheduleStats, String> {
+        Ok(simulate(self, build_image))
+    }
+
+    pub fn trace(&self) -> SimulationTrace {
+        simulate_with_trace(self).1
+    }
+
+    // Journey of every car through the city
+    pub fn car_traces(&self) -> Vec<CarTrace<'a>> {
+        trace_cars(self)
+    }
+
+    // Length of the queue of every street at every second
+    pub fn queue_timeline(&self) -> QueueTimeline<'a> {
+        QueueTimeline::new(self)
+    }
+
+    // Changes from this schedule to another one of the same simulation
+    pub fn diff(&self, other: &Schedule<'a>) -> ScheduleDiff<'a> {
+        ScheduleDiff::new(self, other)
+    }
+
+    // Score of this schedule, given the trace of a simulation of a schedule
+    // that differs from this one only in the given intersections
+    pub fn rescore<I>(&self, baseline: &SimulationTrace, inter_ids: I) -> Score
+    where
+        I: IntoIterator<Item = IntersectionId>,
+    {
+        rescore(self, baseline, inter_ids)
+    }
+
+    // Reference implementation of the simulation, stepping through every
+    // second; kept to check the event-driven engine against
+    pub fn reference_stats(
+        &self,
+        build_image: bool,
+    ) -> Result<ScheduleStats, String> {
         let mut stats = ScheduleStats::new(self);
 
         let mut inter_start_col: HashMap<IntersectionId, u32> = HashMap::new();
@@ -317,7 +422,7 @@ impl<'a> Schedule<'a> {
                 if let Some(inter) = self.intersections.get(&inter_id) {
                     intersection = inter;
                 } else {
-                    assert_eq!(is_green, false);
+                    assert!(!is_green);
                     continue;
                 }
 
@@ -405,6 +510,13 @@ impl<'a> Schedule<'a> {
     pub fn load_from_str(&mut self, s: &str) -> Result<(), String> {
         let mut lines = s.lines().zip(1..);
         let mut intersections = HashMap::new();
+        let street_index: HashMap<&str, StreetId> = self
+            .simulation
+            .streets
+            .iter()
+            .enumerate()
+            .map(|(street_id, street)| (street.name.as_str(), street_id))
+            .collect();
 
         let num_intersections: usize = lines
             .next()
@@ -449,13 +561,9 @@ impl<'a> Schedule<'a> {
                 let street_name = fields.next().ok_or_else(|| {
                     format!("Line {}: missing street name", line_num)
                 })?;
-                let street_id = self
-                    .simulation
-                    .streets
-                    .iter()
-                    .enumerate()
-                    .find(|(_, street)| street.name == street_name)
-                    .map(|(street_id, _)| street_id)
+                let street_id = street_index
+                    .get(street_name)
+                    .copied()
                     .ok_or_else(|| {
                         format!(
                             "Line {}: unknown street: {}",
@@ -551,7 +659,7 @@ impl Car {
 impl Display for Schedule<'_> {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         writeln!(f, "{}", self.intersections.len())?;
-        for (inter_id, light) in &self.intersections {
+        for (inter_id, light) in self.sorted_intersections() {
             writeln!(f, "{}\n{}", inter_id, light.turns.len())?;
             for &(street_id, time) in &light.turns {
                 let street_name =
@@ -587,3 +695,147 @@ impl Display for ScheduleStats {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        example, random_schedule, random_simulation, EXAMPLE_SCHEDULE,
+    };
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Check the stats of the engine against the per-second simulation
+    fn assert_same_stats(schedule: &Schedule, build_image: bool) {
+        let stats = schedule.stats(build_image).unwrap();
+        let reference = schedule.reference_stats(build_image).unwrap();
+        assert_eq!(stats.num_intersections, reference.num_intersections);
+        assert_eq!(stats.num_streets, reference.num_streets);
+        assert_eq!(stats.num_arrived_cars, reference.num_arrived_cars);
+        assert_eq!(stats.earliest_arrival, reference.earliest_arrival);
+        assert_eq!(stats.latest_arrival, reference.latest_arrival);
+        assert_eq!(stats.crossed_streets, reference.crossed_streets);
+        assert_eq!(stats.total_wait_time, reference.total_wait_time);
+        assert_eq!(stats.score, reference.score, "schedule:\n{}", schedule);
+        // Images are only drawn on request; the reference lays intersections
+        // out in the order it first meets them, so only images of a single
+        // intersection have the same layout
+        if build_image && schedule.intersections.len() == 1 {
+            assert!(stats.image == reference.image, "image differs");
+        }
+    }
+
+    #[test]
+    fn example_stats() {
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        assert_eq!(schedule.stats(false).unwrap().score, 1002);
+        assert_same_stats(&schedule, false);
+    }
+
+    #[test]
+    fn stats_match_reference() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..200 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            assert_same_stats(&schedule, false);
+            assert_same_stats(&schedule, true);
+
+            // Each intersection on its own
+            for (&inter_id, inter) in schedule.intersections.iter() {
+                let mut single = Schedule::new(&simulation);
+                single.intersections.insert(inter_id, inter.clone());
+                assert_same_stats(&single, true);
+            }
+        }
+    }
+
+    #[test]
+    fn written_in_id_order() {
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        assert_eq!(
+            schedule.to_string(),
+            "3\n0\n1\nrue-de-londres 2\n1\n2\nrue-d-athenes 2\n\
+             rue-d-amsterdam 1\n2\n1\nrue-de-moscou 1\n",
+        );
+
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..50 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            let s = schedule.to_string();
+            let mut lines = s.lines().skip(1);
+            let mut inter_ids = Vec::new();
+            while let Some(line) = lines.next() {
+                inter_ids.push(line.parse::<IntersectionId>().unwrap());
+                let num_streets: usize = lines.next().unwrap().parse().unwrap();
+                lines.nth(num_streets - 1);
+            }
+            let mut sorted_ids = inter_ids.clone();
+            sorted_ids.sort_unstable();
+            sorted_ids.dedup();
+            assert_eq!(inter_ids, sorted_ids);
+            assert_eq!(inter_ids.len(), schedule.intersections.len());
+        }
+    }
+
+    #[test]
+    fn canonical_hashes() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..50 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            let hash = schedule.canonical_hash();
+
+            // Same schedule, intersections inserted in reverse order
+            let mut reversed = Schedule::new(&simulation);
+            for (inter_id, inter) in
+                schedule.sorted_intersections().into_iter().rev()
+            {
+                for &(street_id, time) in inter.turns.iter() {
+                    reversed.add_street(inter_id, street_id, time);
+                }
+            }
+            assert_eq!(reversed.canonical_hash(), hash);
+
+            // Another order of the turns of an intersection
+            let (&inter_id, inter) =
+                schedule.intersections.iter().next().unwrap();
+            if inter.turns.len() > 1 {
+                let mut reordered = schedule.clone();
+                let inter = reordered.intersections.get_mut(&inter_id).unwrap();
+                inter.turns.swap(0, 1);
+                assert_ne!(reordered.canonical_hash(), hash);
+            }
+
+            // Another green time
+            let street_id = inter.turns[0].0;
+            let mut longer = schedule.clone();
+            longer.add_street_time(street_id, 1);
+            assert_ne!(longer.canonical_hash(), hash);
+        }
+    }
+
+    #[test]
+    fn rotations() {
+        let mut inter = Intersection::new(0, 1);
+        inter.add_street(1, 2);
+        inter.add_street(2, 3);
+        inter.rotate(1);
+        assert_eq!(inter.turns, [(1, 2), (2, 3), (0, 1)]);
+        assert_eq!(inter.offsets(), [0, 2, 5]);
+        assert_eq!(inter.cycle(), 6);
+        inter.rotate(5);
+        assert_eq!(inter.turns, [(0, 1), (1, 2), (2, 3)]);
+
+        for street_id in 0..3 {
+            inter.remove_street(street_id);
+        }
+        inter.rotate(1);
+        assert!(inter.turns.is_empty());
+    }
+}
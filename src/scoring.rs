@@ -0,0 +1,211 @@
+use super::*;
+use crate::sched::Schedule;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs::read_to_string;
+use std::io::{self, Write};
+
+// Score of a schedule file, or the reason it could not be scored
+#[derive(Serialize)]
+pub struct FileScore {
+    pub file: String,
+    #[serde(flatten)]
+    pub result: ScoreResult,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ScoreResult {
+    Scored {
+        score: Score,
+        arrived_cars: usize,
+        // Percentage of the simulation's maximum theoretical score
+        max_score_percent: f64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+// Score schedule files of a simulation in parallel
+pub fn score_files(simulation: &Simulation, files: &[&str]) -> Vec<FileScore> {
+    let max_score = f64::from(simulation.max_theoretical_score());
+    files
+        .par_iter()
+        .map(|&file| {
+            let result = match score_file(simulation, file) {
+                Ok((score, arrived_cars)) => ScoreResult::Scored {
+                    score,
+                    arrived_cars,
+                    max_score_percent: if max_score > 0.0 {
+                        100.0 * f64::from(score) / max_score
+                    } else {
+                        0.0
+                    },
+                },
+                Err(error) => ScoreResult::Failed { error },
+            };
+            FileScore {
+                file: file.to_string(),
+                result,
+            }
+        })
+        .collect()
+}
+
+fn score_file(
+    simulation: &Simulation,
+    file: &str,
+) -> Result<(Score, usize), String> {
+    let data = read_to_string(file)
+        .map_err(|err| format!("Failed to read '{}': {}", file, err))?;
+    let mut schedule = Schedule::new(simulation);
+    schedule.load_from_str(&data)?;
+    let stats = schedule.stats(false)?;
+    Ok((stats.score, stats.num_arrived_cars))
+}
+
+pub fn write_table<W: Write>(
+    scores: &[FileScore],
+    writer: &mut W,
+) -> io::Result<()> {
+    let width = scores
+        .iter()
+        .map(|file_score| file_score.file.len())
+        .chain(std::iter::once("File".len()))
+        .max()
+        .unwrap();
+    writeln!(
+        writer,
+        "{:width$}  {:>10}  {:>12}  {:>7}",
+        "File",
+        "Score",
+        "Arrived cars",
+        "Max %",
+        width = width,
+    )?;
+    for file_score in scores.iter() {
+        match &file_score.result {
+            ScoreResult::Scored {
+                score,
+                arrived_cars,
+                max_score_percent,
+            } => writeln!(
+                writer,
+                "{:width$}  {:>10}  {:>12}  {:>6.2}%",
+                file_score.file,
+                score,
+                arrived_cars,
+                max_score_percent,
+                width = width,
+            )?,
+            ScoreResult::Failed { error } => writeln!(
+                writer,
+                "{:width$}  {}",
+                file_score.file,
+                error,
+                width = width,
+            )?,
+        }
+    }
+    Ok(())
+}
+
+pub fn write_json<W: Write>(
+    scores: &[FileScore],
+    writer: &mut W,
+) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *writer, scores)?;
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{example, temp_dir, EXAMPLE_SCHEDULE};
+    use std::fs::write;
+
+    #[test]
+    fn score_example_files() {
+        let dir = temp_dir("score_example_files");
+        let good = dir.join("good.txt");
+        let bad = dir.join("bad.txt");
+        let missing = dir.join("missing.txt");
+        write(&good, EXAMPLE_SCHEDULE).unwrap();
+        write(&bad, "1\n0\n1\nrue-de-paris 1\n").unwrap();
+        let files = [
+            good.to_str().unwrap(),
+            bad.to_str().unwrap(),
+            missing.to_str().unwrap(),
+        ];
+
+        let simulation = example();
+        let scores = score_files(&simulation, &files);
+        assert_eq!(scores.len(), 3);
+        for (file_score, &file) in scores.iter().zip(files.iter()) {
+            assert_eq!(file_score.file, file);
+        }
+        match scores[0].result {
+            ScoreResult::Scored {
+                score,
+                arrived_cars,
+                max_score_percent,
+            } => {
+                assert_eq!(score, 1002);
+                assert_eq!(arrived_cars, 1);
+                assert!(
+                    (max_score_percent - 100.0 * 1002.0 / 2002.0).abs() < 1e-9
+                );
+            }
+            ScoreResult::Failed { ref error } => panic!("{}", error),
+        }
+        assert!(matches!(scores[1].result, ScoreResult::Failed { .. }));
+        match &scores[2].result {
+            ScoreResult::Failed { error } => {
+                assert!(error
+                    .starts_with(&format!("Failed to read '{}'", files[2])))
+            }
+            ScoreResult::Scored { .. } => panic!("Scored a missing file"),
+        }
+
+        let mut table = Vec::new();
+        write_table(&scores, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        let width = files.iter().map(|file| file.len()).max().unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            format!(
+                "{:width$}  {:>10}  {:>12}  {:>7}",
+                "File",
+                "Score",
+                "Arrived cars",
+                "Max %",
+                width = width,
+            )
+        );
+        assert_eq!(
+            lines[1],
+            format!(
+                "{:width$}  {:>10}  {:>12}  {:>7}",
+                files[0],
+                1002,
+                1,
+                "50.05%",
+                width = width,
+            )
+        );
+
+        let mut json = Vec::new();
+        write_json(&scores, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[0]["file"], files[0]);
+        assert_eq!(json[0]["score"], 1002);
+        assert_eq!(json[0]["arrived_cars"], 1);
+        assert!(json[0]["error"].is_null());
+        assert_eq!(json[1]["file"], files[1]);
+        assert!(json[1]["error"].is_string());
+        assert!(json[1]["score"].is_null());
+    }
+}
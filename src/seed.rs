@@ -0,0 +1,159 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Mutex;
+
+// Source of random seeds for a scheduler or improver: every call to schedule
+// or improve draws the seed of its random number generators from it, so that
+// a whole run can be reproduced from a single seed
+pub struct SeedSource {
+    rng: Mutex<StdRng>,
+}
+
+impl Default for SeedSource {
+    fn default() -> Self {
+        Self::new(rand::random())
+    }
+}
+
+impl SeedSource {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub fn next_seed(&self) -> u64 {
+        self.rng.lock().unwrap().gen()
+    }
+}
+
+// Random number generator of the index-th task derived from a seed; tasks run
+// in parallel get their own generator, which does not depend on the thread
+// that happens to run them
+pub fn derive_rng(seed: u64, index: usize) -> StdRng {
+    StdRng::seed_from_u64(derive_seed(seed, index as u64))
+}
+
+pub fn derive_seed(seed: u64, index: u64) -> u64 {
+    mix(seed ^ mix(index))
+}
+
+// SplitMix64 finalizer
+pub fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapt::AdaptiveScheduler;
+    use crate::anneal::SimulatedAnnealingImprover;
+    use crate::greedy::GreedyImprover;
+    use crate::improve::Improver;
+    use crate::intersect::QueueOrder;
+    use crate::phased::PhasedImprover;
+    use crate::sched::Scheduler;
+    use crate::shuffle::ShuffleImprover;
+    use crate::testing::{random_schedule, random_simulation};
+    use crate::traffic::TrafficScheduler;
+    use crate::{Score, Simulation};
+    use std::fmt::Debug;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    // Check that runs on random simulations give the same results with the
+    // same seed, and that some of them differ with another seed
+    fn check_seeds<T, F>(run: F)
+    where
+        T: Debug + PartialEq,
+        F: Fn(&Simulation, u64) -> T,
+    {
+        let mut rng = StdRng::seed_from_u64(2021);
+        let mut diverged = 0;
+        for _ in 0..20 {
+            let simulation = random_simulation(&mut rng);
+            let seed = rng.gen();
+            let result = run(&simulation, seed);
+            assert_eq!(run(&simulation, seed), result);
+            if run(&simulation, seed.wrapping_add(1)) != result {
+                diverged += 1;
+            }
+        }
+        assert!(diverged > 0);
+    }
+
+    fn schedule_result<S: Scheduler>(
+        scheduler: S,
+        simulation: &Simulation,
+    ) -> (u64, Score) {
+        let schedule = scheduler.schedule(simulation);
+        (schedule.canonical_hash(), schedule.score().unwrap())
+    }
+
+    // Result of an improver on a random schedule of the simulation, where
+    // every intersection is scheduled
+    fn improve_result<I: Improver>(
+        improver: I,
+        simulation: &Simulation,
+    ) -> Option<(u64, Score)> {
+        let mut rng = StdRng::seed_from_u64(simulation.car_paths.len() as u64);
+        let mut schedule = random_schedule(simulation, &mut rng);
+        for (street_id, street) in simulation.streets.iter().enumerate() {
+            let inter_id = street.end_intersection;
+            if !schedule.intersections.contains_key(&inter_id) {
+                schedule.add_street(inter_id, street_id, 1);
+            }
+        }
+        let abort_flag = Arc::new(AtomicBool::new(false));
+        improver
+            .improve(abort_flag, schedule)
+            .map(|(schedule, score)| {
+                assert_eq!(schedule.score(), Ok(score));
+                (schedule.canonical_hash(), score)
+            })
+    }
+
+    #[test]
+    fn seeded_schedulers() {
+        check_seeds(|simulation, seed| {
+            let mut scheduler = AdaptiveScheduler::default();
+            scheduler.set_queue_order(QueueOrder::Random);
+            scheduler.set_seed(seed);
+            schedule_result(scheduler, simulation)
+        });
+        check_seeds(|simulation, seed| {
+            let mut scheduler = TrafficScheduler::default();
+            scheduler.set_seed(seed);
+            schedule_result(scheduler, simulation)
+        });
+    }
+
+    #[test]
+    fn seeded_improvers() {
+        check_seeds(|simulation, seed| {
+            let mut improver = SimulatedAnnealingImprover::default();
+            improver.set_seed(seed);
+            improve_result(improver, simulation)
+        });
+        check_seeds(|simulation, seed| {
+            let mut improver = PhasedImprover::default();
+            improver.set_seed(seed);
+            improve_result(improver, simulation)
+        });
+        check_seeds(|simulation, seed| {
+            let mut improver = ShuffleImprover::default();
+            improver.set_min_wait_time(1);
+            improver.set_seed(seed);
+            improve_result(improver, simulation)
+        });
+        check_seeds(|simulation, seed| {
+            let mut improver = GreedyImprover::default();
+            improver.set_min_wait_time(1);
+            improver.set_seed(seed);
+            improve_result(improver, simulation)
+        });
+    }
+}
@@ -1,8 +1,12 @@
 use super::*;
 use crate::improve::Improver;
 use crate::sched::Schedule;
+use crate::seed::SeedSource;
 use log::info;
-use rand::thread_rng;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cmp::Reverse;
+use std::iter::once;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -10,6 +14,7 @@ pub struct ShuffleImprover {
     min_wait_time: Time,
     max_streets: usize,
     max_shuffles: usize,
+    seeds: SeedSource,
 }
 
 impl Default for ShuffleImprover {
@@ -18,6 +23,7 @@ impl Default for ShuffleImprover {
             min_wait_time: 10,
             max_streets: 10,
             max_shuffles: 10,
+            seeds: SeedSource::default(),
         }
     }
 }
@@ -34,6 +40,10 @@ impl ShuffleImprover {
     pub fn set_max_shuffles(&mut self, max_shuffles: usize) {
         self.max_shuffles = max_shuffles;
     }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seeds = SeedSource::new(seed);
+    }
 }
 
 impl Improver for ShuffleImprover {
@@ -48,16 +58,17 @@ impl Improver for ShuffleImprover {
             self.min_wait_time, self.max_streets, self.max_shuffles,
         );
 
-        let mut rng = thread_rng();
+        let mut rng = StdRng::seed_from_u64(self.seeds.next_seed());
 
         // Sort streets by total wait time
         let stats = schedule.stats(false).unwrap();
+        let trace = schedule.trace();
         let mut wait_times: Vec<(StreetId, Time)> = stats
             .total_wait_time
             .into_iter()
             .filter(|&(_, time)| time >= self.min_wait_time)
             .collect();
-        wait_times.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        wait_times.sort_unstable_by_key(|&(id, time)| (Reverse(time), id));
 
         let mut best_count = 0;
         let mut best_score = stats.score;
@@ -86,7 +97,8 @@ impl Improver for ShuffleImprover {
                         break 'outer;
                     }
 
-                    let new_score = new_schedule.score().unwrap();
+                    let new_score =
+                        new_schedule.rescore(&trace, once(inter_id));
                     if new_score <= best_score {
                         continue;
                     }
@@ -1,7 +1,10 @@
+use super::*;
 use std::collections::HashMap;
 use std::hash::Hash;
-use std::ops::Add;
+use std::ops::{Add, RangeInclusive};
 
+// Sums of all subsets of a list of values, each with the values of the first
+// subset found; kept as a reference for SubsetSums
 pub struct AllSums<T> {
     sums: HashMap<T, Vec<T>>,
 }
@@ -46,3 +49,197 @@ impl<T: Eq + Copy + Hash + Add<Output = T>> AllSums<T> {
         range.find_map(|s| self.sums.get(&s)).cloned()
     }
 }
+
+// Sums up to a maximum of all subsets of a list of times, as a bitset; each
+// sum records the time added when it was first reached, so that the values of
+// a sum can be rebuilt by walking back through these times. Gives the same
+// answers as AllSums for sums up to the maximum
+pub struct SubsetSums {
+    max_sum: Time,
+    // Largest sum so far (bounded by the maximum), up to which the bitset
+    // grows as times are added
+    top_sum: Time,
+    // Bit s is set if some subset adds up to s
+    reachable: Vec<u64>,
+    // Time added when each sum was first reached (0 for unreached sums, and
+    // for the empty subset)
+    last_time: Vec<Time>,
+}
+
+impl SubsetSums {
+    pub fn new(max_sum: Time) -> Self {
+        Self {
+            max_sum,
+            top_sum: 0,
+            reachable: vec![1],
+            last_time: vec![0],
+        }
+    }
+
+    pub fn add(&mut self, time: Time) {
+        if time == 0 || time > self.max_sum {
+            return;
+        }
+        self.top_sum = (self.top_sum + time).min(self.max_sum);
+        let len = self.top_sum as usize + 1;
+        self.reachable.resize(len.div_ceil(64), 0);
+        self.last_time.resize(len, 0);
+
+        // reachable |= reachable << time, from the highest word down so that
+        // the words shifted in are still the ones from before this time
+        let word_shift = (time / 64) as usize;
+        let bit_shift = time % 64;
+        for idx in (word_shift..self.reachable.len()).rev() {
+            let src = idx - word_shift;
+            let mut shifted = self.reachable[src] << bit_shift;
+            if bit_shift > 0 && src > 0 {
+                shifted |= self.reachable[src - 1] >> (64 - bit_shift);
+            }
+
+            let mut new_bits = shifted & !self.reachable[idx];
+            self.reachable[idx] |= new_bits;
+            while new_bits != 0 {
+                let sum = idx * 64 + new_bits.trailing_zeros() as usize;
+                if sum <= self.top_sum as usize {
+                    self.last_time[sum] = time;
+                }
+                new_bits &= new_bits - 1;
+            }
+        }
+
+        // Clear sums above the maximum in the last word
+        if !len.is_multiple_of(64) {
+            *self.reachable.last_mut().unwrap() &= (1 << (len % 64)) - 1;
+        }
+    }
+
+    pub fn contains(&self, sum: Time) -> bool {
+        sum <= self.top_sum
+            && self.reachable[sum as usize / 64] & (1 << (sum % 64)) != 0
+    }
+
+    pub fn contains_any(&self, range: RangeInclusive<Time>) -> bool {
+        self.min_sum(range).is_some()
+    }
+
+    // Times of the smallest sum in a range, in the order they were added
+    pub fn get_min_sum_values(
+        &self,
+        range: RangeInclusive<Time>,
+    ) -> Option<Vec<Time>> {
+        let mut sum = self.min_sum(range)?;
+        let mut times = Vec::new();
+        while sum > 0 {
+            let time = self.last_time[sum as usize];
+            times.push(time);
+            sum -= time;
+        }
+        times.reverse();
+        Some(times)
+    }
+
+    fn min_sum(&self, range: RangeInclusive<Time>) -> Option<Time> {
+        let (start, end) = (*range.start(), (*range.end()).min(self.top_sum));
+        if start > end {
+            return None;
+        }
+
+        let (start_word, end_word) = (start as usize / 64, end as usize / 64);
+        for idx in start_word..=end_word {
+            let mut bits = self.reachable[idx];
+            if idx == start_word {
+                bits &= !0 << (start % 64);
+            }
+            if idx == end_word && end % 64 != 63 {
+                bits &= (1 << (end % 64 + 1)) - 1;
+            }
+            if bits != 0 {
+                return Some((idx * 64) as Time + bits.trailing_zeros());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Check every range of sums up to the maximum against AllSums
+    fn assert_same_sums(
+        all_sums: &AllSums<Time>,
+        subset_sums: &SubsetSums,
+        max_sum: Time,
+    ) {
+        for start in 0..=max_sum {
+            for end in start..=max_sum {
+                assert_eq!(
+                    subset_sums.contains_any(start..=end),
+                    all_sums.contains_any(start..=end),
+                    "contains_any({}..={})",
+                    start,
+                    end,
+                );
+                assert_eq!(
+                    subset_sums.get_min_sum_values(start..=end),
+                    all_sums.get_min_sum_values(start..=end),
+                    "get_min_sum_values({}..={})",
+                    start,
+                    end,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let subset_sums = SubsetSums::new(10);
+        assert_same_sums(&AllSums::default(), &subset_sums, 10);
+        assert_eq!(subset_sums.get_min_sum_values(0..=10), Some(vec![]));
+        assert!(!subset_sums.contains_any(1..=10));
+    }
+
+    #[test]
+    fn small_values() {
+        let mut all_sums = AllSums::default();
+        let mut subset_sums = SubsetSums::new(12);
+        for time in [3, 5, 3, 1, 7] {
+            all_sums.add(time);
+            subset_sums.add(time);
+            assert_same_sums(&all_sums, &subset_sums, 12);
+        }
+        assert_eq!(subset_sums.get_min_sum_values(9..=12), Some(vec![3, 5, 1]));
+    }
+
+    #[test]
+    fn values_above_maximum() {
+        let mut all_sums = AllSums::default();
+        let mut subset_sums = SubsetSums::new(5);
+        for time in [4, 9, 2, 6, 1] {
+            all_sums.add(time);
+            subset_sums.add(time);
+            assert_same_sums(&all_sums, &subset_sums, 5);
+        }
+        assert!(!subset_sums.contains(9));
+        assert!(!subset_sums.contains_any(6..=100));
+    }
+
+    #[test]
+    fn random_values() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for max_sum in [1, 63, 64, 65, 127, 128, 200] {
+            for _ in 0..10 {
+                let mut all_sums = AllSums::default();
+                let mut subset_sums = SubsetSums::new(max_sum);
+                for _ in 0..rng.gen_range(1..12) {
+                    let time = rng.gen_range(1..=max_sum.min(80));
+                    all_sums.add(time);
+                    subset_sums.add(time);
+                }
+                assert_same_sums(&all_sums, &subset_sums, max_sum);
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use super::*;
+use crate::sched::Schedule;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::PathBuf;
+
+// Simulations and schedules shared by the unit tests
+
+// Example of the problem statement
+pub const EXAMPLE: &str = "\
+6 4 5 2 1000
+2 0 rue-de-londres 1
+0 1 rue-d-amsterdam 1
+3 1 rue-d-athenes 1
+2 3 rue-de-rome 2
+1 2 rue-de-moscou 3
+4 rue-de-londres rue-d-amsterdam rue-de-moscou rue-de-rome
+3 rue-d-athenes rue-de-moscou rue-de-londres
+";
+
+// Schedule of the example in the problem statement
+pub const EXAMPLE_SCHEDULE: &str = "\
+3
+1
+2
+rue-d-athenes 2
+rue-d-amsterdam 1
+0
+1
+rue-de-londres 2
+2
+1
+rue-de-moscou 1
+";
+
+pub fn example() -> Simulation {
+    EXAMPLE.parse().unwrap()
+}
+
+// Simulation built from streets given as (start, end, travel time), named
+// after their IDs, and paths of street IDs
+pub fn simulation(
+    duration: Time,
+    num_intersections: u32,
+    streets: &[(IntersectionId, IntersectionId, Time)],
+    car_paths: &[&[StreetId]],
+    bonus: Score,
+) -> Simulation {
+    Simulation {
+        duration,
+        num_intersections,
+        streets: streets
+            .iter()
+            .enumerate()
+            .map(|(street_id, &(start, end, travel_time))| Street {
+                name: format!("s{}", street_id),
+                start_insersection: start,
+                end_intersection: end,
+                travel_time,
+            })
+            .collect(),
+        car_paths: car_paths.iter().map(|path| path.to_vec()).collect(),
+        bonus,
+    }
+}
+
+// Random city: a ring of intersections with a few more streets, and cars
+// driving random walks through it
+pub fn random_simulation<R: Rng>(rng: &mut R) -> Simulation {
+    let num_intersections = rng.gen_range(2..8);
+    let mut streets: Vec<(IntersectionId, IntersectionId, Time)> = (0
+        ..num_intersections)
+        .map(|start| {
+            let end = (start + 1) % num_intersections;
+            (start, end, rng.gen_range(1..4))
+        })
+        .collect();
+    for _ in 0..rng.gen_range(0..2 * num_intersections) {
+        let start = rng.gen_range(0..num_intersections);
+        let end =
+            (start + rng.gen_range(1..num_intersections)) % num_intersections;
+        streets.push((start, end, rng.gen_range(1..4)));
+    }
+
+    let car_paths: Vec<Vec<StreetId>> = (0..rng.gen_range(1..30))
+        .map(|_| {
+            let mut path = vec![rng.gen_range(0..streets.len())];
+            for _ in 0..rng.gen_range(1..6) {
+                let end = streets[*path.last().unwrap()].1;
+                let next: Vec<StreetId> = (0..streets.len())
+                    .filter(|&street_id| streets[street_id].0 == end)
+                    .collect();
+                path.push(*next.choose(rng).unwrap());
+            }
+            path
+        })
+        .collect();
+    let car_paths: Vec<&[StreetId]> =
+        car_paths.iter().map(|path| path.as_slice()).collect();
+
+    simulation(
+        rng.gen_range(5..40),
+        num_intersections,
+        &streets,
+        &car_paths,
+        rng.gen_range(0..100),
+    )
+}
+
+// Random schedule: each intersection gets a random subset of its incoming
+// streets, in random order, with green times from 1 to 3 seconds (at least one
+// street is scheduled)
+pub fn random_schedule<'a, R: Rng>(
+    simulation: &'a Simulation,
+    rng: &mut R,
+) -> Schedule<'a> {
+    let mut street_ids: Vec<StreetId> = (0..simulation.streets.len()).collect();
+    street_ids.shuffle(rng);
+    let mut schedule = Schedule::new(simulation);
+    for (idx, street_id) in street_ids.into_iter().enumerate() {
+        if idx == 0 || rng.gen_bool(0.75) {
+            let inter_id = simulation.streets[street_id].end_intersection;
+            schedule.add_street(inter_id, street_id, rng.gen_range(1..4));
+        }
+    }
+    schedule
+}
+
+// Empty directory for the files of a test, named after the test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "hashcode2021-{}-{}",
+        std::process::id(),
+        name
+    ));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,350 @@
+use super::*;
+use crate::engine::{run, FixedLights, Observer};
+use crate::sched::{Schedule, WHITE};
+use image::{ImageBuffer, Rgb, RgbImage};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::iter::once;
+
+// Length of the queue of every street at every second of a simulation (as
+// seen at the end of each second, once cars have crossed intersections)
+pub struct QueueTimeline<'a> {
+    simulation: &'a Simulation,
+    // For each street: the times at which the length of its queue changed,
+    // and its new length (the queue is empty until the first change)
+    changes: Vec<Vec<(Time, usize)>>,
+}
+
+// Period of time during which the queue of a street has the same length
+#[derive(Serialize)]
+pub struct QueueRun {
+    pub start: Time,
+    pub end: Time,
+    pub queue_len: usize,
+}
+
+#[derive(Serialize)]
+struct StreetTimeline<'a> {
+    street_id: StreetId,
+    street_name: &'a str,
+    runs: Vec<QueueRun>,
+}
+
+impl<'a> QueueTimeline<'a> {
+    pub fn new(schedule: &Schedule<'a>) -> Self {
+        let simulation = schedule.simulation;
+        let mut policy = FixedLights::new(schedule);
+        let mut timeline = Self {
+            simulation,
+            changes: vec![Vec::new(); simulation.streets.len()],
+        };
+        run(simulation, &mut policy, &mut timeline);
+        timeline
+    }
+
+    pub fn queue_len(&self, street_id: StreetId, time: Time) -> usize {
+        let changes = &self.changes[street_id];
+        match changes.binary_search_by_key(&time, |&(t, _)| t) {
+            Ok(idx) => changes[idx].1,
+            Err(0) => 0,
+            Err(idx) => changes[idx - 1].1,
+        }
+    }
+
+    pub fn max_queue_len(&self) -> usize {
+        self.changes
+            .iter()
+            .flat_map(|changes| changes.iter().map(|&(_, len)| len))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Periods of time during which the street's queue was not empty
+    pub fn runs(&self, street_id: StreetId) -> Vec<QueueRun> {
+        let changes = &self.changes[street_id];
+        changes
+            .iter()
+            .zip(
+                changes
+                    .iter()
+                    .skip(1)
+                    .map(|&(time, _)| time - 1)
+                    .chain(once(self.simulation.duration)),
+            )
+            .filter(|(&(_, queue_len), _)| queue_len > 0)
+            .map(|(&(start, queue_len), end)| QueueRun {
+                start,
+                end,
+                queue_len,
+            })
+            .collect()
+    }
+
+    // Streets whose queue was not empty at some point, grouped by the
+    // intersection at their end
+    pub fn busy_streets(&self) -> Vec<StreetId> {
+        let mut streets: Vec<StreetId> = (0..self.changes.len())
+            .filter(|&street_id| !self.changes[street_id].is_empty())
+            .collect();
+        streets.sort_unstable_by_key(|&street_id| {
+            (
+                self.simulation.streets[street_id].end_intersection,
+                street_id,
+            )
+        });
+        streets
+    }
+
+    // Image with one column per busy street and one row per second, coloured
+    // from yellow (one car waiting) to dark red (longest queue)
+    pub fn heatmap(&self) -> RgbImage {
+        let streets = self.busy_streets();
+        let width = u32::try_from(streets.len()).unwrap().max(1);
+        let height = 1 + self.simulation.duration;
+        let max_len = self.max_queue_len().max(1);
+        let mut image = ImageBuffer::from_pixel(width, height, WHITE);
+
+        for (col, &street_id) in (0..).zip(streets.iter()) {
+            for queue_run in self.runs(street_id) {
+                let color = heat_color(queue_run.queue_len, max_len);
+                for row in queue_run.start..=queue_run.end {
+                    image.put_pixel(col, row, color);
+                }
+            }
+        }
+        image
+    }
+
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "street_id,street_name,start,end,queue_len")?;
+        for street_id in self.busy_streets() {
+            let street_name = &self.simulation.streets[street_id].name;
+            for queue_run in self.runs(street_id) {
+                writeln!(
+                    writer,
+                    "{},{},{},{},{}",
+                    street_id,
+                    street_name,
+                    queue_run.start,
+                    queue_run.end,
+                    queue_run.queue_len,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let streets: Vec<StreetTimeline> = self
+            .busy_streets()
+            .into_iter()
+            .map(|street_id| StreetTimeline {
+                street_id,
+                street_name: &self.simulation.streets[street_id].name,
+                runs: self.runs(street_id),
+            })
+            .collect();
+        serde_json::to_writer(&mut *writer, &streets)?;
+        writeln!(writer)
+    }
+
+    fn set_queue_len(
+        &mut self,
+        street_id: StreetId,
+        time: Time,
+        queue_len: usize,
+    ) {
+        let changes = &mut self.changes[street_id];
+        if changes.last().is_some_and(|&(t, _)| t == time) {
+            // Queue length changed more than once in the same second
+            changes.pop();
+        }
+        let prev_len = changes.last().map_or(0, |&(_, len)| len);
+        if queue_len != prev_len {
+            changes.push((time, queue_len));
+        }
+    }
+}
+
+impl Observer for QueueTimeline<'_> {
+    fn car_queued(
+        &mut self,
+        time: Time,
+        _car_id: CarId,
+        _position: usize,
+        street_id: StreetId,
+        queue_len: usize,
+    ) {
+        self.set_queue_len(street_id, time, queue_len);
+    }
+
+    fn car_crossed(
+        &mut self,
+        time: Time,
+        _car_id: CarId,
+        _position: usize,
+        street_id: StreetId,
+        queue_len: usize,
+    ) {
+        self.set_queue_len(street_id, time, queue_len);
+    }
+}
+
+fn heat_color(queue_len: usize, max_len: usize) -> Rgb<u8> {
+    // Yellow (255, 255, 0) to dark red (128, 0, 0)
+    let heat = (queue_len - 1) as f32 / (max_len - 1).max(1) as f32;
+    Rgb([
+        (255.0 - 127.0 * heat) as u8,
+        (255.0 * (1.0 - heat)) as u8,
+        0,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        example, random_schedule, random_simulation, EXAMPLE_SCHEDULE,
+    };
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Queue length of every street at the end of every second, counted from
+    // the cars that joined each queue and the times they crossed
+    fn queue_lens(schedule: &Schedule) -> Vec<Vec<usize>> {
+        let simulation = schedule.simulation;
+        let trace = schedule.trace();
+        trace
+            .queues
+            .iter()
+            .map(|queue| {
+                (0..=simulation.duration)
+                    .map(|time| {
+                        queue
+                            .iter()
+                            .filter(|&&(joined, car_id, position)| {
+                                let crossed =
+                                    trace.car_times[car_id][position].1;
+                                joined <= time
+                                    && crossed.is_none_or(|t| t > time)
+                            })
+                            .count()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Check a timeline against the queue lengths of its schedule
+    fn check_timeline(schedule: &Schedule) {
+        let simulation = schedule.simulation;
+        let timeline = QueueTimeline::new(schedule);
+        let lens = queue_lens(schedule);
+
+        for (street_id, street_lens) in lens.iter().enumerate() {
+            // One change per second at most, and only when the length changes
+            let changes = &timeline.changes[street_id];
+            let mut prev = (None, 0);
+            for &(time, queue_len) in changes.iter() {
+                assert!(prev.0.is_none_or(|t| t < time));
+                assert_ne!(queue_len, prev.1);
+                assert_eq!(queue_len, street_lens[time as usize]);
+                prev = (Some(time), queue_len);
+            }
+
+            let mut run_lens = vec![0; street_lens.len()];
+            for queue_run in timeline.runs(street_id) {
+                assert!(queue_run.start <= queue_run.end);
+                for time in queue_run.start..=queue_run.end {
+                    run_lens[time as usize] = queue_run.queue_len;
+                }
+            }
+            assert_eq!(&run_lens, street_lens);
+            for (time, &queue_len) in (0..).zip(street_lens.iter()) {
+                assert_eq!(timeline.queue_len(street_id, time), queue_len);
+            }
+        }
+
+        // One column per busy street, coloured where its queue is not empty
+        let streets = timeline.busy_streets();
+        let heatmap = timeline.heatmap();
+        assert_eq!(heatmap.height(), 1 + simulation.duration);
+        assert_eq!(heatmap.width() as usize, streets.len().max(1));
+        for (col, &street_id) in (0..).zip(streets.iter()) {
+            for (row, &queue_len) in (0..).zip(lens[street_id].iter()) {
+                let pixel = *heatmap.get_pixel(col, row);
+                assert_eq!(pixel == WHITE, queue_len == 0);
+            }
+        }
+
+        // One CSV line and one JSON run per run of each busy street
+        let mut csv = Vec::new();
+        timeline.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut rows = csv.lines().skip(1);
+        let mut json = Vec::new();
+        timeline.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), streets.len());
+        for (idx, &street_id) in streets.iter().enumerate() {
+            let street_name = &simulation.streets[street_id].name;
+            assert_eq!(json[idx]["street_id"], street_id);
+            for (run_idx, queue_run) in
+                timeline.runs(street_id).iter().enumerate()
+            {
+                assert_eq!(
+                    rows.next().unwrap(),
+                    format!(
+                        "{},{},{},{},{}",
+                        street_id,
+                        street_name,
+                        queue_run.start,
+                        queue_run.end,
+                        queue_run.queue_len,
+                    ),
+                );
+                let json_run = &json[idx]["runs"][run_idx];
+                assert_eq!(json_run["start"], queue_run.start);
+                assert_eq!(json_run["end"], queue_run.end);
+                assert_eq!(json_run["queue_len"], queue_run.queue_len);
+            }
+        }
+        assert_eq!(rows.next(), None);
+    }
+
+    #[test]
+    fn example_timeline() {
+        let simulation = example();
+        let mut schedule = Schedule::new(&simulation);
+        schedule.load_from_str(EXAMPLE_SCHEDULE).unwrap();
+        check_timeline(&schedule);
+
+        // Cars that join a queue and cross in the same second leave no trace:
+        // only the first car waits, at rue-d-amsterdam (street 1) at 1
+        let timeline = QueueTimeline::new(&schedule);
+        assert!(timeline.changes[0].is_empty());
+        assert_eq!(timeline.changes[1], vec![(1, 1), (2, 0)]);
+        assert_eq!(timeline.busy_streets(), vec![1]);
+        assert_eq!(timeline.max_queue_len(), 1);
+        let mut csv = Vec::new();
+        timeline.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "street_id,street_name,start,end,queue_len\n\
+             1,rue-d-amsterdam,1,1,1\n",
+        );
+        let heatmap = timeline.heatmap();
+        assert_eq!(*heatmap.get_pixel(0, 1), Rgb([255, 255, 0]));
+        assert_eq!(*heatmap.get_pixel(0, 2), WHITE);
+    }
+
+    #[test]
+    fn random_timelines() {
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..50 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            check_timeline(&schedule);
+        }
+    }
+}
@@ -1,13 +1,17 @@
 use super::*;
-use crate::intersect::reorder_all_intersections;
+use crate::intersect::{reorder_all_intersections, QueueOrder};
 use crate::sched::{Schedule, Scheduler};
+use crate::seed::SeedSource;
 use log::info;
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::collections::HashMap;
 
 pub struct TrafficScheduler {
     min_base: f32,
     max_base: f32,
+    queue_order: QueueOrder,
+    seeds: SeedSource,
 }
 
 impl Default for TrafficScheduler {
@@ -15,6 +19,8 @@ impl Default for TrafficScheduler {
         Self {
             min_base: 1.5_f32,
             max_base: 3.5_f32,
+            queue_order: QueueOrder::Random,
+            seeds: SeedSource::default(),
         }
     }
 }
@@ -27,6 +33,14 @@ impl TrafficScheduler {
     pub fn set_max_base(&mut self, max_base: f32) {
         self.max_base = max_base;
     }
+
+    pub fn set_queue_order(&mut self, queue_order: QueueOrder) {
+        self.queue_order = queue_order;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seeds = SeedSource::new(seed);
+    }
 }
 
 impl Scheduler for TrafficScheduler {
@@ -54,12 +68,21 @@ impl Scheduler for TrafficScheduler {
             }
         }
 
-        let log_base = thread_rng().gen_range(self.min_base..=self.max_base);
-        info!("Traffic scheduler: log base {}", log_base);
+        let seed = self.seeds.next_seed();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let log_base = rng.gen_range(self.min_base..=self.max_base);
+        info!("Traffic scheduler: log base {}, seed {}", log_base, seed);
 
         for (&inter_id, counters) in traffic.iter() {
             let min_traffic = *counters.values().min().unwrap() as f32;
-            for (&street_id, &counter) in counters.iter() {
+            // Add streets in order of ID, the initial order of the streets
+            // affects how the intersection is reordered
+            let mut counters: Vec<(StreetId, usize)> = counters
+                .iter()
+                .map(|(&id, &counter)| (id, counter))
+                .collect();
+            counters.sort_unstable();
+            for (street_id, counter) in counters {
                 // Normalize the time each street gets based on the total
                 // number of cars that need to cross it
                 let time = ((counter as f32) / min_traffic)
@@ -71,7 +94,10 @@ impl Scheduler for TrafficScheduler {
             }
         }
 
-        reorder_all_intersections(&mut schedule);
+        reorder_all_intersections(
+            &mut schedule,
+            self.queue_order.policy(&mut rng),
+        );
 
         schedule
     }
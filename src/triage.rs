@@ -0,0 +1,424 @@
+use super::*;
+use crate::engine::SimulationTrace;
+use crate::sched::{Schedule, Scheduler};
+use log::info;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Value of a car under a schedule: the points it earns, and the points it
+// costs the cars queued behind it at each light it crosses (each of them
+// waits one more second, counted only for cars that arrive)
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct CarValue {
+    pub car_id: CarId,
+    pub arrived: bool,
+    pub points: Score,
+    pub cost: Score,
+}
+
+impl CarValue {
+    pub fn marginal(&self) -> i64 {
+        i64::from(self.points) - i64::from(self.cost)
+    }
+
+    // Cars that don't arrive only take slots from the other cars, and cars
+    // that cost more than they earn are worth less than nothing
+    pub fn is_hopeless(&self) -> bool {
+        !self.arrived || self.marginal() < 0
+    }
+}
+
+pub fn car_values(
+    simulation: &Simulation,
+    trace: &SimulationTrace,
+) -> Vec<CarValue> {
+    let mut values: Vec<CarValue> = trace
+        .car_times
+        .iter()
+        .enumerate()
+        .map(|(car_id, car_times)| {
+            let arrival = car_times.last().and_then(|&(arrival, _)| arrival);
+            CarValue {
+                car_id,
+                arrived: arrival.is_some(),
+                points: arrival.map_or(0, |time| {
+                    simulation.bonus + (simulation.duration - time)
+                }),
+                cost: 0,
+            }
+        })
+        .collect();
+
+    for queue in trace.queues.iter() {
+        // Number of arrived cars among the first cars of the queue
+        let mut arrived_before = vec![0];
+        for &(_, car_id, _) in queue.iter() {
+            let arrived = Score::from(values[car_id].arrived);
+            arrived_before.push(arrived_before.last().unwrap() + arrived);
+        }
+        for (idx, &(_, car_id, position)) in queue.iter().enumerate() {
+            let crossed = match trace.car_times[car_id][position].1 {
+                Some(time) => time,
+                None => continue,
+            };
+            // Cars join in queue order, so the cars behind this one that
+            // were already waiting when it crossed come right after it
+            let end = queue.partition_point(|&(time, _, _)| time <= crossed);
+            if end > idx + 1 {
+                values[car_id].cost +=
+                    arrived_before[end] - arrived_before[idx + 1];
+            }
+        }
+    }
+    values
+}
+
+// Copy of a simulation without some of its cars (streets keep their IDs)
+pub fn without_cars(
+    simulation: &Simulation,
+    car_ids: &BTreeSet<CarId>,
+) -> Simulation {
+    Simulation {
+        duration: simulation.duration,
+        num_intersections: simulation.num_intersections,
+        streets: simulation.streets.clone(),
+        car_paths: simulation
+            .car_paths
+            .iter()
+            .enumerate()
+            .filter(|(car_id, _)| !car_ids.contains(car_id))
+            .map(|(_, path)| path.clone())
+            .collect(),
+        bonus: simulation.bonus,
+    }
+}
+
+// Cars sacrificed by a triage and its effect on the score
+#[derive(Debug, Default, Serialize)]
+pub struct TriageReport {
+    pub rounds: u32,
+    pub num_cars: usize,
+    // Value of each sacrificed car under the schedule it was picked from
+    pub sacrificed: Vec<CarValue>,
+    pub score_before: Score,
+    pub score_after: Score,
+}
+
+impl TriageReport {
+    // Points the sacrificed cars earned when they were picked
+    pub fn lost_points(&self) -> Score {
+        self.sacrificed.iter().map(|value| value.points).sum()
+    }
+}
+
+impl Display for TriageReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "\
+            Triage rounds   : {}\n\
+            Sacrificed cars : {} of {}\n\
+            Points lost     : {}\n\
+            Score before    : {}\n\
+            Score after     : {}\n\
+            Score delta     : {:+}",
+            self.rounds,
+            self.sacrificed.len(),
+            self.num_cars,
+            self.lost_points(),
+            self.score_before,
+            self.score_after,
+            i64::from(self.score_after) - i64::from(self.score_before),
+        )
+    }
+}
+
+// Reschedules a simulation without its hopeless cars: each round sacrifices
+// the hopeless cars of the current schedule, lowest marginal value first,
+// and runs the scheduler again on the other cars; if that doesn't raise the
+// score, half as many cars are tried, down to a single one; each attempt runs
+// a freshly built scheduler, so that seeded schedulers start from their seed
+// whatever the attempts before
+pub struct Triage {
+    max_rounds: u32,
+}
+
+impl Default for Triage {
+    fn default() -> Self {
+        Self { max_rounds: 5 }
+    }
+}
+
+impl Triage {
+    pub fn set_max_rounds(&mut self, max_rounds: u32) {
+        self.max_rounds = max_rounds;
+    }
+
+    pub fn run<'a, F>(
+        &self,
+        abort_flag: &AtomicBool,
+        build_scheduler: F,
+        schedule: Schedule<'a>,
+    ) -> (Schedule<'a>, TriageReport)
+    where
+        F: Fn() -> Box<dyn Scheduler>,
+    {
+        let simulation = schedule.simulation;
+        let mut trace = schedule.trace();
+        let mut best = schedule;
+        let mut report = TriageReport {
+            num_cars: simulation.car_paths.len(),
+            score_before: trace.score,
+            score_after: trace.score,
+            ..TriageReport::default()
+        };
+        let mut sacrificed: BTreeSet<CarId> = BTreeSet::new();
+
+        'rounds: while report.rounds < self.max_rounds {
+            let mut candidates: Vec<CarValue> = car_values(simulation, &trace)
+                .into_iter()
+                .filter(|value| {
+                    value.is_hopeless() && !sacrificed.contains(&value.car_id)
+                })
+                .collect();
+            candidates
+                .sort_unstable_by_key(|value| (value.marginal(), value.car_id));
+
+            let mut num_cars = candidates.len();
+            while num_cars > 0 {
+                if abort_flag.load(Ordering::SeqCst) {
+                    break 'rounds;
+                }
+                let mut car_ids = sacrificed.clone();
+                car_ids.extend(
+                    candidates[..num_cars].iter().map(|value| value.car_id),
+                );
+                let scheduler = build_scheduler();
+                let new_schedule =
+                    reschedule(scheduler.as_ref(), simulation, &car_ids);
+                let new_trace = new_schedule.trace();
+                if new_trace.score > report.score_after {
+                    report.rounds += 1;
+                    info!(
+                        "Triage: round {}, sacrificed {} more cars, score {}",
+                        report.rounds, num_cars, new_trace.score,
+                    );
+                    report.score_after = new_trace.score;
+                    report
+                        .sacrificed
+                        .extend_from_slice(&candidates[..num_cars]);
+                    sacrificed = car_ids;
+                    best = new_schedule;
+                    trace = new_trace;
+                    continue 'rounds;
+                }
+                num_cars /= 2;
+            }
+            // No number of cars raised the score
+            break;
+        }
+        report.sacrificed.sort_unstable_by_key(|value| value.car_id);
+        (best, report)
+    }
+}
+
+// Schedule of a simulation built by running the scheduler without some cars
+fn reschedule<'a>(
+    scheduler: &dyn Scheduler,
+    simulation: &'a Simulation,
+    car_ids: &BTreeSet<CarId>,
+) -> Schedule<'a> {
+    let reduced = without_cars(simulation, car_ids);
+    let mut schedule = Schedule::new(simulation);
+    schedule.intersections = scheduler.schedule(&reduced).intersections;
+    schedule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::simulation;
+    use std::cell::Cell;
+    use std::iter::once;
+
+    // Scheduler giving a green second to every street crossed by the cars,
+    // in order of street ID
+    struct CrossedStreets;
+
+    impl Scheduler for CrossedStreets {
+        fn schedule<'a>(&self, simulation: &'a Simulation) -> Schedule<'a> {
+            let mut street_ids: Vec<StreetId> = simulation
+                .car_paths
+                .iter()
+                .flat_map(|path| path[..path.len() - 1].iter().copied())
+                .collect();
+            street_ids.sort_unstable();
+            street_ids.dedup();
+            let mut schedule = Schedule::new(simulation);
+            for street_id in street_ids {
+                let inter_id = simulation.streets[street_id].end_intersection;
+                schedule.add_street(inter_id, street_id, 1);
+            }
+            schedule
+        }
+    }
+
+    // Triage of the schedule built by CrossedStreets, with the number of
+    // schedulers built
+    fn triage<'a>(
+        triage: &Triage,
+        abort: bool,
+        simulation: &'a Simulation,
+    ) -> (Schedule<'a>, TriageReport, u32) {
+        let schedule = CrossedStreets.schedule(simulation);
+        let built = Cell::new(0);
+        let build_scheduler = || -> Box<dyn Scheduler> {
+            built.set(built.get() + 1);
+            Box::new(CrossedStreets)
+        };
+        let abort_flag = AtomicBool::new(abort);
+        let (schedule, report) =
+            triage.run(&abort_flag, build_scheduler, schedule);
+        (schedule, report, built.get())
+    }
+
+    fn sacrificed(report: &TriageReport) -> Vec<CarId> {
+        report.sacrificed.iter().map(|value| value.car_id).collect()
+    }
+
+    #[test]
+    fn blocking_car() {
+        // Three cars queued at the same light: the first one heads for a
+        // street too long to reach its end, and holds up the two others
+        let simulation = simulation(
+            10,
+            3,
+            &[(0, 1, 1), (1, 0, 1), (1, 2, 20)],
+            &[&[0, 2], &[0, 1], &[0, 1]],
+            100,
+        );
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 0, 1);
+        let trace = schedule.trace();
+        assert_eq!(trace.score, 108 + 107);
+
+        let values: Vec<(CarId, bool, Score, Score, bool)> =
+            car_values(&simulation, &trace)
+                .into_iter()
+                .map(|value| {
+                    (
+                        value.car_id,
+                        value.arrived,
+                        value.points,
+                        value.cost,
+                        value.is_hopeless(),
+                    )
+                })
+                .collect();
+        assert_eq!(
+            values,
+            vec![
+                (0, false, 0, 2, true),
+                (1, true, 108, 1, false),
+                (2, true, 107, 0, false),
+            ],
+        );
+
+        // Without the blocking car, the others cross one second earlier
+        let car_ids: BTreeSet<CarId> = once(0).collect();
+        let reduced = without_cars(&simulation, &car_ids);
+        assert_eq!(reduced.car_paths, vec![vec![0, 1], vec![0, 1]]);
+        let mut schedule = Schedule::new(&reduced);
+        schedule.add_street(1, 0, 1);
+        assert_eq!(schedule.score(), Ok(109 + 108));
+
+        // But the blocking car still drives once sacrificed, so it is kept
+        let (schedule, report, built) =
+            triage(&Triage::default(), false, &simulation);
+        assert_eq!(schedule.score(), Ok(108 + 107));
+        assert_eq!((report.rounds, built), (0, 1));
+        assert!(report.sacrificed.is_empty());
+        assert_eq!(report.score_after, report.score_before);
+    }
+
+    // Intersections 0 and 1 where a car that can't arrive in time (on streets
+    // 0 and 2) takes the first green second from the car of the next street
+    // (1 and 3); at intersection 2, the green second of the third such car
+    // (street 5) puts the green of street 6 at 8, just in time for the car
+    // arriving there then
+    fn hopeless_cars() -> Simulation {
+        simulation(
+            9,
+            6,
+            &[
+                (4, 0, 1),
+                (4, 0, 1),
+                (4, 1, 1),
+                (4, 1, 1),
+                (4, 2, 1),
+                (4, 2, 1),
+                (3, 2, 8),
+                (4, 3, 1),
+                (0, 5, 20),
+                (1, 5, 20),
+                (2, 5, 20),
+                (0, 5, 1),
+                (1, 5, 1),
+                (2, 5, 1),
+            ],
+            &[
+                &[0, 8],
+                &[2, 9],
+                &[5, 10],
+                &[1, 11],
+                &[3, 12],
+                &[4, 13],
+                &[7, 6, 13],
+            ],
+            10,
+        )
+    }
+
+    #[test]
+    fn triage_rounds() {
+        let simulation = hopeless_cars();
+        assert_eq!(CrossedStreets.schedule(&simulation).score(), Ok(62));
+        // Sacrificing the three hopeless cars at once makes the last car late
+        let car_ids: BTreeSet<CarId> = (0..3).collect();
+        let schedule = reschedule(&CrossedStreets, &simulation, &car_ids);
+        assert_eq!(schedule.score(), Ok(54));
+
+        // Each round tries the three cars first, then halves: the first car
+        // alone raises the score, then the first two; the last round finds
+        // nothing
+        let (schedule, report, built) =
+            triage(&Triage::default(), false, &simulation);
+        assert_eq!(sacrificed(&report), vec![0, 1]);
+        assert_eq!(
+            (report.rounds, report.score_before, report.score_after),
+            (2, 62, 64)
+        );
+        assert_eq!(report.num_cars, 7);
+        assert_eq!(report.lost_points(), 0);
+        assert_eq!(schedule.score(), Ok(64));
+        assert_eq!(built, 5);
+        assert_eq!(schedule.intersections.get(&0).unwrap().turns, [(1, 1)]);
+        assert_eq!(schedule.intersections.get(&1).unwrap().turns, [(3, 1)]);
+
+        let mut one_round = Triage::default();
+        one_round.set_max_rounds(1);
+        let (schedule, report, built) = triage(&one_round, false, &simulation);
+        assert_eq!(sacrificed(&report), vec![0]);
+        assert_eq!((report.rounds, report.score_after), (1, 63));
+        assert_eq!(schedule.score(), Ok(63));
+        assert_eq!(built, 2);
+
+        let (schedule, report, built) =
+            triage(&Triage::default(), true, &simulation);
+        assert!(report.sacrificed.is_empty());
+        assert_eq!((report.rounds, report.score_after), (0, 62));
+        assert_eq!(schedule.score(), Ok(62));
+        assert_eq!(built, 0);
+    }
+}
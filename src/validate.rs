@@ -0,0 +1,368 @@
+use super::*;
+use std::collections::hash_map::Entry;
+
+// Rule of the problem statement broken by a schedule file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+// Check a schedule file against every rule of the problem statement, the way
+// the judge does; returns all violations found, in line order
+pub fn validate_schedule(simulation: &Simulation, s: &str) -> Vec<Violation> {
+    let mut validator = Validator {
+        simulation,
+        street_ids: simulation
+            .streets
+            .iter()
+            .enumerate()
+            .map(|(street_id, street)| (street.name.as_str(), street_id))
+            .collect(),
+        lines: s.lines().zip(1..).collect(),
+        next_line: 0,
+        violations: Vec::new(),
+    };
+    validator.validate();
+    validator.violations
+}
+
+struct Validator<'a> {
+    simulation: &'a Simulation,
+    street_ids: HashMap<&'a str, StreetId>,
+    lines: Vec<(&'a str, usize)>,
+    next_line: usize,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Validator<'a> {
+    fn validate(&mut self) {
+        let num_schedules = match self.read_number("number of schedules") {
+            Some(num) => num,
+            None => return,
+        };
+        if num_schedules == 0 {
+            self.violation(
+                1,
+                "Submissions must include at least one schedule".to_string(),
+            );
+        } else if num_schedules > self.simulation.num_intersections as usize {
+            self.violation(
+                1,
+                format!(
+                    "{} schedules, but there are only {} intersections",
+                    num_schedules, self.simulation.num_intersections,
+                ),
+            );
+        }
+
+        // Line of the first schedule of each intersection
+        let mut inter_lines: HashMap<IntersectionId, usize> = HashMap::new();
+        for num in 0..num_schedules {
+            if self.next_line >= self.lines.len() {
+                let line = self.lines.len() + 1;
+                self.violation(
+                    line,
+                    format!(
+                        "Missing schedules: {} declared, {} found",
+                        num_schedules, num,
+                    ),
+                );
+                return;
+            }
+            if !self.validate_intersection(&mut inter_lines) {
+                return;
+            }
+        }
+
+        // Only whitespace may follow the last schedule
+        let trailing: Vec<usize> = self.lines[self.next_line..]
+            .iter()
+            .filter(|(text, _)| !text.trim().is_empty())
+            .map(|&(_, line)| line)
+            .collect();
+        if let Some(&line) = trailing.first() {
+            self.violation(
+                line,
+                format!(
+                    "Unexpected content after the last schedule ({} lines)",
+                    trailing.len(),
+                ),
+            );
+        }
+    }
+
+    // Check the schedule of an intersection; returns false if the rest of
+    // the file can't be checked
+    fn validate_intersection(
+        &mut self,
+        inter_lines: &mut HashMap<IntersectionId, usize>,
+    ) -> bool {
+        let inter_line = self.lines[self.next_line].1;
+        let inter_id = self.read_number("intersection ID");
+        if let Some(inter_id) = inter_id {
+            if inter_id >= self.simulation.num_intersections as usize {
+                self.violation(
+                    inter_line,
+                    format!(
+                        "Intersection {} does not exist (there are {})",
+                        inter_id, self.simulation.num_intersections,
+                    ),
+                );
+            } else {
+                match inter_lines.entry(inter_id as IntersectionId) {
+                    Entry::Occupied(entry) => {
+                        let first_line = *entry.get();
+                        self.violation(
+                            inter_line,
+                            format!(
+                                "Intersection {} already scheduled on line {}",
+                                inter_id, first_line,
+                            ),
+                        );
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(inter_line);
+                    }
+                }
+            }
+        }
+
+        let num_streets_line = self.lines.get(self.next_line).map(|l| l.1);
+        let num_streets = match self.read_number("number of streets") {
+            Some(num) => num,
+            None => return false,
+        };
+        if num_streets == 0 {
+            self.violation(
+                num_streets_line.unwrap(),
+                "Schedules must include at least one street".to_string(),
+            );
+        }
+
+        // Line of each street in this schedule
+        let mut street_lines: HashMap<StreetId, usize> = HashMap::new();
+        for num in 0..num_streets {
+            let (text, line) = match self.lines.get(self.next_line) {
+                Some(&line) => line,
+                None => {
+                    let line = self.lines.len() + 1;
+                    self.violation(
+                        line,
+                        format!(
+                            "Missing streets: {} declared, {} found",
+                            num_streets, num,
+                        ),
+                    );
+                    return false;
+                }
+            };
+            self.next_line += 1;
+            self.validate_street(text, line, inter_id, &mut street_lines);
+        }
+        true
+    }
+
+    fn validate_street(
+        &mut self,
+        text: &str,
+        line: usize,
+        inter_id: Option<usize>,
+        street_lines: &mut HashMap<StreetId, usize>,
+    ) {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 2 {
+            self.violation(
+                line,
+                format!(
+                    "Expected street name and green time, found '{}'",
+                    text
+                ),
+            );
+            return;
+        }
+        let (street_name, time) = (fields[0], fields[1]);
+
+        match self.street_ids.get(street_name) {
+            None => self
+                .violation(line, format!("Unknown street '{}'", street_name)),
+            Some(&street_id) => {
+                let end_inter = self.simulation.streets[street_id]
+                    .end_intersection as usize;
+                if inter_id.is_some_and(|inter_id| inter_id != end_inter) {
+                    self.violation(
+                        line,
+                        format!(
+                            "Street '{}' ends at intersection {}, not {}",
+                            street_name,
+                            end_inter,
+                            inter_id.unwrap(),
+                        ),
+                    );
+                }
+                if let Some(&first_line) = street_lines.get(&street_id) {
+                    self.violation(
+                        line,
+                        format!(
+                            "Street '{}' already scheduled on line {}",
+                            street_name, first_line,
+                        ),
+                    );
+                } else {
+                    street_lines.insert(street_id, line);
+                }
+            }
+        }
+
+        match time.parse::<Time>() {
+            Ok(time) if time >= 1 && time <= self.simulation.duration => (),
+            Ok(time) => self.violation(
+                line,
+                format!(
+                    "Green time {} of street '{}' is not between 1 and {}",
+                    time, street_name, self.simulation.duration,
+                ),
+            ),
+            Err(err) => self.violation(
+                line,
+                format!("Invalid green time '{}': {}", time, err),
+            ),
+        }
+    }
+
+    // Read a line with a single number
+    fn read_number(&mut self, what: &str) -> Option<usize> {
+        let (text, line) = match self.lines.get(self.next_line) {
+            Some(&line) => line,
+            None => {
+                let line = self.lines.len() + 1;
+                self.violation(line, format!("Missing {}", what));
+                return None;
+            }
+        };
+        self.next_line += 1;
+        match text.trim().parse() {
+            Ok(num) => Some(num),
+            Err(err) => {
+                self.violation(
+                    line,
+                    format!("Invalid {} '{}': {}", what, text, err),
+                );
+                None
+            }
+        }
+    }
+
+    fn violation(&mut self, line: usize, message: String) {
+        self.violations.push(Violation { line, message });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        example, random_schedule, random_simulation, EXAMPLE_SCHEDULE,
+    };
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Lines and messages of the violations found in a schedule file of the
+    // example
+    fn violations(s: &str) -> Vec<(usize, String)> {
+        validate_schedule(&example(), s)
+            .into_iter()
+            .map(|violation| (violation.line, violation.message))
+            .collect()
+    }
+
+    #[test]
+    fn valid_schedules() {
+        assert!(violations(EXAMPLE_SCHEDULE).is_empty());
+
+        let mut rng = StdRng::seed_from_u64(2021);
+        for _ in 0..50 {
+            let simulation = random_simulation(&mut rng);
+            let schedule = random_schedule(&simulation, &mut rng);
+            let s = schedule.to_string();
+            assert_eq!(validate_schedule(&simulation, &s), vec![]);
+        }
+    }
+
+    #[test]
+    fn no_schedules() {
+        assert_eq!(
+            violations("0\n"),
+            vec![(
+                1,
+                "Submissions must include at least one schedule".to_string()
+            )],
+        );
+        assert_eq!(
+            violations(""),
+            vec![(1, "Missing number of schedules".to_string())]
+        );
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        assert_eq!(
+            violations("1\n0\n1\nrue-de-londres 7\n"),
+            vec![(
+                4,
+                "Green time 7 of street 'rue-de-londres' is not between 1 \
+                 and 6"
+                    .to_string()
+            )],
+        );
+        assert_eq!(
+            violations("1\n1\n1\nrue-de-londres 1\n"),
+            vec![(
+                4,
+                "Street 'rue-de-londres' ends at intersection 0, not 1"
+                    .to_string()
+            )],
+        );
+        assert_eq!(
+            violations("1\n1\n2\nrue-d-athenes 1\nrue-d-athenes 1\n"),
+            vec![(
+                5,
+                "Street 'rue-d-athenes' already scheduled on line 4"
+                    .to_string()
+            )],
+        );
+        assert_eq!(
+            violations("2\n0\n1\nrue-de-londres 1\n0\n1\nrue-de-londres 1\n"),
+            vec![(5, "Intersection 0 already scheduled on line 2".to_string())],
+        );
+        assert_eq!(
+            violations("1\n4\n1\nrue-de-paris 1\n"),
+            vec![
+                (2, "Intersection 4 does not exist (there are 4)".to_string()),
+                (4, "Unknown street 'rue-de-paris'".to_string()),
+            ],
+        );
+        assert_eq!(
+            violations("1\n0\n0\n"),
+            vec![(3, "Schedules must include at least one street".to_string())],
+        );
+        assert_eq!(
+            violations("2\n0\n1\nrue-de-londres 1\n"),
+            vec![(5, "Missing schedules: 2 declared, 1 found".to_string())],
+        );
+        assert_eq!(
+            violations("1\n0\n1\nrue-de-londres 1\nrue-de-rome 1\n"),
+            vec![(
+                5,
+                "Unexpected content after the last schedule (1 lines)"
+                    .to_string()
+            )],
+        );
+    }
+}
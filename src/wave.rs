@@ -0,0 +1,486 @@
+use super::*;
+use crate::engine::{intersection_lights, Light, SimulationTrace};
+use crate::improve::Improver;
+use crate::sched::{Intersection, Schedule};
+use log::{debug, info};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::iter::once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Sequence of streets followed one after another by many cars
+#[derive(Clone, Debug)]
+pub struct Corridor {
+    pub streets: Vec<StreetId>,
+    // Cars going through the busiest turn of the corridor
+    pub cars: usize,
+}
+
+impl Corridor {
+    // Intersections crossed along the corridor, in order
+    pub fn intersections(
+        &self,
+        simulation: &Simulation,
+    ) -> Vec<IntersectionId> {
+        let mut inter_ids: Vec<IntersectionId> = Vec::new();
+        for &street_id in self.streets.iter() {
+            let inter_id = simulation.streets[street_id].end_intersection;
+            if !inter_ids.contains(&inter_id) {
+                inter_ids.push(inter_id);
+            }
+        }
+        inter_ids
+    }
+}
+
+// Busiest corridors of a simulation: starting from the turn (pair of
+// consecutive streets) taken by most cars, each corridor is extended forwards
+// and backwards with the busiest turn taken by at least min_cars cars, and
+// turns are used by one corridor only
+pub fn busy_corridors(
+    simulation: &Simulation,
+    max_corridors: usize,
+    min_cars: usize,
+) -> Vec<Corridor> {
+    let mut turns: HashMap<(StreetId, StreetId), usize> = HashMap::new();
+    for car_path in simulation.car_paths.iter() {
+        for pair in car_path.windows(2) {
+            *turns.entry((pair[0], pair[1])).or_insert(0) += 1;
+        }
+    }
+    let mut next: HashMap<StreetId, Vec<(usize, StreetId)>> = HashMap::new();
+    let mut prev: HashMap<StreetId, Vec<(usize, StreetId)>> = HashMap::new();
+    let mut sorted_turns: Vec<((StreetId, StreetId), usize)> = turns
+        .into_iter()
+        .filter(|&(_, cars)| cars >= min_cars.max(1))
+        .collect();
+    sorted_turns.sort_unstable_by_key(|&(turn, cars)| (Reverse(cars), turn));
+    for &((from, to), cars) in sorted_turns.iter() {
+        next.entry(from).or_default().push((cars, to));
+        prev.entry(to).or_default().push((cars, from));
+    }
+
+    let mut used: HashSet<(StreetId, StreetId)> = HashSet::new();
+    let mut corridors = Vec::new();
+    for &((from, to), cars) in sorted_turns.iter() {
+        if corridors.len() >= max_corridors {
+            break;
+        }
+        if used.contains(&(from, to)) {
+            continue;
+        }
+        used.insert((from, to));
+        let mut streets = vec![from, to];
+        let mut visited: HashSet<StreetId> = streets.iter().copied().collect();
+
+        // Extend forwards, then backwards
+        while let Some(&(_, street_id)) =
+            next.get(streets.last().unwrap()).and_then(|turns| {
+                turns.iter().find(|&&(_, to)| {
+                    !visited.contains(&to)
+                        && !used.contains(&(*streets.last().unwrap(), to))
+                })
+            })
+        {
+            used.insert((*streets.last().unwrap(), street_id));
+            visited.insert(street_id);
+            streets.push(street_id);
+        }
+        let mut head: Vec<StreetId> = Vec::new();
+        let mut first = from;
+        while let Some(&(_, street_id)) = prev.get(&first).and_then(|turns| {
+            turns.iter().find(|&&(_, from)| {
+                !visited.contains(&from) && !used.contains(&(from, first))
+            })
+        }) {
+            used.insert((street_id, first));
+            visited.insert(street_id);
+            head.push(street_id);
+            first = street_id;
+        }
+        head.reverse();
+        head.extend(streets);
+
+        corridors.push(Corridor {
+            streets: head,
+            cars,
+        });
+    }
+    corridors
+}
+
+// Total time cars wait at an intersection, given the times they join the
+// queues of its streets (taken from a trace, so upstream traffic is fixed);
+// each green second lets one car through, as in the simulation
+pub fn local_wait(
+    intersection: &Intersection,
+    trace: &SimulationTrace,
+    duration: Time,
+) -> u64 {
+    intersection_lights(intersection)
+        .into_iter()
+        .map(|(street_id, light)| {
+            let join_times = trace.queues[street_id]
+                .iter()
+                .map(|&(join_time, _, _)| join_time);
+            street_wait(&light, join_times, duration)
+        })
+        .sum()
+}
+
+// Total time cars wait at a light, given the times they join its queue (in
+// increasing order); cars still waiting at the end of the simulation count
+// until then
+pub fn street_wait<I>(light: &Light, join_times: I, duration: Time) -> u64
+where
+    I: IntoIterator<Item = Time>,
+{
+    let mut total_wait: u64 = 0;
+    // First second at which the next car may cross
+    let mut free_time = 0;
+    for join_time in join_times {
+        let cross_time = light
+            .next_green(join_time.max(free_time))
+            .filter(|&time| time <= duration);
+        match cross_time {
+            Some(time) => {
+                total_wait += u64::from(time - join_time);
+                free_time = time + 1;
+            }
+            None => total_wait += u64::from(duration - join_time),
+        }
+    }
+    total_wait
+}
+
+// Rotation of the turns of an intersection that best aligns its green times
+// with the arrivals recorded in a trace, with the resulting wait time; the
+// current order (rotation 0) wins ties
+pub fn best_rotation(
+    intersection: &Intersection,
+    trace: &SimulationTrace,
+    duration: Time,
+) -> (usize, u64) {
+    (0..intersection.turns.len().max(1))
+        .map(|rotation| {
+            let mut rotated = intersection.clone();
+            rotated.rotate(rotation);
+            (rotation, local_wait(&rotated, trace, duration))
+        })
+        .min_by_key(|&(rotation, wait)| (wait, rotation))
+        .unwrap()
+}
+
+// Apply the best rotation to an intersection of a schedule; returns the
+// rotation, if the intersection changed
+pub fn align_intersection(
+    schedule: &mut Schedule,
+    trace: &SimulationTrace,
+    inter_id: IntersectionId,
+) -> Option<usize> {
+    let duration = schedule.simulation.duration;
+    let inter = schedule.intersections.get_mut(&inter_id)?;
+    let (rotation, _) = best_rotation(inter, trace, duration);
+    if rotation == 0 {
+        return None;
+    }
+    inter.rotate(rotation);
+    Some(rotation)
+}
+
+// Improver that builds green waves along the busiest corridors: the
+// intersections of a corridor are aligned one after another with the cars
+// arriving from upstream, keeping each rotation that improves the score (so
+// that the arrivals at the next intersection reflect it)
+pub struct GreenWaveImprover {
+    max_corridors: usize,
+    min_cars: usize,
+}
+
+impl Default for GreenWaveImprover {
+    fn default() -> Self {
+        Self {
+            max_corridors: 100,
+            min_cars: 10,
+        }
+    }
+}
+
+impl GreenWaveImprover {
+    pub fn set_max_corridors(&mut self, max_corridors: usize) {
+        self.max_corridors = max_corridors;
+    }
+
+    pub fn set_min_cars(&mut self, min_cars: usize) {
+        self.min_cars = min_cars;
+    }
+
+    fn align_corridor<'a>(
+        &self,
+        abort_flag: &AtomicBool,
+        mut schedule: Schedule<'a>,
+        trace: &SimulationTrace,
+        curr_score: Score,
+        corridor: &Corridor,
+    ) -> Option<(Schedule<'a>, Score)> {
+        let mut best_score = curr_score;
+        // Trace of the schedule once it has been improved
+        let mut new_trace: Option<SimulationTrace> = None;
+        for inter_id in corridor.intersections(schedule.simulation) {
+            if abort_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            let previous = match schedule.intersections.get(&inter_id) {
+                Some(inter) => inter.clone(),
+                None => continue,
+            };
+            let trace = new_trace.as_ref().unwrap_or(trace);
+            let rotation =
+                match align_intersection(&mut schedule, trace, inter_id) {
+                    Some(rotation) => rotation,
+                    None => continue,
+                };
+
+            let new_score = schedule.rescore(trace, once(inter_id));
+            if new_score > best_score {
+                debug!(
+                    "Green wave: rotating intersection {} by {} turns, \
+                     score {}",
+                    inter_id, rotation, new_score,
+                );
+                best_score = new_score;
+                new_trace = Some(schedule.trace());
+            } else {
+                schedule.intersections.insert(inter_id, previous);
+            }
+        }
+
+        if new_trace.is_some() {
+            Some((schedule, best_score))
+        } else {
+            None
+        }
+    }
+}
+
+impl Improver for GreenWaveImprover {
+    fn improve<'a>(
+        &self,
+        abort_flag: Arc<AtomicBool>,
+        schedule: Schedule<'a>,
+    ) -> Option<(Schedule<'a>, Score)> {
+        let corridors = busy_corridors(
+            schedule.simulation,
+            self.max_corridors,
+            self.min_cars,
+        );
+        info!(
+            "Green wave improver: {} corridors with at least {} cars",
+            corridors.len(),
+            self.min_cars,
+        );
+
+        // Align corridors in decreasing order of traffic; return as soon as
+        // an improvement is found
+        let trace = schedule.trace();
+        let curr_score = trace.score;
+        let result = corridors.par_iter().find_map_first(|corridor| {
+            if abort_flag.load(Ordering::SeqCst) {
+                return None;
+            }
+            self.align_corridor(
+                &abort_flag,
+                schedule.clone(),
+                &trace,
+                curr_score,
+                corridor,
+            )
+        });
+        if let Some((_, score)) = result.as_ref() {
+            info!("Green wave improver: new best score {}", score);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::simulation;
+
+    #[test]
+    fn corridors() {
+        let simulation = simulation(
+            10,
+            4,
+            &[(0, 1, 1), (1, 2, 1), (2, 3, 1), (3, 0, 1)],
+            &[
+                &[0, 1, 2],
+                &[0, 1, 2],
+                &[0, 1, 2],
+                &[2, 3],
+                &[2, 3],
+                &[3, 0],
+            ],
+            100,
+        );
+        let corridors = busy_corridors(&simulation, 10, 2);
+        assert_eq!(corridors.len(), 1);
+        assert_eq!(corridors[0].streets, vec![0, 1, 2, 3]);
+        assert_eq!(corridors[0].cars, 3);
+        assert_eq!(corridors[0].intersections(&simulation), vec![1, 2, 3, 0]);
+
+        // The last turn is taken by a single car and can't extend the first
+        // corridor, whose streets are already visited
+        let corridors = busy_corridors(&simulation, 10, 1);
+        let streets: Vec<(Vec<StreetId>, usize)> = corridors
+            .into_iter()
+            .map(|corridor| (corridor.streets, corridor.cars))
+            .collect();
+        assert_eq!(streets, vec![(vec![0, 1, 2, 3], 3), (vec![3, 0], 1)]);
+        assert_eq!(busy_corridors(&simulation, 1, 1).len(), 1);
+        assert!(busy_corridors(&simulation, 10, 4).is_empty());
+    }
+
+    #[test]
+    fn wait_at_light() {
+        // Green on the second second of a 3 second cycle
+        let mut intersection = Intersection::new(0, 1);
+        intersection.add_street(1, 1);
+        intersection.add_street(2, 1);
+        let lights = intersection_lights(&intersection);
+        let light = &lights[1].1;
+
+        assert_eq!(street_wait(light, vec![], 6), 0);
+        // Crossing at 1 and 4, then still waiting at the end
+        assert_eq!(street_wait(light, vec![0, 0, 4], 6), 1 + 4 + 2);
+        assert_eq!(street_wait(light, vec![1, 2], 6), 2);
+    }
+
+    #[test]
+    fn rotations() {
+        // Two streets into intersection 1, the second one with two cars
+        let two_cars = simulation(
+            10,
+            3,
+            &[(0, 1, 1), (2, 1, 1), (1, 0, 1)],
+            &[&[1, 2], &[1, 2]],
+            100,
+        );
+        let mut schedule = Schedule::new(&two_cars);
+        schedule.add_street(1, 0, 2);
+        schedule.add_street(1, 1, 1);
+        let trace = schedule.trace();
+        let inter = schedule.intersections.get(&1).unwrap().clone();
+        assert_eq!(local_wait(&inter, &trace, 10), 2 + 5);
+        assert_eq!(best_rotation(&inter, &trace, 10), (1, 3));
+
+        let mut aligned = schedule.clone();
+        assert_eq!(align_intersection(&mut aligned, &trace, 1), Some(1));
+        assert_eq!(
+            aligned.intersections.get(&1).unwrap().turns,
+            [(1, 1), (0, 2)]
+        );
+        assert!(aligned.score().unwrap() > trace.score);
+        let trace = aligned.trace();
+        assert_eq!(align_intersection(&mut aligned, &trace, 1), None);
+
+        // One car on each street: both orders make one car wait one second
+        let balanced = simulation(
+            10,
+            3,
+            &[(0, 1, 1), (2, 1, 1), (1, 0, 1)],
+            &[&[0, 2], &[1, 2]],
+            100,
+        );
+        let mut schedule = Schedule::new(&balanced);
+        schedule.add_street(1, 0, 1);
+        schedule.add_street(1, 1, 1);
+        let trace = schedule.trace();
+        let inter = schedule.intersections.get(&1).unwrap();
+        assert_eq!(best_rotation(inter, &trace, 10), (0, 1));
+        assert_eq!(align_intersection(&mut schedule, &trace, 1), None);
+    }
+
+    // Corridor of streets 0, 1 and 2 through intersections 1 and 2, with
+    // streets 4, 5 and 6 crossing it there and the given cars
+    fn corridor(car_paths: &[&[StreetId]]) -> Simulation {
+        simulation(
+            10,
+            8,
+            &[
+                (0, 1, 1),
+                (1, 2, 1),
+                (2, 3, 1),
+                (1, 4, 1),
+                (5, 1, 1),
+                (6, 2, 1),
+                (7, 2, 1),
+            ],
+            car_paths,
+            100,
+        )
+    }
+
+    fn improve_wave(schedule: Schedule) -> Option<(Schedule, Score)> {
+        let mut improver = GreenWaveImprover::default();
+        improver.set_min_cars(1);
+        improver.improve(Arc::new(AtomicBool::new(false)), schedule)
+    }
+
+    #[test]
+    fn aligned_corridor() {
+        // The first car leaves the corridor at intersection 1, the second one
+        // follows it; both wait for street 0 at intersection 1, then the
+        // second one waits for street 1 at intersection 2 once the first
+        // intersection is aligned
+        let simulation = corridor(&[&[0, 3], &[0, 1, 2]]);
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 4, 1);
+        schedule.add_street(1, 0, 1);
+        schedule.add_street(2, 1, 1);
+        schedule.add_street(2, 5, 1);
+        let score = schedule.score().unwrap();
+
+        let (aligned, new_score) = improve_wave(schedule).unwrap();
+        assert_eq!(new_score, score + 2);
+        assert_eq!(aligned.score(), Ok(new_score));
+        assert_eq!(
+            aligned.intersections.get(&1).unwrap().turns,
+            [(0, 1), (4, 1)]
+        );
+        assert_eq!(
+            aligned.intersections.get(&2).unwrap().turns,
+            [(5, 1), (1, 1)]
+        );
+    }
+
+    #[test]
+    fn reverted_rotation() {
+        // Aligning intersection 1 alone only makes the car wait longer at
+        // intersection 2, so it is reverted and intersection 2 is aligned with
+        // the original arrivals
+        let simulation = corridor(&[&[0, 1, 2]]);
+        let mut schedule = Schedule::new(&simulation);
+        schedule.add_street(1, 4, 1);
+        schedule.add_street(1, 0, 1);
+        schedule.add_street(2, 1, 1);
+        schedule.add_street(2, 5, 1);
+        schedule.add_street(2, 6, 1);
+        let score = schedule.score().unwrap();
+
+        let (aligned, new_score) = improve_wave(schedule).unwrap();
+        assert_eq!(new_score, score + 1);
+        assert_eq!(aligned.score(), Ok(new_score));
+        assert_eq!(
+            aligned.intersections.get(&1).unwrap().turns,
+            [(4, 1), (0, 1)]
+        );
+        assert_eq!(
+            aligned.intersections.get(&2).unwrap().turns,
+            [(5, 1), (6, 1), (1, 1)]
+        );
+    }
+}